@@ -0,0 +1,699 @@
+use crate::save_state::{
+    read_bool, read_bytes, read_u16, read_u32, read_u8, write_bool, write_bytes, write_u16,
+    write_u32, write_u8, SaveState,
+};
+use std::io;
+
+pub const APU_FIRST: u16 = 0xFF10;
+pub const APU_LAST: u16 = 0xFF3F;
+const APU_SIZE: usize = (APU_LAST - APU_FIRST + 1) as usize;
+
+const NR10: usize = 0x00;
+const NR11: usize = 0x01;
+const NR12: usize = 0x02;
+const NR13: usize = 0x03;
+const NR14: usize = 0x04;
+const NR21: usize = 0x06;
+const NR22: usize = 0x07;
+const NR23: usize = 0x08;
+const NR24: usize = 0x09;
+const NR30: usize = 0x0A;
+const NR31: usize = 0x0B;
+const NR32: usize = 0x0C;
+const NR33: usize = 0x0D;
+const NR34: usize = 0x0E;
+const NR41: usize = 0x10;
+const NR42: usize = 0x11;
+const NR43: usize = 0x12;
+const NR44: usize = 0x13;
+const NR50: usize = 0x14;
+const NR51: usize = 0x15;
+const NR52: usize = 0x16;
+const WAVE_RAM_FIRST: usize = 0x20;
+
+// bits that always read back as 1, write-only and unused registers read 0xFF
+#[rustfmt::skip]
+const READ_MASKS: [u8; WAVE_RAM_FIRST] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const POWER: u8 = 0b1000_0000;
+const TRIGGER: u8 = 0b1000_0000;
+const LENGTH_ENABLE: u8 = 0b0100_0000;
+
+pub const SAMPLE_RATE: u32 = 44100;
+const CPU_FREQUENCY: u32 = 4194304;
+// the frame sequencer runs at 512Hz
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+// one second of interleaved stereo samples, a frontend that never drains them loses the rest
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize * 2;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+struct Envelope {
+    volume: u8,
+    period: u8,
+    increase: bool,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            volume: 0,
+            period: 0,
+            increase: false,
+            timer: 0,
+        }
+    }
+    fn trigger(&mut self, register: u8) {
+        self.volume = register >> 4;
+        self.increase = register & 0b1000 != 0;
+        self.period = register & 0b111;
+        self.timer = self.period;
+    }
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    enabled: bool,
+    shadow_frequency: u16,
+    timer: u8,
+}
+
+struct SquareChannel {
+    enabled: bool,
+    length: u16,
+    frequency: u16,
+    timer: u32,
+    duty_step: usize,
+    envelope: Envelope,
+    sweep: Sweep,
+}
+
+impl SquareChannel {
+    fn new() -> Self {
+        SquareChannel {
+            enabled: false,
+            length: 0,
+            frequency: 0,
+            timer: 0,
+            duty_step: 0,
+            envelope: Envelope::new(),
+            sweep: Sweep {
+                enabled: false,
+                shadow_frequency: 0,
+                timer: 0,
+            },
+        }
+    }
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self, duty: u8) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[duty as usize >> 6][self.duty_step] * self.envelope.volume
+    }
+}
+
+struct WaveChannel {
+    enabled: bool,
+    length: u16,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+}
+
+impl WaveChannel {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    length: u16,
+    timer: u32,
+    lfsr: u16,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn period(polynomial: u8) -> u32 {
+        NOISE_DIVISORS[(polynomial & 0b111) as usize] << (polynomial >> 4)
+    }
+    fn step(&mut self, cycles: u32, polynomial: u8) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = NoiseChannel::period(polynomial);
+            let bit = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            // 7 bit mode also feeds the result back into bit 6
+            if polynomial & 0b1000 != 0 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+}
+
+pub struct APU {
+    registers: [u8; APU_SIZE],
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+    sequencer_clock: u32,
+    sequencer_step: u8,
+    sample_clock: u32,
+    // interleaved left/right samples in -1.0..=1.0
+    samples: Vec<f32>,
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            registers: [0; APU_SIZE],
+            channel1: SquareChannel::new(),
+            channel2: SquareChannel::new(),
+            channel3: WaveChannel {
+                enabled: false,
+                length: 0,
+                frequency: 0,
+                timer: 0,
+                position: 0,
+            },
+            channel4: NoiseChannel {
+                enabled: false,
+                length: 0,
+                timer: 0,
+                lfsr: 0x7FFF,
+                envelope: Envelope::new(),
+            },
+            sequencer_clock: 0,
+            sequencer_step: 0,
+            sample_clock: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        let index = (address - APU_FIRST) as usize;
+        match index {
+            NR52 => {
+                let channels = [
+                    self.channel1.enabled,
+                    self.channel2.enabled,
+                    self.channel3.enabled,
+                    self.channel4.enabled,
+                ];
+                let status = channels
+                    .iter()
+                    .enumerate()
+                    .fold(0, |bits, (i, &enabled)| bits | (enabled as u8) << i);
+                self.registers[NR52] & POWER | READ_MASKS[NR52] | status
+            }
+            WAVE_RAM_FIRST.. => self.registers[index],
+            _ => self.registers[index] | READ_MASKS[index],
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, byte: u8) {
+        let index = (address - APU_FIRST) as usize;
+        if index == NR52 {
+            if byte & POWER == 0 {
+                // powering off clears every register but the wave ram
+                *self = APU {
+                    registers: self.registers,
+                    samples: std::mem::take(&mut self.samples),
+                    ..APU::new()
+                };
+                self.registers[..WAVE_RAM_FIRST].fill(0);
+            } else {
+                self.registers[NR52] = POWER;
+            }
+            return;
+        }
+        if index < WAVE_RAM_FIRST && self.registers[NR52] & POWER == 0 {
+            return;
+        }
+        self.registers[index] = byte;
+        match index {
+            NR11 => self.channel1.length = 64 - (byte & 0x3F) as u16,
+            NR21 => self.channel2.length = 64 - (byte & 0x3F) as u16,
+            NR31 => self.channel3.length = 256 - byte as u16,
+            NR41 => self.channel4.length = 64 - (byte & 0x3F) as u16,
+            NR12 if byte & 0xF8 == 0 => self.channel1.enabled = false,
+            NR22 if byte & 0xF8 == 0 => self.channel2.enabled = false,
+            NR30 if byte & POWER == 0 => self.channel3.enabled = false,
+            NR42 if byte & 0xF8 == 0 => self.channel4.enabled = false,
+            NR13 | NR14 => {
+                self.channel1.frequency = self.frequency(NR13);
+                if index == NR14 && byte & TRIGGER != 0 {
+                    self.trigger_channel1();
+                }
+            }
+            NR23 | NR24 => {
+                self.channel2.frequency = self.frequency(NR23);
+                if index == NR24 && byte & TRIGGER != 0 {
+                    let channel = &mut self.channel2;
+                    channel.enabled = self.registers[NR22] & 0xF8 != 0;
+                    if channel.length == 0 {
+                        channel.length = 64;
+                    }
+                    channel.timer = channel.period();
+                    channel.envelope.trigger(self.registers[NR22]);
+                }
+            }
+            NR33 | NR34 => {
+                self.channel3.frequency = self.frequency(NR33);
+                if index == NR34 && byte & TRIGGER != 0 {
+                    let channel = &mut self.channel3;
+                    channel.enabled = self.registers[NR30] & POWER != 0;
+                    if channel.length == 0 {
+                        channel.length = 256;
+                    }
+                    channel.timer = channel.period();
+                    channel.position = 0;
+                }
+            }
+            NR44 if byte & TRIGGER != 0 => {
+                let channel = &mut self.channel4;
+                channel.enabled = self.registers[NR42] & 0xF8 != 0;
+                if channel.length == 0 {
+                    channel.length = 64;
+                }
+                channel.timer = NoiseChannel::period(self.registers[NR43]);
+                channel.lfsr = 0x7FFF;
+                channel.envelope.trigger(self.registers[NR42]);
+            }
+            _ => {}
+        }
+    }
+
+    // sets every register from a snapshot of another emulator, channels keep playing
+    // as NR52 says instead of being triggered again
+    pub fn restore_registers(&mut self, registers: &[u8]) {
+        self.write_byte(APU_FIRST + NR52 as u16, registers[NR52]);
+        for (index, &byte) in registers.iter().enumerate() {
+            let byte = match index {
+                NR52 => continue,
+                NR14 | NR24 | NR34 | NR44 => byte & !TRIGGER,
+                _ => byte,
+            };
+            self.write_byte(APU_FIRST + index as u16, byte);
+        }
+        let status = registers[NR52];
+        self.channel1.enabled = status & 0b0001 != 0;
+        self.channel2.enabled = status & 0b0010 != 0;
+        self.channel3.enabled = status & 0b0100 != 0;
+        self.channel4.enabled = status & 0b1000 != 0;
+    }
+
+    // 11 bit frequency spread over the low register and the bottom of the next one
+    fn frequency(&self, low: usize) -> u16 {
+        (self.registers[low + 1] as u16 & 0b111) << 8 | self.registers[low] as u16
+    }
+
+    fn trigger_channel1(&mut self) {
+        let sweep = self.registers[NR10];
+        let channel = &mut self.channel1;
+        channel.enabled = self.registers[NR12] & 0xF8 != 0;
+        if channel.length == 0 {
+            channel.length = 64;
+        }
+        channel.timer = channel.period();
+        channel.envelope.trigger(self.registers[NR12]);
+        channel.sweep.shadow_frequency = channel.frequency;
+        channel.sweep.timer = APU::sweep_period(sweep);
+        channel.sweep.enabled = sweep & 0b0111_0111 != 0;
+        if sweep & 0b111 != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    fn sweep_period(sweep: u8) -> u8 {
+        match (sweep >> 4) & 0b111 {
+            0 => 8,
+            period => period,
+        }
+    }
+
+    // next frequency of the sweep, disables channel 1 when it overflows
+    fn sweep_frequency(&mut self) -> u16 {
+        let sweep = self.registers[NR10];
+        let shadow = self.channel1.sweep.shadow_frequency;
+        let delta = shadow >> (sweep & 0b111);
+        let frequency = if sweep & 0b1000 != 0 {
+            shadow.wrapping_sub(delta)
+        } else {
+            shadow + delta
+        };
+        if frequency > 2047 {
+            self.channel1.enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = self.registers[NR10];
+        self.channel1.sweep.timer = self.channel1.sweep.timer.saturating_sub(1);
+        if self.channel1.sweep.timer != 0 {
+            return;
+        }
+        self.channel1.sweep.timer = APU::sweep_period(sweep);
+        if !self.channel1.sweep.enabled || (sweep >> 4) & 0b111 == 0 {
+            return;
+        }
+        let frequency = self.sweep_frequency();
+        if frequency <= 2047 && sweep & 0b111 != 0 {
+            self.channel1.sweep.shadow_frequency = frequency;
+            self.channel1.frequency = frequency;
+            self.registers[NR13] = frequency as u8;
+            self.registers[NR14] = self.registers[NR14] & !0b111 | (frequency >> 8) as u8;
+            self.sweep_frequency();
+        }
+    }
+
+    fn clock_lengths(&mut self) {
+        let channels = [
+            (NR14, &mut self.channel1.enabled, &mut self.channel1.length),
+            (NR24, &mut self.channel2.enabled, &mut self.channel2.length),
+            (NR34, &mut self.channel3.enabled, &mut self.channel3.length),
+            (NR44, &mut self.channel4.enabled, &mut self.channel4.length),
+        ];
+        for (control, enabled, length) in channels {
+            if self.registers[control] & LENGTH_ENABLE != 0 && *length > 0 {
+                *length -= 1;
+                if *length == 0 {
+                    *enabled = false;
+                }
+            }
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        match self.sequencer_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.clock_sweep();
+            }
+            7 => {
+                self.channel1.envelope.clock();
+                self.channel2.envelope.clock();
+                self.channel4.envelope.clock();
+            }
+            _ => {}
+        }
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    fn wave_output(&self) -> u8 {
+        if !self.channel3.enabled {
+            return 0;
+        }
+        let byte = self.registers[WAVE_RAM_FIRST + self.channel3.position / 2];
+        let sample = if self.channel3.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match (self.registers[NR32] >> 5) & 0b11 {
+            0 => 0,
+            shift => sample >> (shift - 1),
+        }
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        let outputs = [
+            (
+                self.channel1.enabled,
+                self.channel1.output(self.registers[NR11]),
+            ),
+            (
+                self.channel2.enabled,
+                self.channel2.output(self.registers[NR21]),
+            ),
+            (self.channel3.enabled, self.wave_output()),
+            (self.channel4.enabled, self.channel4.output()),
+        ];
+        let panning = self.registers[NR51];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, &(enabled, output)) in outputs.iter().enumerate() {
+            // the DAC turns 0..=15 into -1.0..=1.0, a stopped channel is silent
+            let analog = match enabled {
+                true => output as f32 / 7.5 - 1.0,
+                false => 0.0,
+            };
+            if panning & (1 << (i + 4)) != 0 {
+                left += analog;
+            }
+            if panning & (1 << i) != 0 {
+                right += analog;
+            }
+        }
+        let left_volume = ((self.registers[NR50] >> 4) & 0b111) as f32 + 1.0;
+        let right_volume = (self.registers[NR50] & 0b111) as f32 + 1.0;
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        let cycles = cycles as u32;
+        if self.registers[NR52] & POWER != 0 {
+            self.channel1.step(cycles);
+            self.channel2.step(cycles);
+            self.channel3.step(cycles);
+            self.channel4.step(cycles, self.registers[NR43]);
+            self.sequencer_clock += cycles;
+            while self.sequencer_clock >= FRAME_SEQUENCER_PERIOD {
+                self.sequencer_clock -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer();
+            }
+        }
+        self.sample_clock += cycles * SAMPLE_RATE;
+        while self.sample_clock >= CPU_FREQUENCY {
+            self.sample_clock -= CPU_FREQUENCY;
+            let (left, right) = if self.registers[NR52] & POWER != 0 {
+                self.mix()
+            } else {
+                (0.0, 0.0)
+            };
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_u8(state, self.volume);
+        write_u8(state, self.period);
+        write_bool(state, self.increase);
+        write_u8(state, self.timer);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.volume = read_u8(state)?;
+        self.period = read_u8(state)?;
+        self.increase = read_bool(state)?;
+        self.timer = read_u8(state)?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bool(state, self.enabled);
+        write_u16(state, self.length);
+        write_u16(state, self.frequency);
+        write_u32(state, self.timer);
+        write_u8(state, self.duty_step as u8);
+        self.envelope.save_state(state);
+        write_bool(state, self.sweep.enabled);
+        write_u16(state, self.sweep.shadow_frequency);
+        write_u8(state, self.sweep.timer);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.enabled = read_bool(state)?;
+        self.length = read_u16(state)?;
+        self.frequency = read_u16(state)?;
+        self.timer = read_u32(state)?;
+        self.duty_step = read_u8(state)? as usize % 8;
+        self.envelope.load_state(state)?;
+        self.sweep.enabled = read_bool(state)?;
+        self.sweep.shadow_frequency = read_u16(state)?;
+        self.sweep.timer = read_u8(state)?;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bool(state, self.enabled);
+        write_u16(state, self.length);
+        write_u16(state, self.frequency);
+        write_u32(state, self.timer);
+        write_u8(state, self.position as u8);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.enabled = read_bool(state)?;
+        self.length = read_u16(state)?;
+        self.frequency = read_u16(state)?;
+        self.timer = read_u32(state)?;
+        self.position = read_u8(state)? as usize % 32;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bool(state, self.enabled);
+        write_u16(state, self.length);
+        write_u32(state, self.timer);
+        write_u16(state, self.lfsr);
+        self.envelope.save_state(state);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.enabled = read_bool(state)?;
+        self.length = read_u16(state)?;
+        self.timer = read_u32(state)?;
+        self.lfsr = read_u16(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+impl SaveState for APU {
+    // buffered samples belong to the frontend and aren't saved
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bytes(state, &self.registers);
+        self.channel1.save_state(state);
+        self.channel2.save_state(state);
+        self.channel3.save_state(state);
+        self.channel4.save_state(state);
+        write_u32(state, self.sequencer_clock);
+        write_u8(state, self.sequencer_step);
+        write_u32(state, self.sample_clock);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        read_bytes(state, &mut self.registers)?;
+        self.channel1.load_state(state)?;
+        self.channel2.load_state(state)?;
+        self.channel3.load_state(state)?;
+        self.channel4.load_state(state)?;
+        self.sequencer_clock = read_u32(state)?;
+        self.sequencer_step = read_u8(state)? % 8;
+        self.sample_clock = read_u32(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(apu: &mut APU, register: usize, byte: u8) {
+        apu.write_byte(APU_FIRST + register as u16, byte);
+    }
+
+    // channel 2 at full volume on both sides
+    fn playing() -> APU {
+        let mut apu = APU::new();
+        write(&mut apu, NR52, POWER);
+        write(&mut apu, NR50, 0x77);
+        write(&mut apu, NR51, 0xFF);
+        write(&mut apu, NR21, 0x80);
+        write(&mut apu, NR22, 0xF0);
+        write(&mut apu, NR23, 0x00);
+        write(&mut apu, NR24, TRIGGER | 0x07);
+        apu
+    }
+
+    #[test]
+    fn an_enabled_channel_plays_in_range() {
+        let mut apu = playing();
+        assert_eq!(apu.read_byte(APU_FIRST + NR52 as u16), 0xF2);
+        // a frame's worth
+        for _ in 0..70224 / 4 {
+            apu.step(4);
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len() / 2, 738);
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        assert!(samples.iter().any(|&sample| sample > 0.0));
+        assert!(samples.iter().any(|&sample| sample < 0.0));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn samples_nobody_takes_stop_at_a_second() {
+        let mut apu = playing();
+        for _ in 0..2 * CPU_FREQUENCY / 128 {
+            apu.step(128);
+        }
+        assert_eq!(apu.take_samples().len(), MAX_BUFFERED_SAMPLES);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::{
+    interrupt::INTERRUPT_ENABLE,
+    memory_bus::{MemoryBus, IO_SIZE},
+    rtc::REGISTER_COUNT,
+    save_state::{read_bytes, read_u16, read_u32, read_u8, write_bytes, write_u16, write_u32},
+    GameBoy,
+};
+use std::{
+    io::{self, Error, ErrorKind},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// BESS (Best Effort Save State, https://github.com/LIJI32/SameBoy/blob/master/BESS.md)
+// is a list of blocks appended to an emulator's own state, found through a footer
+// holding the offset of the first block and the "BESS" magic. Memory regions are
+// stored outside of the blocks and referenced by size and offset into the file.
+
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const FOOTER_SIZE: usize = 8;
+
+const BLOCK_NAME: &[u8; 4] = b"NAME";
+const BLOCK_INFO: &[u8; 4] = b"INFO";
+const BLOCK_CORE: &[u8; 4] = b"CORE";
+const BLOCK_MBC: &[u8; 4] = b"MBC ";
+const BLOCK_RTC: &[u8; 4] = b"RTC ";
+const BLOCK_END: &[u8; 4] = b"END ";
+
+const CORE_MAJOR_VERSION: u16 = 1;
+const CORE_MINOR_VERSION: u16 = 1;
+const CORE_SIZE: usize = 0xD0;
+// original Game Boy, revision not specified
+const MODEL: &[u8; 4] = b"GD  ";
+// current and latched registers as 32 bit values and a 64 bit UNIX timestamp
+const RTC_SIZE: usize = 0x30;
+const MBC_WRITE_SIZE: usize = 3;
+
+const EXECUTION_RUNNING: u8 = 0;
+const EXECUTION_HALTED: u8 = 1;
+const EXECUTION_STOPPED: u8 = 2;
+
+const TITLE_FIRST: u16 = 0x0134;
+const TITLE_SIZE: usize = 0x10;
+const GLOBAL_CHECKSUM: u16 = 0x014E;
+
+const WORKING_RAM_FIRST: u16 = 0xC000;
+const WORKING_RAM_SIZE: usize = 0x2000;
+const VIDEO_RAM_FIRST: u16 = 0x8000;
+const VIDEO_RAM_SIZE: usize = 0x2000;
+const OAM_FIRST: u16 = 0xFE00;
+const OAM_SIZE: usize = 0xA0;
+const HIGH_RAM_FIRST: u16 = 0xFF80;
+const HIGH_RAM_SIZE: usize = 0x7F;
+// index into the buffer table of the CORE block, first address and size
+const REGIONS: [(usize, u16, usize); 4] = [
+    (0, WORKING_RAM_FIRST, WORKING_RAM_SIZE),
+    (1, VIDEO_RAM_FIRST, VIDEO_RAM_SIZE),
+    (3, OAM_FIRST, OAM_SIZE),
+    (4, HIGH_RAM_FIRST, HIGH_RAM_SIZE),
+];
+const CARTRIDGE_RAM_BUFFER: usize = 2;
+const IO_FIRST: u16 = 0xFF00;
+const BOOT_ROM_DISABLE: u16 = 0xFF50;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("BESS: {}", message))
+}
+
+fn read_region(bus: &MemoryBus, first: u16, size: usize) -> Vec<u8> {
+    (0..size).map(|i| bus.peek_byte(first + i as u16)).collect()
+}
+
+fn write_block(state: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    write_bytes(state, id);
+    write_u32(state, data.len() as u32);
+    write_bytes(state, data);
+}
+
+/// Writes our own save state followed by BESS blocks, so the file loads both
+/// here and in other emulators. Cartridges with a bank controller get an MBC
+/// block and those with a clock an RTC block.
+pub fn save_bess(game_boy: &GameBoy) -> Vec<u8> {
+    let cpu = game_boy.cpu();
+    let bus = cpu.bus();
+    let cartridge = bus.cartridge();
+    let mut state = game_boy.save_state();
+
+    // (size, offset) of RAM, VRAM, MBC RAM, OAM, HRAM and both colour palettes
+    let mut buffers = [(0, 0); 7];
+    for (buffer, first, size) in REGIONS {
+        buffers[buffer] = (size as u32, state.len() as u32);
+        write_bytes(&mut state, &read_region(bus, first, size));
+    }
+    buffers[CARTRIDGE_RAM_BUFFER] = (cartridge.ram().len() as u32, state.len() as u32);
+    write_bytes(&mut state, cartridge.ram());
+
+    let first_block = state.len() as u32;
+    write_block(&mut state, BLOCK_NAME, b"rust-boy");
+
+    let mut info = read_region(bus, TITLE_FIRST, TITLE_SIZE);
+    info.extend(read_region(bus, GLOBAL_CHECKSUM, 2));
+    write_block(&mut state, BLOCK_INFO, &info);
+
+    let registers = cpu.registers();
+    let mut core = Vec::with_capacity(CORE_SIZE);
+    write_u16(&mut core, CORE_MAJOR_VERSION);
+    write_u16(&mut core, CORE_MINOR_VERSION);
+    write_bytes(&mut core, MODEL);
+    write_u16(&mut core, cpu.pc());
+    for register in [
+        registers.get_af(),
+        registers.get_bc(),
+        registers.get_de(),
+        registers.get_hl(),
+    ] {
+        write_u16(&mut core, register);
+    }
+    write_u16(&mut core, cpu.sp());
+    core.push(cpu.interrupts_enabled() as u8);
+    core.push(bus.peek_byte(INTERRUPT_ENABLE));
+    core.push(if cpu.is_halted() {
+        EXECUTION_HALTED
+    } else {
+        EXECUTION_RUNNING
+    });
+    core.push(0);
+    let mut io = read_region(bus, IO_FIRST, IO_SIZE);
+    io[(BOOT_ROM_DISABLE - IO_FIRST) as usize] = !bus.boot_rom_enabled() as u8;
+    write_bytes(&mut core, &io);
+    for (size, offset) in buffers {
+        write_u32(&mut core, size);
+        write_u32(&mut core, offset);
+    }
+    write_block(&mut state, BLOCK_CORE, &core);
+
+    let bank_writes = cartridge.bank_writes();
+    if !bank_writes.is_empty() {
+        let mut mbc = Vec::with_capacity(bank_writes.len() * MBC_WRITE_SIZE);
+        for (address, value) in bank_writes {
+            write_u16(&mut mbc, address);
+            mbc.push(value);
+        }
+        write_block(&mut state, BLOCK_MBC, &mbc);
+    }
+    if let Some(clock) = cartridge.rtc() {
+        let mut rtc = Vec::with_capacity(RTC_SIZE);
+        for register in clock.registers().into_iter().chain(clock.latched()) {
+            write_u32(&mut rtc, register as u32);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        write_bytes(&mut rtc, &now.to_le_bytes());
+        write_block(&mut state, BLOCK_RTC, &rtc);
+    }
+    write_block(&mut state, BLOCK_END, &[]);
+
+    write_u32(&mut state, first_block);
+    write_bytes(&mut state, FOOTER_MAGIC);
+    state
+}
+
+/// Loads the BESS blocks of a state written by any emulator. Unknown blocks
+/// are skipped, the writes of the MBC block are replayed into the cartridge and
+/// PPU and APU internals restart from the registers. The clock counts emulated
+/// time, so the RTC block sets it without adding the time since it was saved.
+/// Nothing is changed unless the whole file is valid.
+pub fn load_bess(game_boy: &mut GameBoy, file: &[u8]) -> io::Result<()> {
+    if file.len() < FOOTER_SIZE || &file[file.len() - 4..] != FOOTER_MAGIC {
+        return Err(invalid("footer not found"));
+    }
+    let mut footer = &file[file.len() - FOOTER_SIZE..];
+    let first_block = read_u32(&mut footer)? as usize;
+    let mut blocks = file
+        .get(first_block..file.len() - FOOTER_SIZE)
+        .ok_or_else(|| invalid("first block out of range"))?;
+
+    let mut core = None;
+    let mut bank_writes = Vec::new();
+    let mut clock = None;
+    loop {
+        if blocks.is_empty() {
+            return Err(invalid("END block missing"));
+        }
+        let mut id = [0; 4];
+        read_bytes(&mut blocks, &mut id)?;
+        let length = read_u32(&mut blocks)? as usize;
+        if blocks.len() < length {
+            return Err(invalid("block truncated"));
+        }
+        let (data, rest) = blocks.split_at(length);
+        blocks = rest;
+        match &id {
+            BLOCK_END => break,
+            BLOCK_CORE if length < CORE_SIZE => return Err(invalid("CORE block too short")),
+            BLOCK_CORE => core = Some(data),
+            BLOCK_MBC if !length.is_multiple_of(MBC_WRITE_SIZE) => {
+                return Err(invalid("MBC block malformed"))
+            }
+            BLOCK_MBC => {
+                for mut write in data.chunks_exact(MBC_WRITE_SIZE) {
+                    let address = read_u16(&mut write)?;
+                    if !matches!(address, 0x0000..=0x7FFF | 0xA000..=0xBFFF) {
+                        return Err(invalid("MBC block writes outside the cartridge"));
+                    }
+                    bank_writes.push((address, write[0]));
+                }
+            }
+            BLOCK_RTC if length != RTC_SIZE => return Err(invalid("RTC block malformed")),
+            BLOCK_RTC => {
+                let mut data = data;
+                let mut registers = [[0; REGISTER_COUNT]; 2];
+                for register in registers.iter_mut().flatten() {
+                    *register = read_u32(&mut data)? as u8;
+                }
+                clock = Some(registers);
+            }
+            // NAME and INFO are informational, anything else is for other emulators
+            _ => {}
+        }
+    }
+    let mut core = core.ok_or_else(|| invalid("CORE block missing"))?;
+
+    if read_u16(&mut core)? != CORE_MAJOR_VERSION {
+        return Err(invalid("unsupported CORE version"));
+    }
+    read_u16(&mut core)?;
+    let mut model = [0; 4];
+    read_bytes(&mut core, &mut model)?;
+    if model[0] != MODEL[0] {
+        return Err(invalid("only original Game Boy states are supported"));
+    }
+    let pc = read_u16(&mut core)?;
+    let af = read_u16(&mut core)?.to_be_bytes();
+    let bc = read_u16(&mut core)?.to_be_bytes();
+    let de = read_u16(&mut core)?.to_be_bytes();
+    let hl = read_u16(&mut core)?.to_be_bytes();
+    let sp = read_u16(&mut core)?;
+    let ime = read_u8(&mut core)? != 0;
+    let interrupt_enable = read_u8(&mut core)?;
+    let execution = read_u8(&mut core)?;
+    read_u8(&mut core)?;
+    let mut io = [0; IO_SIZE];
+    read_bytes(&mut core, &mut io)?;
+    let mut buffers = [&[][..]; 7];
+    for buffer in buffers.iter_mut() {
+        let size = read_u32(&mut core)? as usize;
+        let offset = read_u32(&mut core)? as usize;
+        *buffer = offset
+            .checked_add(size)
+            .and_then(|end| file.get(offset..end))
+            .ok_or_else(|| invalid("memory buffer out of range"))?;
+    }
+
+    let cpu = game_boy.cpu_mut();
+    cpu.set_pc(pc);
+    cpu.set_sp(sp);
+    cpu.set_interrupts_enabled(ime);
+    cpu.set_halted(matches!(execution, EXECUTION_HALTED | EXECUTION_STOPPED));
+    let registers = cpu.registers_mut();
+    [registers.a, registers.b, registers.d, registers.h] = [af[0], bc[0], de[0], hl[0]];
+    [registers.c, registers.e, registers.l] = [bc[1], de[1], hl[1]];
+    registers.f = af[1].into();
+
+    let bus = cpu.bus_mut();
+    for (buffer, first, size) in REGIONS {
+        for (i, &byte) in buffers[buffer].iter().take(size).enumerate() {
+            bus.write_byte(first + i as u16, byte);
+        }
+    }
+    bus.restore_io_registers(&io);
+    bus.write_byte(INTERRUPT_ENABLE, interrupt_enable);
+
+    let cartridge = bus.cartridge_mut();
+    let ram = buffers[CARTRIDGE_RAM_BUFFER];
+    let size = ram.len().min(cartridge.ram().len());
+    cartridge.ram_mut()[..size].copy_from_slice(&ram[..size]);
+    cartridge.reset();
+    for (address, value) in bank_writes {
+        match address {
+            0xA000..=0xBFFF => cartridge.write_ram(address, value),
+            _ => cartridge.write_rom(address, value),
+        }
+    }
+    // states of cartridges without a clock are loaded without one
+    if let (Some([registers, latched]), Some(rtc)) = (clock, cartridge.rtc_mut()) {
+        rtc.restore(registers, latched);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::MINUTES;
+
+    // MBC3 with a clock and 32 KB of ram
+    fn game_boy() -> GameBoy {
+        let mut rom = vec![0; 4 * 0x4000];
+        rom[0x147] = 0x10;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x03;
+        GameBoy::new(vec![0; 0x100], &rom).unwrap()
+    }
+
+    // a state in which every part BESS knows about differs from power on
+    fn saved() -> (GameBoy, Vec<u8>) {
+        let mut game_boy = game_boy();
+        game_boy.run_frame();
+        let cpu = game_boy.cpu_mut();
+        cpu.set_pc(0x1234);
+        cpu.registers_mut().a = 0x56;
+        let bus = cpu.bus_mut();
+        bus.write_byte(0xC010, 0x78);
+        bus.write_byte(0x0000, 0x0A);
+        bus.write_byte(0x2000, 3);
+        bus.write_byte(0x4000, 2);
+        bus.write_byte(0xA000, 0x9A);
+        bus.cartridge_mut().rtc_mut().unwrap().write(MINUTES, 42);
+        let state = save_bess(&game_boy);
+        (game_boy, state)
+    }
+
+    #[test]
+    fn round_trip_keeps_cpu_memory_banks_and_clock() {
+        let (saved, state) = saved();
+        let mut game_boy = game_boy();
+        load_bess(&mut game_boy, &state).unwrap();
+        let cpu = game_boy.cpu();
+        assert_eq!((cpu.pc(), cpu.registers().a), (0x1234, 0x56));
+        let bus = cpu.bus();
+        assert_eq!(bus.peek_byte(0xC010), 0x78);
+        assert_eq!(bus.peek_byte(0xA000), 0x9A);
+        assert_eq!(bus.cartridge().ram()[2 * 0x2000], 0x9A);
+        assert_eq!(
+            bus.cartridge().bank_writes(),
+            saved.cpu().bus().cartridge().bank_writes()
+        );
+        assert_eq!(bus.cartridge().rtc().unwrap().registers()[MINUTES], 42);
+    }
+
+    #[test]
+    fn invalid_files_change_nothing() {
+        let (_, state) = saved();
+        let mut game_boy = game_boy();
+        let before = game_boy.save_state();
+        assert!(load_bess(&mut game_boy, &state[..state.len() - 1]).is_err());
+        // the address of the first MBC write moved into the I/O registers
+        let mbc = state.windows(4).rposition(|id| id == BLOCK_MBC).unwrap();
+        let mut bad = state.clone();
+        bad[mbc + 8..mbc + 10].copy_from_slice(&0xFF00u16.to_le_bytes());
+        assert!(load_bess(&mut game_boy, &bad).is_err());
+        assert_eq!(game_boy.save_state(), before);
+    }
+}
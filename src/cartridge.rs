@@ -0,0 +1,413 @@
+use crate::{
+    rtc::{self, Rtc},
+    save_state::{read_bool, read_bytes, read_u8, write_bool, write_bytes, write_u8, SaveState},
+};
+use std::io::{self, Error, ErrorKind};
+
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+// the smallest cartridge fills both rom banks of the address space
+const MIN_ROM_SIZE: usize = 2 * ROM_BANK_SIZE;
+// an MBC1 selects banks with 5 + 2 bits
+const MBC1_MAX_ROM_SIZE: usize = 128 * ROM_BANK_SIZE;
+const MBC1_MAX_RAM_SIZE: usize = 4 * RAM_BANK_SIZE;
+// an MBC3 selects rom banks with 7 bits and has 4 ram banks
+const MBC3_MAX_ROM_SIZE: usize = 128 * ROM_BANK_SIZE;
+const MBC3_MAX_RAM_SIZE: usize = 4 * RAM_BANK_SIZE;
+// values of the MBC3 ram bank register that select a clock register instead
+const MBC3_RTC_FIRST: u8 = 0x08;
+const MBC3_RTC_LAST: u8 = 0x0C;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc3,
+}
+
+/// The game pak: its ROM, the RAM on the board and the registers of the memory
+/// bank controller that maps them into 0000-7FFF and A000-BFFF.
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mapper: Mapper,
+    ram_enabled: bool,
+    // lower 5 bits of the rom bank at 4000-7FFF, all 7 on an MBC3
+    rom_bank: u8,
+    // upper rom bank bits, or the ram bank in advanced banking mode. The ram bank or
+    // clock register on an MBC3
+    bank2: u8,
+    advanced_banking: bool,
+    rtc: Option<Rtc>,
+}
+
+impl Default for Cartridge {
+    // no cartridge inserted, reads see an empty rom
+    fn default() -> Self {
+        Cartridge::new(&[]).unwrap()
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Cartridge {
+    // roms without a header are taken as ROM only, short ones are padded
+    pub fn new(rom: &[u8]) -> io::Result<Self> {
+        let header = |offset: usize| rom.get(offset).copied().unwrap_or(0);
+        let (mapper, has_ram, has_clock) = match header(CARTRIDGE_TYPE) {
+            0x00 => (Mapper::RomOnly, false, false),
+            0x01 => (Mapper::Mbc1, false, false),
+            0x02 | 0x03 => (Mapper::Mbc1, true, false),
+            0x08 | 0x09 => (Mapper::RomOnly, true, false),
+            0x0F => (Mapper::Mbc3, false, true),
+            0x10 => (Mapper::Mbc3, true, true),
+            0x11 => (Mapper::Mbc3, false, false),
+            0x12 | 0x13 => (Mapper::Mbc3, true, false),
+            kind => {
+                return Err(invalid(format!(
+                    "unsupported cartridge type 0x{:02X}",
+                    kind
+                )))
+            }
+        };
+        let max_rom_size = match mapper {
+            Mapper::RomOnly => MIN_ROM_SIZE,
+            Mapper::Mbc1 => MBC1_MAX_ROM_SIZE,
+            Mapper::Mbc3 => MBC3_MAX_ROM_SIZE,
+        };
+        if rom.len() > max_rom_size {
+            return Err(invalid(format!(
+                "a {:?} cartridge holds at most {} KB of rom, this one is {} KB",
+                mapper,
+                max_rom_size / 1024,
+                rom.len() / 1024
+            )));
+        }
+        let ram_size = match header(RAM_SIZE) {
+            _ if !has_ram => 0,
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            size => return Err(invalid(format!("unknown ram size 0x{:02X}", size))),
+        };
+        let max_ram_size = match mapper {
+            Mapper::RomOnly => RAM_BANK_SIZE,
+            Mapper::Mbc1 => MBC1_MAX_RAM_SIZE,
+            Mapper::Mbc3 => MBC3_MAX_RAM_SIZE,
+        };
+        if ram_size > max_ram_size {
+            return Err(invalid(format!(
+                "a {:?} cartridge can't map {} KB of ram",
+                mapper,
+                ram_size / 1024
+            )));
+        }
+        // the header tells the size in banks, padding keeps bank numbers in range
+        let declared_size = MIN_ROM_SIZE << header(ROM_SIZE).min(8);
+        let mut padded = rom.to_vec();
+        padded.resize(
+            rom.len()
+                .max(declared_size)
+                .clamp(MIN_ROM_SIZE, max_rom_size),
+            0,
+        );
+        let mut cartridge = Cartridge {
+            rom: padded,
+            ram: vec![0; ram_size],
+            mapper,
+            ram_enabled: false,
+            rom_bank: 0,
+            bank2: 0,
+            advanced_banking: false,
+            rtc: has_clock.then(Rtc::new),
+        };
+        cartridge.reset();
+        Ok(cartridge)
+    }
+    // the registers power up cleared, the ram and the clock keep running on the battery
+    pub fn reset(&mut self) {
+        self.ram_enabled = self.mapper == Mapper::RomOnly;
+        self.rom_bank = 1;
+        self.bank2 = 0;
+        self.advanced_banking = false;
+    }
+    // bytes save_state writes, the ram and the clock depend on the header
+    pub fn state_size(&self) -> usize {
+        let clock = self.rtc.as_ref().map_or(0, |_| rtc::STATE_SIZE);
+        self.ram.len() + 4 + clock
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+    // the bank register writes that bring a freshly reset controller to this state
+    pub fn bank_writes(&self) -> Vec<(u16, u8)> {
+        let ram_enable = match self.ram_enabled {
+            true => 0x0A,
+            false => 0x00,
+        };
+        match self.mapper {
+            Mapper::RomOnly => Vec::new(),
+            Mapper::Mbc1 => vec![
+                (0x0000, ram_enable),
+                (0x2000, self.rom_bank),
+                (0x4000, self.bank2),
+                (0x6000, self.advanced_banking as u8),
+            ],
+            Mapper::Mbc3 => vec![
+                (0x0000, ram_enable),
+                (0x2000, self.rom_bank),
+                (0x4000, self.bank2),
+            ],
+        }
+    }
+    pub fn step(&mut self, cycles: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.step(cycles);
+        }
+    }
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len() / ROM_BANK_SIZE
+    }
+    // offset into the rom file of the byte mapped at 0000-7FFF
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let address = address as usize;
+        let bank = match self.mapper {
+            Mapper::RomOnly => address / ROM_BANK_SIZE,
+            Mapper::Mbc1 if address < ROM_BANK_SIZE => match self.advanced_banking {
+                true => (self.bank2 as usize) << 5,
+                false => 0,
+            },
+            Mapper::Mbc1 => (self.bank2 as usize) << 5 | self.rom_bank as usize,
+            Mapper::Mbc3 if address < ROM_BANK_SIZE => 0,
+            Mapper::Mbc3 => self.rom_bank as usize,
+        };
+        (bank % self.rom_bank_count()) * ROM_BANK_SIZE + address % ROM_BANK_SIZE
+    }
+    // offset into the ram of the byte mapped at A000-BFFF
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let bank = match self.mapper {
+            Mapper::Mbc1 if self.advanced_banking => self.bank2 as usize,
+            Mapper::Mbc3 if self.bank2 > 0b11 => return None,
+            Mapper::Mbc3 => self.bank2 as usize,
+            _ => 0,
+        };
+        Some((bank * RAM_BANK_SIZE + (address as usize - 0xA000)) % self.ram.len())
+    }
+    // the clock register an MBC3 maps at A000-BFFF instead of ram
+    fn rtc_register(&self) -> Option<usize> {
+        match (self.rtc.is_some() && self.ram_enabled, self.bank2) {
+            (true, MBC3_RTC_FIRST..=MBC3_RTC_LAST) => Some((self.bank2 - MBC3_RTC_FIRST) as usize),
+            _ => None,
+        }
+    }
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.rom[self.rom_offset(address)]
+    }
+    // writes to the rom area set the bank controller registers
+    pub fn write_rom(&mut self, address: u16, byte: u8) {
+        match (self.mapper, address) {
+            (Mapper::RomOnly, _) => {}
+            (_, 0x0000..=0x1FFF) => self.ram_enabled = byte & 0x0F == 0x0A,
+            // bank 0 can't be selected at 4000-7FFF, it maps bank 1 instead
+            (Mapper::Mbc1, 0x2000..=0x3FFF) => self.rom_bank = (byte & 0x1F).max(1),
+            (Mapper::Mbc1, 0x4000..=0x5FFF) => self.bank2 = byte & 0b11,
+            (Mapper::Mbc1, _) => self.advanced_banking = byte & 1 != 0,
+            (Mapper::Mbc3, 0x2000..=0x3FFF) => self.rom_bank = (byte & 0x7F).max(1),
+            (Mapper::Mbc3, 0x4000..=0x5FFF) => self.bank2 = byte & 0x0F,
+            (Mapper::Mbc3, _) => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.latch(byte);
+                }
+            }
+        }
+    }
+    // disabled or missing ram reads open bus
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if let (Some(register), Some(rtc)) = (self.rtc_register(), &self.rtc) {
+            return rtc.read(register);
+        }
+        self.ram_offset(address)
+            .map_or(0xFF, |offset| self.ram[offset])
+    }
+    pub fn write_ram(&mut self, address: u16, byte: u8) {
+        if let Some(register) = self.rtc_register() {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.write(register, byte);
+            }
+            return;
+        }
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = byte;
+        }
+    }
+}
+
+impl SaveState for Cartridge {
+    // the rom is loaded from its file, only the ram, the bank registers and the clock
+    // are saved
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bytes(state, &self.ram);
+        write_bool(state, self.ram_enabled);
+        write_u8(state, self.rom_bank);
+        write_u8(state, self.bank2);
+        write_bool(state, self.advanced_banking);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        read_bytes(state, &mut self.ram)?;
+        self.ram_enabled = read_bool(state)?;
+        let (rom_bank_mask, bank2_mask) = match self.mapper {
+            Mapper::Mbc3 => (0x7F, 0x0F),
+            _ => (0x1F, 0b11),
+        };
+        self.rom_bank = (read_u8(state)? & rom_bank_mask).max(1);
+        self.bank2 = read_u8(state)? & bank2_mask;
+        self.advanced_banking = read_bool(state)?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc;
+
+    // every bank starts with its own number
+    fn with_header(banks: usize, kind: u8, ram_size: u8) -> Cartridge {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom[CARTRIDGE_TYPE] = kind;
+        rom[RAM_SIZE] = ram_size;
+        Cartridge::new(&rom).unwrap()
+    }
+
+    #[test]
+    fn mbc1_switches_rom_banks() {
+        let mut cartridge = with_header(64, 0x01, 0);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+        cartridge.write_rom(0x2000, 5);
+        assert_eq!(cartridge.read_rom(0x4000), 5);
+        // bank 0 maps bank 1, and bank2 supplies bits 5-6
+        cartridge.write_rom(0x2000, 0);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+        cartridge.write_rom(0x4000, 1);
+        assert_eq!(cartridge.read_rom(0x4000), 33);
+        assert_eq!(cartridge.rom_offset(0x4001), 33 * ROM_BANK_SIZE + 1);
+        assert_eq!(cartridge.read_rom(0x0000), 0);
+        // numbers past the end of a smaller rom wrap around
+        cartridge.write_rom(0x4000, 2);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+    }
+
+    #[test]
+    fn mbc1_ram_is_enabled_and_banked() {
+        let mut cartridge = with_header(4, 0x03, 0x03);
+        cartridge.write_ram(0xA000, 1);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF, "disabled after power on");
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 1);
+        cartridge.write_rom(0x6000, 1);
+        cartridge.write_rom(0x4000, 2);
+        cartridge.write_ram(0xA000, 3);
+        assert_eq!(cartridge.ram[0], 1);
+        assert_eq!(cartridge.ram[2 * RAM_BANK_SIZE], 3);
+        assert_eq!(cartridge.read_ram(0xA000), 3);
+        cartridge.write_rom(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_switches_rom_banks_and_maps_the_clock() {
+        let mut cartridge = with_header(128, 0x10, 0x03);
+        cartridge.write_rom(0x2000, 0x45);
+        assert_eq!(cartridge.read_rom(0x4000), 0x45);
+        assert_eq!(cartridge.read_rom(0x0000), 0);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 3);
+        cartridge.write_ram(0xA000, 0x33);
+        assert_eq!(cartridge.ram()[3 * RAM_BANK_SIZE], 0x33);
+        // 08-0C select the clock registers
+        cartridge.write_rom(0x4000, 0x0A);
+        cartridge.write_ram(0xA000, 5);
+        assert_eq!(cartridge.read_ram(0xA000), 5);
+        assert_eq!(cartridge.rtc().unwrap().registers()[rtc::HOURS], 5);
+        assert_eq!(cartridge.ram()[3 * RAM_BANK_SIZE], 0x33);
+        // a clock second later the latched hours are still the same
+        cartridge.write_rom(0x4000, 0x08);
+        cartridge.write_ram(0xA000, 59);
+        for _ in 0..4_194_304 / 128 {
+            cartridge.step(128);
+        }
+        assert_eq!(cartridge.read_ram(0xA000), 59);
+        cartridge.write_rom(0x6000, 0);
+        cartridge.write_rom(0x6000, 1);
+        assert_eq!(cartridge.read_ram(0xA000), 0);
+        assert!(with_header(4, 0x13, 0x02).rtc().is_none());
+    }
+
+    #[test]
+    fn headers_decide_the_ram_and_what_loads() {
+        assert!(
+            with_header(4, 0x01, 0x03).ram.is_empty(),
+            "no ram without the type saying so"
+        );
+        assert_eq!(with_header(4, 0x03, 0x02).ram.len(), RAM_BANK_SIZE);
+        assert!(Cartridge::new(&[]).unwrap().ram.is_empty());
+
+        let mut rom = vec![0; 4 * ROM_BANK_SIZE];
+        assert!(Cartridge::new(&rom).is_err(), "ROM only can't hold 64 KB");
+        rom[CARTRIDGE_TYPE] = 0x19;
+        assert!(Cartridge::new(&rom).is_err(), "MBC5 isn't emulated");
+    }
+
+    #[test]
+    fn save_state_keeps_ram_and_banks() {
+        let mut cartridge = with_header(8, 0x03, 0x02);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x2000, 6);
+        cartridge.write_ram(0xA123, 0x42);
+        let mut state = Vec::new();
+        cartridge.save_state(&mut state);
+        let mut loaded = with_header(8, 0x03, 0x02);
+        loaded.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(loaded.read_rom(0x4000), 6);
+        assert_eq!(loaded.read_ram(0xA123), 0x42);
+
+        let mut cartridge = with_header(8, 0x10, 0x02);
+        cartridge.rtc_mut().unwrap().write(rtc::MINUTES, 12);
+        let mut state = Vec::new();
+        cartridge.save_state(&mut state);
+        let mut loaded = with_header(8, 0x10, 0x02);
+        loaded.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(loaded.rtc().unwrap().registers()[rtc::MINUTES], 12);
+        assert_eq!(loaded.bank_writes(), cartridge.bank_writes());
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::{Error, ErrorKind};
+
+// the flag bits BizHawk uses for Game Boy code/data logs
+pub const OPCODE: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+
+/// Code/Data Log: a byte of flags per ROM byte telling how the running game
+/// used it, executed as an opcode, executed as an operand, read as data or
+/// not at all when it's zero.
+pub struct CodeDataLog {
+    flags: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(rom_size: usize) -> Self {
+        CodeDataLog {
+            flags: vec![0; rom_size],
+        }
+    }
+    // carries on from a log of an earlier session
+    pub fn load(log: &[u8], rom_size: usize) -> Result<Self, Error> {
+        if log.len() != rom_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "code/data log doesn't match the rom size",
+            ));
+        }
+        Ok(CodeDataLog {
+            flags: log.to_vec(),
+        })
+    }
+    pub fn save(&self) -> &[u8] {
+        &self.flags
+    }
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).copied().unwrap_or(0)
+    }
+    // read as data and never run
+    pub fn is_data(&self, offset: usize) -> bool {
+        self.flags(offset) & (DATA | OPCODE | OPERAND) == DATA
+    }
+    pub fn mark(&mut self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game_boy::rom_with, GameBoy};
+
+    #[test]
+    fn flags_tell_opcodes_operands_and_data_apart() {
+        // LD A,($0150); JR -5 back to it
+        let rom = rom_with(&[0xFA, 0x50, 0x01, 0x18, 0xFB]);
+        let mut game_boy = GameBoy::new(vec![0; 0x100], &rom).unwrap();
+        game_boy.set_code_data_log(Some(CodeDataLog::new(rom.len())));
+        game_boy.run_frame();
+        let log = game_boy.take_code_data_log().unwrap();
+        let flags: Vec<u8> = (0x100..0x106).map(|offset| log.flags(offset)).collect();
+        assert_eq!(flags, [OPCODE, OPERAND, OPERAND, OPCODE, OPERAND, 0]);
+        assert_eq!(log.flags(0x150), DATA);
+        assert!(log.is_data(0x150));
+        assert!(!log.is_data(0x101));
+        // the boot rom ran from 0000 but isn't the cartridge's
+        assert_eq!(log.flags(0x00), 0);
+    }
+
+    #[test]
+    fn saved_logs_load_and_keep_adding() {
+        let mut log = CodeDataLog::new(0x8000);
+        log.mark(0x100, OPCODE);
+        log.mark(0x150, DATA);
+        let mut log = CodeDataLog::load(log.save(), 0x8000).unwrap();
+        log.mark(0x150, OPERAND);
+        log.mark(0x8000, DATA);
+        assert_eq!(log.flags(0x100), OPCODE);
+        assert_eq!(log.flags(0x150), DATA | OPERAND);
+        assert_eq!(log.save().len(), 0x8000);
+        assert!(CodeDataLog::load(log.save(), 0x4000).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+// deeper than any real program, code that calls without returning stops here
+const MAX_DEPTH: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    // where the CALL, RST or interrupt went
+    pub routine: u16,
+    pub return_address: u16,
+}
+
+/// Shadow of the calls the CPU made, kept next to the real stack in memory.
+/// A return that doesn't match any frame is a computed jump and leaves it be.
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack::default()
+    }
+    // outermost first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+    // the routine running now, None before the first call
+    pub fn routine(&self) -> Option<u16> {
+        self.frames.last().map(|frame| frame.routine)
+    }
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+    pub(crate) fn enter(&mut self, routine: u16, return_address: u16) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(Frame {
+            routine,
+            return_address,
+        });
+    }
+    // unwinds to the frame returning to address, skipping frames left by tail calls
+    pub(crate) fn exit(&mut self, address: u16) {
+        if let Some(index) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_address == address)
+        {
+            self.frames.truncate(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routines(call_stack: &CallStack) -> Vec<u16> {
+        call_stack
+            .frames()
+            .iter()
+            .map(|frame| frame.routine)
+            .collect()
+    }
+
+    #[test]
+    fn returns_unwind_to_their_frame() {
+        let mut call_stack = CallStack::new();
+        call_stack.enter(0x0200, 0x0103);
+        call_stack.enter(0x0300, 0x0205);
+        // a tail call, 0300 jumped on to 0400 which returns for both
+        call_stack.enter(0x0400, 0x0310);
+        call_stack.exit(0x0310);
+        assert_eq!(routines(&call_stack), [0x0200, 0x0300]);
+        call_stack.exit(0x0103);
+        assert!(call_stack.frames().is_empty());
+    }
+
+    #[test]
+    fn a_return_nothing_called_keeps_the_frames() {
+        let mut call_stack = CallStack::new();
+        call_stack.enter(0x0200, 0x0103);
+        call_stack.exit(0x1234);
+        assert_eq!(routines(&call_stack), [0x0200]);
+        assert_eq!(call_stack.routine(), Some(0x0200));
+    }
+
+    #[test]
+    fn the_outermost_calls_give_way_past_the_limit() {
+        let mut call_stack = CallStack::new();
+        for i in 0..MAX_DEPTH as u16 + 2 {
+            call_stack.enter(i, i);
+        }
+        assert_eq!(call_stack.frames().len(), MAX_DEPTH);
+        assert_eq!(call_stack.frames()[0].routine, 2);
+    }
+}
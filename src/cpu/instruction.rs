@@ -1,4 +1,28 @@
+use std::fmt;
+
 pub type InstructionSize = u8;
+
+// machine cycles in T-states, conditional instructions count the branch as not taken
+#[rustfmt::skip]
+const INSTRUCTION_CYCLES: [u8; 256] = [
+    4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4,
+    4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4,
+    8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+    8, 12, 8, 8, 12, 12, 12, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    8, 12, 12, 16, 12, 16, 8, 16, 8, 16, 12, 4, 12, 24, 8, 16,
+    8, 12, 12, 0, 12, 16, 8, 16, 8, 16, 12, 0, 12, 0, 8, 16,
+    12, 12, 8, 0, 0, 16, 8, 16, 16, 4, 16, 0, 0, 0, 8, 16,
+    12, 12, 8, 4, 0, 16, 8, 16, 12, 8, 16, 4, 0, 0, 8, 16,
+];
+#[derive(Debug)]
 pub enum Instruction {
     LD(LoadType),
     ADD(ArithmeticTarget),
@@ -48,6 +72,39 @@ pub enum Instruction {
     SET(u8, Target),
 }
 impl Instruction {
+    pub fn cycles(byte: u8, prefixed: bool, branch_taken: bool) -> u8 {
+        if prefixed {
+            return match byte {
+                0x40..=0x7F if byte & 0x07 == 0x06 => 12,
+                _ if byte & 0x07 == 0x06 => 16,
+                _ => 8,
+            };
+        }
+        let extra = match byte {
+            0x20 | 0x28 | 0x30 | 0x38 if branch_taken => 4,
+            0xC2 | 0xCA | 0xD2 | 0xDA if branch_taken => 4,
+            0xC0 | 0xC8 | 0xD0 | 0xD8 if branch_taken => 12,
+            0xC4 | 0xCC | 0xD4 | 0xDC if branch_taken => 12,
+            _ => 0,
+        };
+        INSTRUCTION_CYCLES[byte as usize] + extra
+    }
+    // bytes taken by the opcode and its operands
+    pub fn size(byte: u8, prefixed: bool) -> InstructionSize {
+        if prefixed {
+            return 2;
+        }
+        match byte {
+            0x01 | 0x08 | 0x11 | 0x21 | 0x31 | 0xEA | 0xFA => 3,
+            0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA => 3,
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => 3,
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => 2,
+            0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 2,
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => 2,
+            0xE0 | 0xE8 | 0xF0 | 0xF8 => 2,
+            _ => 1,
+        }
+    }
     pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
         if prefixed {
             Instruction::from_byte_prefixed(byte)
@@ -839,6 +896,7 @@ impl Instruction {
     }
 }
 pub type Source = Target;
+#[derive(Debug)]
 pub enum Target {
     A,
     B,
@@ -856,6 +914,7 @@ pub enum Target {
     D8,
 }
 
+#[derive(Debug)]
 pub enum ArithmeticTarget {
     A,
     B,
@@ -868,6 +927,7 @@ pub enum ArithmeticTarget {
     D8,
 }
 
+#[derive(Debug)]
 pub enum JumpTest {
     NotZero,
     Zero,
@@ -876,6 +936,7 @@ pub enum JumpTest {
     Always,
 }
 
+#[derive(Debug)]
 pub enum LoadByteTarget {
     A,
     B,
@@ -893,6 +954,7 @@ pub enum LoadByteTarget {
     HLINCR,
     HLDECR,
 }
+#[derive(Debug)]
 pub enum LoadByteSource {
     A,
     B,
@@ -911,6 +973,7 @@ pub enum LoadByteSource {
     HLINCR,
     HLDECR,
 }
+#[derive(Debug)]
 pub enum LoadWordTarget {
     BC,
     DE,
@@ -918,20 +981,235 @@ pub enum LoadWordTarget {
     N16I,
     SP,
 }
+#[derive(Debug)]
 pub enum LoadWordSource {
     D16,
     SP,
     HL,
 }
+#[derive(Debug)]
 pub enum LoadType {
     Byte(LoadByteTarget, LoadByteSource),
     Word(LoadWordTarget, LoadWordSource),
     HLFromSPN,
 }
 
+#[derive(Debug)]
 pub enum StackTarget {
     BC,
     DE,
     HL,
     AF,
 }
+
+// mnemonics follow the RGBDS manual, immediates are shown as the placeholders
+// n8, n16, a8, a16 and e8 until the disassembler fills in their values
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::LD(load) => write!(f, "{}", load),
+            Instruction::ADD(target) => write!(f, "ADD A,{}", target),
+            Instruction::ADDHL(source) => write!(f, "ADD HL,{}", source),
+            Instruction::ADDSP => write!(f, "ADD SP,e8"),
+            Instruction::ADC(source) => write!(f, "ADC A,{}", source),
+            Instruction::SUB(source) => write!(f, "SUB A,{}", source),
+            Instruction::SBC(source) => write!(f, "SBC A,{}", source),
+            Instruction::AND(source) => write!(f, "AND A,{}", source),
+            Instruction::XOR(source) => write!(f, "XOR A,{}", source),
+            Instruction::OR(source) => write!(f, "OR A,{}", source),
+            Instruction::CP(source) => write!(f, "CP A,{}", source),
+            Instruction::DAA => write!(f, "DAA"),
+            Instruction::SCF => write!(f, "SCF"),
+            Instruction::CPL => write!(f, "CPL"),
+            Instruction::CCF => write!(f, "CCF"),
+            Instruction::INC(target) => write!(f, "INC {}", target),
+            Instruction::DEC(target) => write!(f, "DEC {}", target),
+            Instruction::JP(JumpTest::Always) => write!(f, "JP a16"),
+            Instruction::JP(test) => write!(f, "JP {},a16", test),
+            Instruction::JR(JumpTest::Always) => write!(f, "JR e8"),
+            Instruction::JR(test) => write!(f, "JR {},e8", test),
+            Instruction::JPHL => write!(f, "JP HL"),
+            Instruction::PUSH(target) => write!(f, "PUSH {}", target),
+            Instruction::POP(target) => write!(f, "POP {}", target),
+            Instruction::CALL(JumpTest::Always) => write!(f, "CALL a16"),
+            Instruction::CALL(test) => write!(f, "CALL {},a16", test),
+            Instruction::RET(JumpTest::Always) => write!(f, "RET"),
+            Instruction::RET(test) => write!(f, "RET {}", test),
+            Instruction::RETI => write!(f, "RETI"),
+            Instruction::NOP => write!(f, "NOP"),
+            Instruction::STOP => write!(f, "STOP"),
+            Instruction::HALT => write!(f, "HALT"),
+            Instruction::RLCA => write!(f, "RLCA"),
+            Instruction::RRCA => write!(f, "RRCA"),
+            Instruction::RLA => write!(f, "RLA"),
+            Instruction::RRA => write!(f, "RRA"),
+            Instruction::RST(address) => write!(f, "RST ${:02X}", address),
+            Instruction::DI => write!(f, "DI"),
+            Instruction::EI => write!(f, "EI"),
+            Instruction::RLC(target) => write!(f, "RLC {}", target),
+            Instruction::RRC(target) => write!(f, "RRC {}", target),
+            Instruction::RL(target) => write!(f, "RL {}", target),
+            Instruction::RR(target) => write!(f, "RR {}", target),
+            Instruction::SLA(target) => write!(f, "SLA {}", target),
+            Instruction::SRA(target) => write!(f, "SRA {}", target),
+            Instruction::SWAP(target) => write!(f, "SWAP {}", target),
+            Instruction::SRL(target) => write!(f, "SRL {}", target),
+            Instruction::BIT(bit, target) => write!(f, "BIT {},{}", bit, target),
+            Instruction::RES(bit, target) => write!(f, "RES {},{}", bit, target),
+            Instruction::SET(bit, target) => write!(f, "SET {},{}", bit, target),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Target::A => "A",
+            Target::B => "B",
+            Target::C => "C",
+            Target::D => "D",
+            Target::E => "E",
+            Target::H => "H",
+            Target::L => "L",
+            Target::AF => "AF",
+            Target::BC => "BC",
+            Target::DE => "DE",
+            Target::HL => "HL",
+            Target::SP => "SP",
+            Target::IndirectHL => "(HL)",
+            Target::D8 => "n8",
+        };
+        f.write_str(text)
+    }
+}
+
+impl fmt::Display for ArithmeticTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            ArithmeticTarget::A => "A",
+            ArithmeticTarget::B => "B",
+            ArithmeticTarget::C => "C",
+            ArithmeticTarget::D => "D",
+            ArithmeticTarget::E => "E",
+            ArithmeticTarget::H => "H",
+            ArithmeticTarget::L => "L",
+            ArithmeticTarget::HLI => "(HL)",
+            ArithmeticTarget::D8 => "n8",
+        };
+        f.write_str(text)
+    }
+}
+
+// the condition only, Always has none
+impl fmt::Display for JumpTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            JumpTest::NotZero => "NZ",
+            JumpTest::Zero => "Z",
+            JumpTest::NotCarry => "NC",
+            JumpTest::Carry => "C",
+            JumpTest::Always => "",
+        };
+        f.write_str(text)
+    }
+}
+
+impl fmt::Display for LoadByteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            LoadByteTarget::A => "A",
+            LoadByteTarget::B => "B",
+            LoadByteTarget::C => "C",
+            LoadByteTarget::D => "D",
+            LoadByteTarget::E => "E",
+            LoadByteTarget::H => "H",
+            LoadByteTarget::L => "L",
+            LoadByteTarget::N16I => "(a16)",
+            LoadByteTarget::HLI => "(HL)",
+            LoadByteTarget::BCI => "(BC)",
+            LoadByteTarget::DEI => "(DE)",
+            LoadByteTarget::N8I => "(a8)",
+            LoadByteTarget::CI => "($FF00+C)",
+            LoadByteTarget::HLINCR => "(HL+)",
+            LoadByteTarget::HLDECR => "(HL-)",
+        };
+        f.write_str(text)
+    }
+}
+
+impl fmt::Display for LoadByteSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            LoadByteSource::A => "A",
+            LoadByteSource::B => "B",
+            LoadByteSource::C => "C",
+            LoadByteSource::D => "D",
+            LoadByteSource::E => "E",
+            LoadByteSource::H => "H",
+            LoadByteSource::L => "L",
+            LoadByteSource::D8 => "n8",
+            LoadByteSource::N16I => "(a16)",
+            LoadByteSource::N8I => "(a8)",
+            LoadByteSource::HLI => "(HL)",
+            LoadByteSource::BCI => "(BC)",
+            LoadByteSource::DEI => "(DE)",
+            LoadByteSource::CI => "($FF00+C)",
+            LoadByteSource::HLINCR => "(HL+)",
+            LoadByteSource::HLDECR => "(HL-)",
+        };
+        f.write_str(text)
+    }
+}
+
+impl fmt::Display for LoadWordTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            LoadWordTarget::BC => "BC",
+            LoadWordTarget::DE => "DE",
+            LoadWordTarget::HL => "HL",
+            LoadWordTarget::N16I => "(a16)",
+            LoadWordTarget::SP => "SP",
+        };
+        f.write_str(text)
+    }
+}
+
+impl fmt::Display for LoadWordSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            LoadWordSource::D16 => "n16",
+            LoadWordSource::SP => "SP",
+            LoadWordSource::HL => "HL",
+        };
+        f.write_str(text)
+    }
+}
+
+impl fmt::Display for LoadType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // the high page loads are their own mnemonic
+            LoadType::Byte(target @ LoadByteTarget::N8I, source) => {
+                write!(f, "LDH {},{}", target, source)
+            }
+            LoadType::Byte(target, source @ LoadByteSource::N8I) => {
+                write!(f, "LDH {},{}", target, source)
+            }
+            LoadType::Byte(target, source) => write!(f, "LD {},{}", target, source),
+            LoadType::Word(target, source) => write!(f, "LD {},{}", target, source),
+            LoadType::HLFromSPN => write!(f, "LD HL,SP+e8"),
+        }
+    }
+}
+
+impl fmt::Display for StackTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            StackTarget::BC => "BC",
+            StackTarget::DE => "DE",
+            StackTarget::HL => "HL",
+            StackTarget::AF => "AF",
+        };
+        f.write_str(text)
+    }
+}
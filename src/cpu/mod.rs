@@ -1,16 +1,42 @@
+pub mod call_stack;
 pub mod instruction;
 pub mod registers;
-use crate::memory_bus::MemoryBus;
+use crate::{
+    interrupt::Interrupt,
+    memory_bus::MemoryBus,
+    profiler::Profiler,
+    save_state::{read_bool, read_u16, write_bool, write_u16, SaveState},
+    timer::DIVIDER,
+    trace::Tracer,
+};
+use call_stack::CallStack;
 use instruction::*;
 use registers::Registers;
+use std::io;
 
-pub struct CPU<'a> {
+const INTERRUPT_CYCLES: u8 = 20;
+const HALTED_CYCLES: u8 = 4;
+
+// how an instruction changes the call stack
+enum Flow {
+    Straight,
+    Call,
+    Return,
+}
+
+pub struct CPU {
     registers: Registers,
     pc: u16, // program counter
     sp: u16, // stack pointer
-    bus: &'a mut MemoryBus<'a>,
+    bus: MemoryBus,
     is_halted: bool,
     interrupts_enabled: bool,
+    // EI enables interrupts only after the instruction following it
+    enable_interrupts_pending: bool,
+    // not part of the machine state, save states leave it alone
+    tracer: Option<Tracer>,
+    call_stack: CallStack,
+    profiler: Option<Profiler>,
 }
 macro_rules! update_register {
     // update_register!(self: a => action)
@@ -19,8 +45,8 @@ macro_rules! update_register {
         $self.registers.$reg = $self.$action($self.registers.$reg)
     }};
 }
-impl<'a> CPU<'a> {
-    pub fn new(memory_bus: &'a mut MemoryBus<'a>) -> Self {
+impl CPU {
+    pub fn new(memory_bus: MemoryBus) -> Self {
         CPU {
             registers: Registers::new(),
             pc: 0,
@@ -28,24 +54,222 @@ impl<'a> CPU<'a> {
             bus: memory_bus,
             is_halted: false,
             interrupts_enabled: true,
+            enable_interrupts_pending: false,
+            tracer: None,
+            call_stack: CallStack::new(),
+            profiler: None,
+        }
+    }
+    // power on state, the tracer and profiler stay attached
+    pub fn reset(&mut self) {
+        self.registers = Registers::new();
+        self.pc = 0;
+        self.sp = 0;
+        self.is_halted = false;
+        self.interrupts_enabled = true;
+        self.enable_interrupts_pending = false;
+        self.call_stack.clear();
+        self.bus.reset();
+    }
+    pub fn bus(&self) -> &MemoryBus {
+        &self.bus
+    }
+    pub fn bus_mut(&mut self) -> &mut MemoryBus {
+        &mut self.bus
+    }
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+    pub const REGISTER_NAMES: [&'static str; 14] = [
+        "a", "f", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "pc",
+    ];
+    // a register by its lowercase name, for tools that let users pick one
+    pub fn register(&self, name: &str) -> Option<u16> {
+        let registers = &self.registers;
+        let value = match name {
+            "a" => registers.a as u16,
+            "f" => u8::from(registers.f) as u16,
+            "b" => registers.b as u16,
+            "c" => registers.c as u16,
+            "d" => registers.d as u16,
+            "e" => registers.e as u16,
+            "h" => registers.h as u16,
+            "l" => registers.l as u16,
+            "af" => registers.get_af(),
+            "bc" => registers.get_bc(),
+            "de" => registers.get_de(),
+            "hl" => registers.get_hl(),
+            "sp" => self.sp,
+            "pc" => self.pc,
+            _ => return None,
+        };
+        Some(value)
+    }
+    // false for an unknown name, byte registers take the low byte
+    pub fn set_register(&mut self, name: &str, value: u16) -> bool {
+        let registers = &mut self.registers;
+        let byte = value as u8;
+        match name {
+            "a" => registers.a = byte,
+            "f" => registers.f = byte.into(),
+            "b" => registers.b = byte,
+            "c" => registers.c = byte,
+            "d" => registers.d = byte,
+            "e" => registers.e = byte,
+            "h" => registers.h = byte,
+            "l" => registers.l = byte,
+            "af" => registers.set_af(value),
+            "bc" => registers.set_bc(value),
+            "de" => registers.set_de(value),
+            "hl" => registers.set_hl(value),
+            "sp" => self.sp = value,
+            "pc" => self.pc = value,
+            _ => return false,
+        }
+        true
+    }
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+    pub fn set_halted(&mut self, halted: bool) {
+        self.is_halted = halted;
+    }
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+    // takes effect at once, a pending EI is dropped
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.interrupts_enabled = enabled;
+        self.enable_interrupts_pending = false;
+    }
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+    // executes one instruction (or services an interrupt) and returns the cycles it took,
+    // 0 when an execute watchpoint paused before the instruction
+    pub fn step(&mut self) -> u8 {
+        let pc = self.pc;
+        let enable_interrupts = self.enable_interrupts_pending;
+        let mut flow = Flow::Straight;
+        let cycles = if self.service_interrupt() {
+            flow = Flow::Call;
+            INTERRUPT_CYCLES
+        } else if self.is_halted {
+            HALTED_CYCLES
+        } else {
+            if !self.bus.begin_instruction(self.pc) {
+                return 0;
+            }
+            if let Some(mut tracer) = self.tracer.take() {
+                match tracer.trace(self) {
+                    Ok(()) => self.tracer = Some(tracer),
+                    Err(err) => eprintln!("Trace stopped: {}", err),
+                }
+            }
+            let mut instruction_byte = self.bus.peek_byte(self.pc);
+            let prefixed = instruction_byte == 0xCB;
+            if prefixed {
+                instruction_byte = self.bus.peek_byte(self.pc + 1);
+            }
+            let instruction =
+                Instruction::from_byte(instruction_byte, prefixed).unwrap_or_else(|| {
+                    panic!("Unknown instruction found for: 0x{:x}", instruction_byte)
+                });
+            let branch_taken = self.branch_taken(&instruction);
+            flow = match instruction {
+                Instruction::CALL(_) if branch_taken => Flow::Call,
+                Instruction::RST(_) => Flow::Call,
+                Instruction::RET(_) if branch_taken => Flow::Return,
+                Instruction::RETI => Flow::Return,
+                _ => Flow::Straight,
+            };
+            self.pc = self.execute(instruction);
+            Instruction::cycles(instruction_byte, prefixed, branch_taken)
+        };
+        // the cycles of a call belong to the caller
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, cycles, &self.call_stack);
+        }
+        match flow {
+            Flow::Call => {
+                // whatever was pushed is what the matching return pops
+                let return_address = u16::from_le_bytes([
+                    self.bus.peek_byte(self.sp),
+                    self.bus.peek_byte(self.sp.wrapping_add(1)),
+                ]);
+                self.call_stack.enter(self.pc, return_address);
+            }
+            Flow::Return => self.call_stack.exit(self.pc),
+            Flow::Straight => {}
+        }
+        // unless this instruction was a DI that cancelled it
+        if enable_interrupts && self.enable_interrupts_pending {
+            self.interrupts_enabled = true;
+            self.enable_interrupts_pending = false;
+        }
+        self.bus.step(cycles);
+        cycles
+    }
+    fn service_interrupt(&mut self) -> bool {
+        let pending = self.bus.pending_interrupts();
+        if pending == 0 {
+            return false;
+        }
+        // any pending interrupt wakes the CPU, even when IME is off
+        self.is_halted = false;
+        if !self.interrupts_enabled {
+            return false;
+        }
+        match Interrupt::highest_priority(pending) {
+            Some(interrupt) => {
+                self.interrupts_enabled = false;
+                self.bus.clear_interrupt(interrupt);
+                self.push(self.pc);
+                self.pc = interrupt.vector();
+                true
+            }
+            None => false,
         }
     }
-    pub fn step(&mut self) {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
-        let prefixed = instruction_byte == 0xCB;
-        if prefixed {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+    fn branch_taken(&self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::JP(test)
+            | Instruction::JR(test)
+            | Instruction::CALL(test)
+            | Instruction::RET(test) => self.should_jump(test),
+            _ => false,
         }
-        let instruction = Instruction::from_byte(instruction_byte, prefixed)
-            .unwrap_or_else(|| panic!("Unknown instruction found for: 0x{:x}", instruction_byte));
-        self.pc = self.execute(instruction);
     }
     fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+        self.bus.peek_byte(self.pc + 1)
     }
     fn read_next_word(&self) -> u16 {
-        let lsb = self.bus.read_byte(self.pc + 1);
-        let msb = self.bus.read_byte(self.pc + 2);
+        let lsb = self.bus.peek_byte(self.pc + 1);
+        let msb = self.bus.peek_byte(self.pc + 2);
         ((msb as u16) << 8) | lsb as u16
     }
     fn execute(&mut self, instruction: Instruction) -> u16 {
@@ -65,14 +289,13 @@ impl<'a> CPU<'a> {
                     ArithmeticTarget::HLI => {
                         self.add(self.bus.read_byte(self.registers.get_hl()), false)
                     }
-                    ArithmeticTarget::D8 => {
-                        let v = self.add(self.read_next_byte(), false);
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    ArithmeticTarget::D8 => self.add(self.read_next_byte(), false),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match target {
+                    ArithmeticTarget::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::ADC(source) => {
                 let value = match source {
@@ -86,15 +309,14 @@ impl<'a> CPU<'a> {
                     Source::IndirectHL => {
                         self.add(self.bus.read_byte(self.registers.get_hl()), true)
                     }
-                    Source::D8 => {
-                        let v = self.add(self.read_next_byte(), true);
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    Source::D8 => self.add(self.read_next_byte(), true),
                     _ => panic!("unsupported ADC source"),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::SUB(source) => {
                 let value = match source {
@@ -108,15 +330,14 @@ impl<'a> CPU<'a> {
                     Source::IndirectHL => {
                         self.sub(self.bus.read_byte(self.registers.get_hl()), false)
                     }
-                    Source::D8 => {
-                        let v = self.sub(self.read_next_byte(), false);
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    Source::D8 => self.sub(self.read_next_byte(), false),
                     _ => panic!("unsupported SUB source"),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::SBC(source) => {
                 let value = match source {
@@ -130,15 +351,14 @@ impl<'a> CPU<'a> {
                     Source::IndirectHL => {
                         self.sub(self.bus.read_byte(self.registers.get_hl()), true)
                     }
-                    Source::D8 => {
-                        let v = self.sub(self.read_next_byte(), true);
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    Source::D8 => self.sub(self.read_next_byte(), true),
                     _ => panic!("unsupported SBC source"),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::AND(source) => {
                 let value = match source {
@@ -150,15 +370,14 @@ impl<'a> CPU<'a> {
                     Source::H => self.and(self.registers.h),
                     Source::L => self.and(self.registers.l),
                     Source::IndirectHL => self.and(self.bus.read_byte(self.registers.get_hl())),
-                    Source::D8 => {
-                        let v = self.and(self.read_next_byte());
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    Source::D8 => self.and(self.read_next_byte()),
                     _ => panic!("unsupported AND source"),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::XOR(source) => {
                 let value = match source {
@@ -170,15 +389,14 @@ impl<'a> CPU<'a> {
                     Source::H => self.xor(self.registers.h),
                     Source::L => self.xor(self.registers.l),
                     Source::IndirectHL => self.xor(self.bus.read_byte(self.registers.get_hl())),
-                    Source::D8 => {
-                        let v = self.xor(self.read_next_byte());
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    Source::D8 => self.xor(self.read_next_byte()),
                     _ => panic!("unsupported XOR source"),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::OR(source) => {
                 let value = match source {
@@ -190,15 +408,14 @@ impl<'a> CPU<'a> {
                     Source::H => self.or(self.registers.h),
                     Source::L => self.or(self.registers.l),
                     Source::IndirectHL => self.or(self.bus.read_byte(self.registers.get_hl())),
-                    Source::D8 => {
-                        let v = self.or(self.read_next_byte());
-                        let _ = self.pc.wrapping_add(1);
-                        v
-                    }
+                    Source::D8 => self.or(self.read_next_byte()),
                     _ => panic!("unsupported OR source"),
                 };
                 self.registers.a = value;
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::CP(source) => {
                 match source {
@@ -210,35 +427,35 @@ impl<'a> CPU<'a> {
                     Source::H => self.cp(self.registers.h),
                     Source::L => self.cp(self.registers.l),
                     Source::IndirectHL => self.cp(self.bus.read_byte(self.registers.get_hl())),
-                    Source::D8 => {
-                        self.cp(self.read_next_byte());
-                        let _ = self.pc.wrapping_add(1);
-                    }
+                    Source::D8 => self.cp(self.read_next_byte()),
                     _ => panic!("unsupported CP source"),
                 };
-                self.pc.wrapping_add(1)
+                match source {
+                    Source::D8 => self.pc.wrapping_add(2),
+                    _ => self.pc.wrapping_add(1),
+                }
             }
             Instruction::DAA => {
                 // Z-0C
-                let mut value = self.registers.a as u16;
-                if self.registers.f.subtract {
-                    if self.registers.f.carry {
-                        value = (value - 0x06) & 0xff;
-                    }
-                    if self.registers.f.carry {
-                        value -= 0x60;
-                    }
-                } else {
-                    if self.registers.f.half_carry || (value & 0xf) > 9 {
-                        value += 0x06;
-                    }
-                    if self.registers.f.carry || value > 0x9F {
-                        value += 0x60;
-                    }
+                // corrects A to BCD after an addition or subtraction of two BCD numbers
+                let flags = self.registers.f;
+                let mut correction = 0;
+                let mut carry = flags.carry;
+                if flags.half_carry || (!flags.subtract && self.registers.a & 0xF > 9) {
+                    correction |= 0x06;
+                }
+                if flags.carry || (!flags.subtract && self.registers.a > 0x99) {
+                    correction |= 0x60;
+                    carry = true;
                 }
-                self.registers.f.zero = value == 0;
+                self.registers.a = if flags.subtract {
+                    self.registers.a.wrapping_sub(correction)
+                } else {
+                    self.registers.a.wrapping_add(correction)
+                };
+                self.registers.f.zero = self.registers.a == 0;
                 self.registers.f.half_carry = false;
-                self.registers.f.carry = (value & 0x100) != 0;
+                self.registers.f.carry = carry;
                 self.pc.wrapping_add(1)
             }
             Instruction::SCF => {
@@ -325,10 +542,12 @@ impl<'a> CPU<'a> {
             }
             Instruction::JP(test) => self.jump(self.should_jump(&test)),
             Instruction::JR(test) => {
+                // the offset is signed and relative to the next instruction
+                let next_pc = self.pc.wrapping_add(2);
                 if self.should_jump(&test) {
-                    self.pc.wrapping_add(self.bus.read_byte(self.pc + 1) as u16)
+                    next_pc.wrapping_add(self.read_next_byte() as i8 as u16)
                 } else {
-                    self.pc.wrapping_add(2)
+                    next_pc
                 }
             }
             Instruction::JPHL => self.registers.get_hl(),
@@ -373,8 +592,7 @@ impl<'a> CPU<'a> {
                         LoadByteTarget::H => self.registers.h = source_value,
                         LoadByteTarget::L => self.registers.l = source_value,
                         LoadByteTarget::N16I => {
-                            self.bus.write_byte(self.read_next_word(), source_value);
-                            let _ = self.pc.wrapping_add(2);
+                            self.bus.write_byte(self.read_next_word(), source_value)
                         }
                         LoadByteTarget::HLI => {
                             self.bus.write_byte(self.registers.get_hl(), source_value)
@@ -405,10 +623,15 @@ impl<'a> CPU<'a> {
                             .bus
                             .write_byte(0xFF00 | self.read_next_byte() as u16, source_value),
                     };
-                    match source {
-                        LoadByteSource::D8 => self.pc.wrapping_add(2),
-                        LoadByteSource::N16I => self.pc.wrapping_add(3),
-                        LoadByteSource::N8I => self.pc.wrapping_add(2),
+                    // the operand belongs to whichever side addresses memory with it
+                    match (target, source) {
+                        (_, LoadByteSource::D8) => self.pc.wrapping_add(2),
+                        (_, LoadByteSource::N16I) | (LoadByteTarget::N16I, _) => {
+                            self.pc.wrapping_add(3)
+                        }
+                        (_, LoadByteSource::N8I) | (LoadByteTarget::N8I, _) => {
+                            self.pc.wrapping_add(2)
+                        }
                         _ => self.pc.wrapping_add(1),
                     }
                 }
@@ -423,14 +646,15 @@ impl<'a> CPU<'a> {
                         LoadWordTarget::DE => self.registers.set_de(source_value),
                         LoadWordTarget::HL => self.registers.set_hl(source_value),
                         LoadWordTarget::N16I => {
-                            self.bus.write_word(self.read_next_word(), source_value);
-                            let _ = self.pc.wrapping_add(2);
+                            self.bus.write_word(self.read_next_word(), source_value)
                         }
                         LoadWordTarget::SP => self.sp = source_value,
                     }
 
-                    match source {
-                        LoadWordSource::D16 => self.pc.wrapping_add(3),
+                    match (target, source) {
+                        (_, LoadWordSource::D16) | (LoadWordTarget::N16I, _) => {
+                            self.pc.wrapping_add(3)
+                        }
                         _ => self.pc.wrapping_add(1),
                     }
                 }
@@ -476,7 +700,12 @@ impl<'a> CPU<'a> {
                 self.is_halted = true;
                 self.pc.wrapping_add(1)
             }
-            Instruction::STOP => todo!(),
+            Instruction::STOP => {
+                // approximated by HALT, the joypad interrupt that ends STOP ends it too
+                self.bus.write_byte(DIVIDER, 0);
+                self.is_halted = true;
+                self.pc.wrapping_add(2)
+            }
             Instruction::ADDHL(source) => {
                 // F: - 0 H C
                 let value = match source {
@@ -489,9 +718,9 @@ impl<'a> CPU<'a> {
                 let hl = self.registers.get_hl();
                 let (new_value, did_overflow) = hl.overflowing_add(value);
                 self.registers.f.subtract = false;
-                // Half carry tests if we flow over the 11th bit i.e. does adding the two
-                // numbers together cause the 11th bit to flip
-                let mask = 0b111_1111_1111; // mask out bits 11-15
+                // Half carry tests if we carry out of bit 11 i.e. does adding the lower
+                // 12 bits of the two numbers together overflow them
+                let mask = 0b1111_1111_1111; // mask out bits 12-15
                 self.registers.f.half_carry = (value & mask) + (hl & mask) > mask;
                 self.registers.f.carry = did_overflow;
 
@@ -500,8 +729,9 @@ impl<'a> CPU<'a> {
             }
             Instruction::ADDSP => {
                 // F: 0 0 H C
-                let value = self.read_next_byte() as i8 as u8 as u16;
-                let new_value = value.wrapping_add(value);
+                // the flags come from the unsigned low byte, the result from the signed offset
+                let value = self.read_next_byte() as u16;
+                let new_value = self.sp.wrapping_add(value as u8 as i8 as u16);
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 let half_carry_mask = 0xF;
@@ -517,36 +747,41 @@ impl<'a> CPU<'a> {
                 // 0 0 0 C
                 // Rotate left
                 update_register!(self, a => rotate_left);
+                self.registers.f.zero = false;
                 self.pc.wrapping_add(1)
             }
             Instruction::RRCA => {
                 // 0 0 0 C
                 // Rotate Right
                 update_register!(self, a => rotate_right);
+                self.registers.f.zero = false;
                 self.pc.wrapping_add(1)
             }
             Instruction::RLA => {
                 // 0 0 0 C
                 // Rotate left through carry
                 update_register!(self, a => rotate_left_with_carry);
+                self.registers.f.zero = false;
                 self.pc.wrapping_add(1)
             }
             Instruction::RRA => {
                 // 0 0 0 C
                 // Rotate Right through carry
                 update_register!(self, a => rotate_right_with_carry);
+                self.registers.f.zero = false;
                 self.pc.wrapping_add(1)
             }
             Instruction::RST(addr) => {
-                self.push(self.pc);
+                self.push(self.pc.wrapping_add(1));
                 addr
             }
             Instruction::DI => {
                 self.interrupts_enabled = false;
+                self.enable_interrupts_pending = false;
                 self.pc.wrapping_add(1)
             }
             Instruction::EI => {
-                self.interrupts_enabled = true;
+                self.enable_interrupts_pending = true;
                 self.pc.wrapping_add(1)
             }
             Instruction::RLC(target) => {
@@ -762,19 +997,18 @@ impl<'a> CPU<'a> {
         new_value
     }
     fn should_jump(&self, test: &JumpTest) -> bool {
-        let jump_condition = match test {
+        match test {
             JumpTest::NotZero => !self.registers.f.zero,
             JumpTest::Zero => self.registers.f.zero,
             JumpTest::NotCarry => !self.registers.f.carry,
             JumpTest::Carry => self.registers.f.carry,
             JumpTest::Always => true,
-        };
-        jump_condition
+        }
     }
     fn jump(&mut self, should_jump: bool) -> u16 {
         if should_jump {
-            let least_significant_byte = self.bus.read_byte(self.pc + 1) as u16;
-            let most_significant_byte = self.bus.read_byte(self.pc + 2) as u16;
+            let least_significant_byte = self.bus.peek_byte(self.pc + 1) as u16;
+            let most_significant_byte = self.bus.peek_byte(self.pc + 2) as u16;
             (most_significant_byte << 8) | least_significant_byte
         } else {
             self.pc.wrapping_add(3)
@@ -824,7 +1058,8 @@ impl<'a> CPU<'a> {
         let new_value = value.wrapping_sub(1);
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
-        self.registers.f.half_carry = value & 0xF == 0xF;
+        // borrows from the upper nibble when the lower one was 0
+        self.registers.f.half_carry = value & 0xF == 0;
         new_value
     }
     fn sub(&mut self, value: u8, with_carry: bool) -> u8 {
@@ -872,25 +1107,23 @@ impl<'a> CPU<'a> {
     }
     fn cp(&mut self, value: u8) {
         // Z1HC
-        self.registers.f.zero = self.registers.a == value;
-        self.registers.f.subtract = true;
-        self.registers.f.half_carry = (0x0f & self.registers.a) > (0x0f & value);
-        self.registers.f.carry = self.registers.a < value;
+        // a subtraction that only keeps the flags
+        self.sub(value, false);
     }
     fn rotate_left(&mut self, value: u8) -> u8 {
-        let new_value = (value << 1) | (value >> 7);
+        let new_value = value.rotate_left(1);
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x80) != 0;
+        self.registers.f.carry = (value & 0x80) != 0;
         new_value
     }
     fn rotate_right(&mut self, value: u8) -> u8 {
-        let new_value = (value >> 1) | (value << 7);
+        let new_value = value.rotate_right(1);
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x01) != 0;
+        self.registers.f.carry = (value & 0x01) != 0;
         new_value
     }
     fn rotate_left_with_carry(&mut self, value: u8) -> u8 {
@@ -898,7 +1131,7 @@ impl<'a> CPU<'a> {
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x80) != 0;
+        self.registers.f.carry = (value & 0x80) != 0;
         new_value
     }
     fn rotate_right_with_carry(&mut self, value: u8) -> u8 {
@@ -906,7 +1139,7 @@ impl<'a> CPU<'a> {
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x01) != 0;
+        self.registers.f.carry = (value & 0x01) != 0;
         new_value
     }
     fn shift_left_arithmetic(&mut self, value: u8) -> u8 {
@@ -914,7 +1147,7 @@ impl<'a> CPU<'a> {
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x80) != 0;
+        self.registers.f.carry = (value & 0x80) != 0;
         new_value
     }
     fn shift_right_arithmetic(&mut self, value: u8) -> u8 {
@@ -922,7 +1155,7 @@ impl<'a> CPU<'a> {
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x01) != 0;
+        self.registers.f.carry = (value & 0x01) != 0;
         new_value
     }
     fn swap_nibbles(&mut self, value: u8) -> u8 {
@@ -938,7 +1171,7 @@ impl<'a> CPU<'a> {
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = (new_value & 0x01) != 0;
+        self.registers.f.carry = (value & 0x01) != 0;
         new_value
     }
     fn bit_test(&mut self, value: u8, offset: u8) {
@@ -955,3 +1188,283 @@ impl<'a> CPU<'a> {
         value | (1 << offset)
     }
 }
+impl SaveState for CPU {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        self.registers.save_state(state);
+        write_u16(state, self.pc);
+        write_u16(state, self.sp);
+        write_bool(state, self.is_halted);
+        write_bool(state, self.interrupts_enabled);
+        write_bool(state, self.enable_interrupts_pending);
+        self.bus.save_state(state);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.registers.load_state(state)?;
+        self.pc = read_u16(state)?;
+        self.sp = read_u16(state)?;
+        self.is_halted = read_bool(state)?;
+        self.interrupts_enabled = read_bool(state)?;
+        self.enable_interrupts_pending = read_bool(state)?;
+        // the calls that led to the saved state are unknown
+        self.call_stack.clear();
+        self.bus.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::{INTERRUPT_ENABLE, INTERRUPT_FLAG};
+    use crate::watchpoint::{Access, WatchAction, Watchpoint};
+    use crate::{game_boy::rom_with, symbols::Symbols};
+
+    // a CPU about to run program at 0100, past the boot rom
+    fn cpu_with(program: &[u8]) -> CPU {
+        cpu_with_rom(&rom_with(program))
+    }
+
+    fn cpu_with_rom(rom: &[u8]) -> CPU {
+        let mut bus = MemoryBus::new(vec![0; 0x100]);
+        bus.load_cartridge(rom).unwrap();
+        bus.write_byte(0xFF50, 1);
+        let mut cpu = CPU::new(bus);
+        cpu.pc = 0x100;
+        cpu.sp = 0xFFFE;
+        cpu
+    }
+
+    #[test]
+    fn immediate_operands_are_skipped() {
+        for opcode in [0xC6, 0xCE, 0xD6, 0xDE, 0xE6, 0xEE, 0xF6, 0xFE, 0xE0] {
+            let mut cpu = cpu_with(&[opcode, 0x80]);
+            cpu.step();
+            assert_eq!(cpu.pc, 0x102, "opcode {:02X}", opcode);
+        }
+        // ld (C000),a and ld (C000),sp
+        for opcode in [0xEA, 0x08] {
+            let mut cpu = cpu_with(&[opcode, 0x00, 0xC0]);
+            cpu.step();
+            assert_eq!(cpu.pc, 0x103, "opcode {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn execute_watchpoints_pause_before_the_instruction() {
+        // INC A; JR -3 back to the INC
+        let mut cpu = cpu_with(&[0x3C, 0x18, 0xFD]);
+        cpu.bus.watchpoints().add(Watchpoint {
+            addresses: 0x100..=0x100,
+            read: false,
+            write: false,
+            execute: true,
+            action: WatchAction::Pause,
+        });
+        cpu.registers.a = 0;
+        assert_eq!(cpu.step(), 0);
+        assert_eq!((cpu.pc, cpu.registers.a), (0x100, 0));
+        let hits = cpu.bus.watchpoints().take_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].access, hits[0].pc), (Access::Execute, 0x100));
+        // resuming runs it, coming back around pauses again
+        assert_ne!(cpu.step(), 0);
+        assert_eq!(cpu.registers.a, 1);
+        cpu.step();
+        assert_eq!(cpu.step(), 0);
+        assert_eq!(cpu.registers.a, 1);
+        assert_eq!(cpu.bus.watchpoints().take_hits().len(), 1);
+    }
+
+    fn bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+
+    #[test]
+    fn daa_corrects_bcd_addition_and_subtraction() {
+        let mut cpu = cpu_with(&[]);
+        for a in 0..100 {
+            for b in 0..100 {
+                cpu.registers.a = bcd(a);
+                cpu.registers.a = cpu.add(bcd(b), false);
+                cpu.execute(Instruction::DAA);
+                assert_eq!(cpu.registers.a, bcd((a + b) % 100), "{} + {}", a, b);
+                assert_eq!(cpu.registers.f.carry, a + b >= 100);
+
+                cpu.registers.a = bcd(a);
+                cpu.registers.a = cpu.sub(bcd(b), false);
+                cpu.execute(Instruction::DAA);
+                assert_eq!(cpu.registers.a, bcd((100 + a - b) % 100), "{} - {}", a, b);
+                assert_eq!(cpu.registers.f.carry, a < b);
+            }
+        }
+    }
+
+    #[test]
+    fn jr_offsets_are_signed_and_relative_to_the_next_instruction() {
+        let mut cpu = cpu_with(&[0x18, 0x03]);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x105);
+        let mut cpu = cpu_with(&[0x18, 0xFE]);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn rst_returns_behind_itself() {
+        let mut cpu = cpu_with(&[0xCF]);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x08);
+        assert_eq!(cpu.pop(), 0x101);
+    }
+
+    #[test]
+    fn add_sp_sign_extends_the_offset() {
+        let mut cpu = cpu_with(&[0xE8, 0xF0]);
+        cpu.sp = 0xDFF0;
+        cpu.step();
+        assert_eq!(cpu.sp, 0xDFE0);
+        assert!(cpu.registers.f.carry);
+        assert!(!cpu.registers.f.half_carry);
+    }
+
+    #[test]
+    fn rotates_and_shifts_carry_the_bit_shifted_out() {
+        // rlca, rl a, srl a
+        let mut cpu = cpu_with(&[0x07, 0xCB, 0x17, 0xCB, 0x3F]);
+        cpu.registers.a = 0x80;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x01);
+        assert!(cpu.registers.f.carry && !cpu.registers.f.zero);
+        cpu.registers.a = 0x80;
+        cpu.registers.f.carry = false;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x00);
+        assert!(cpu.registers.f.carry && cpu.registers.f.zero);
+        cpu.registers.a = 0x01;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x00);
+        assert!(cpu.registers.f.carry);
+    }
+
+    #[test]
+    fn half_carry_of_dec_cp_and_add_hl() {
+        let mut cpu = cpu_with(&[]);
+        cpu.dec(0x10);
+        assert!(cpu.registers.f.half_carry);
+        cpu.dec(0x11);
+        assert!(!cpu.registers.f.half_carry);
+        cpu.registers.a = 0x10;
+        cpu.cp(0x01);
+        assert!(cpu.registers.f.half_carry);
+        // add hl,bc carries out of bit 11
+        let mut cpu = cpu_with(&[0x09]);
+        cpu.registers.set_hl(0x0FFF);
+        cpu.registers.set_bc(0x0001);
+        cpu.step();
+        assert_eq!(cpu.registers.get_hl(), 0x1000);
+        assert!(cpu.registers.f.half_carry);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // ei, nop with a vblank interrupt waiting
+        let mut cpu = cpu_with(&[0xFB, 0x00, 0x00]);
+        cpu.interrupts_enabled = false;
+        cpu.bus.write_byte(INTERRUPT_ENABLE, 0x01);
+        cpu.bus.write_byte(INTERRUPT_FLAG, 0x01);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x102);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x40);
+        // ei, di never enables them
+        let mut cpu = cpu_with(&[0xFB, 0xF3, 0x00]);
+        cpu.interrupts_enabled = false;
+        cpu.bus.write_byte(INTERRUPT_ENABLE, 0x01);
+        cpu.bus.write_byte(INTERRUPT_FLAG, 0x01);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn stop_halts_resets_div_and_skips_its_operand() {
+        // 64 nops tick DIV once
+        let mut program = [0x00; 66];
+        program[64] = 0x10;
+        let mut cpu = cpu_with(&program);
+        for _ in 0..64 {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus.read_byte(DIVIDER), 1);
+        cpu.step();
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.pc, 0x142);
+        assert_eq!(cpu.bus.read_byte(DIVIDER), 0);
+    }
+
+    // CALL $0110 into RST $38, which returns, then EI lets the timer interrupt in,
+    // RETI, and a RET to a pushed address before the real return to 0103
+    fn calling_cpu() -> CPU {
+        let mut rom = rom_with(&[0xCD, 0x10, 0x01]);
+        rom[0x38] = 0xC9;
+        rom[0x50] = 0xD9;
+        rom[0x110..0x116].copy_from_slice(&[0xFF, 0xFB, 0x00, 0xE5, 0xC9, 0x00]);
+        rom[0x200] = 0xC9;
+        let mut cpu = cpu_with_rom(&rom);
+        cpu.set_interrupts_enabled(false);
+        cpu.set_register("hl", 0x200);
+        cpu.bus.write_byte(INTERRUPT_ENABLE, 0x04);
+        cpu.bus.write_byte(INTERRUPT_FLAG, 0x04);
+        cpu
+    }
+
+    fn frames(cpu: &CPU) -> Vec<(u16, u16)> {
+        let frames = cpu.call_stack().frames().iter();
+        frames
+            .map(|frame| (frame.routine, frame.return_address))
+            .collect()
+    }
+
+    #[test]
+    fn the_call_stack_follows_calls_and_returns() {
+        let mut cpu = calling_cpu();
+        let main = (0x110, 0x103);
+        for (instruction, expected) in [
+            ("CALL", vec![main]),
+            ("RST", vec![main, (0x38, 0x111)]),
+            ("RET", vec![main]),
+            ("EI", vec![main]),
+            ("NOP", vec![main]),
+            ("interrupt", vec![main, (0x50, 0x113)]),
+            ("RETI", vec![main]),
+            ("PUSH", vec![main]),
+            // to 0200, which nothing called, so it isn't this frame's return
+            ("RET", vec![main]),
+            ("RET", vec![]),
+        ] {
+            cpu.step();
+            assert_eq!(frames(&cpu), expected, "after {}", instruction);
+        }
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn a_call_is_profiled_in_the_caller() {
+        let mut cpu = calling_cpu();
+        cpu.set_profiler(Some(Profiler::new()));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        let mut collapsed = Vec::new();
+        let profiler = cpu.take_profiler().unwrap();
+        profiler
+            .write_collapsed(&Symbols::new(), &mut collapsed)
+            .unwrap();
+        // the RET at 0038 runs in the routine it leaves
+        assert_eq!(
+            String::from_utf8(collapsed).unwrap(),
+            "[root] 24\n[root];$0110 16\n[root];$0110;$0038 16\n"
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use crate::save_state::{read_bytes, write_bytes, SaveState};
+use std::io;
+
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -9,6 +12,12 @@ pub struct Registers {
     pub l: u8,
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         Registers {
@@ -45,7 +54,7 @@ impl Registers {
         self.e = (value & 0xFF) as u8;
     }
     pub fn get_hl(&self) -> u16 {
-        (self.h as u16) << 8 | self.h as u16
+        (self.h as u16) << 8 | self.l as u16
     }
     pub fn set_hl(&mut self, value: u16) {
         self.h = ((value & 0xFF00) >> 8) as u8;
@@ -91,3 +100,45 @@ impl std::convert::From<u8> for FlagsRegister {
         }
     }
 }
+impl SaveState for Registers {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        let f = u8::from(self.f);
+        write_bytes(
+            state,
+            &[self.a, self.b, self.c, self.d, self.e, f, self.h, self.l],
+        );
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        let mut bytes = [0; 8];
+        read_bytes(state, &mut bytes)?;
+        let [a, b, c, d, e, f, h, l] = bytes;
+        *self = Registers {
+            a,
+            b,
+            c,
+            d,
+            e,
+            f: f.into(),
+            h,
+            l,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_pairs_combine_high_and_low_byte() {
+        let mut registers = Registers::new();
+        registers.set_hl(0x1234);
+        assert_eq!((registers.h, registers.l), (0x12, 0x34));
+        assert_eq!(registers.get_hl(), 0x1234);
+        registers.set_bc(0xABCD);
+        registers.set_de(0x5678);
+        assert_eq!(registers.get_bc(), 0xABCD);
+        assert_eq!(registers.get_de(), 0x5678);
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{
+    cpu::{instruction::Instruction, CPU},
+    disassembler,
+    symbols::Symbols,
+    watchpoint::{Access, WatchAction, Watchpoint},
+    GameBoy,
+};
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+const HELP: &str = "\
+s, step [N]          execute N instructions
+n, next              step over calls and rsts
+c, continue          run until a breakpoint
+b, break ADDR        break when PC reaches ADDR
+d, delete ADDR       remove a breakpoint
+bl, breakpoints      list breakpoints
+w, watch ADDR[-END] [rwx]
+                     stop on reads, writes or execution in a range, writes by default
+wl, watchpoints      list watchpoints
+wd ID                remove a watchpoint
+bt, backtrace        show the calls that led here
+r, regs              show the registers
+x, mem ADDR [N]      hexdump N bytes
+dis [ADDR] [N]       disassemble N instructions, from PC by default
+q, quit              leave the debugger
+addresses are hex or symbols, counts decimal, an empty line repeats the last command";
+
+const DUMP_BYTES: usize = 64;
+const DISASSEMBLE_INSTRUCTIONS: usize = 10;
+
+// addresses are written the way they're in every Game Boy document: hex, $ or 0x optional
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// a symbol from the .sym file or a hex address
+fn parse_location(text: &str, symbols: &Symbols) -> Option<u16> {
+    symbols.address_of(text).or_else(|| parse_address(text))
+}
+
+// ADDR or ADDR-END
+fn parse_range(text: &str, symbols: &Symbols) -> Option<(u16, u16)> {
+    match text.split_once('-') {
+        Some((first, last)) => Some((
+            parse_location(first, symbols)?,
+            parse_location(last, symbols)?,
+        )),
+        None => parse_location(text, symbols).map(|address| (address, address)),
+    }
+}
+
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7
+}
+
+/// Interactive command line debugger pausing the CPU between instructions.
+/// Panics of the emulator drop back to the prompt instead of ending the program.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    last_command: String,
+    symbols: Arc<Symbols>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            last_command: String::new(),
+            symbols: Arc::new(Symbols::new()),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn set_symbols(&mut self, symbols: Arc<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    // reads commands until quit or the end of the input
+    pub fn run(
+        &mut self,
+        game_boy: &mut GameBoy,
+        input: impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        self.print_location(game_boy.cpu(), output)?;
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(rustboy) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let command = match line.trim() {
+                "" => self.last_command.clone(),
+                command => command.to_string(),
+            };
+            self.last_command = command.clone();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.execute(game_boy, &command, output)
+            }));
+            match result {
+                Ok(keep_going) => {
+                    if !keep_going? {
+                        return Ok(());
+                    }
+                }
+                Err(_) => writeln!(output, "Command panicked")?,
+            }
+        }
+    }
+
+    // false once the user wants to leave
+    fn execute(
+        &mut self,
+        game_boy: &mut GameBoy,
+        command: &str,
+        output: &mut impl Write,
+    ) -> io::Result<bool> {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        match name {
+            "s" | "step" => {
+                let count = args.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+                for _ in 0..count {
+                    if !self.step(game_boy, output)? {
+                        break;
+                    }
+                }
+                self.print_location(game_boy.cpu(), output)?;
+            }
+            "n" | "next" => {
+                let cpu = game_boy.cpu();
+                let opcode = cpu.bus().peek_byte(cpu.pc());
+                if is_call(opcode) {
+                    let size = Instruction::size(opcode, false) as u16;
+                    let (return_address, sp) = (cpu.pc().wrapping_add(size), cpu.sp());
+                    self.run_until(game_boy, output, |cpu| {
+                        cpu.pc() == return_address && cpu.sp() >= sp
+                    })?;
+                } else {
+                    self.step(game_boy, output)?;
+                }
+                self.print_location(game_boy.cpu(), output)?;
+            }
+            "c" | "continue" => {
+                self.run_until(game_boy, output, |_| false)?;
+                self.print_location(game_boy.cpu(), output)?;
+            }
+            "b" | "break" => match args.first().and_then(|a| parse_location(a, &self.symbols)) {
+                Some(address) => {
+                    self.breakpoints.insert(address);
+                    writeln!(output, "Breakpoint at {}", self.location(address))?;
+                }
+                None => writeln!(output, "usage: break ADDR")?,
+            },
+            "d" | "delete" => match args.first().and_then(|a| parse_location(a, &self.symbols)) {
+                Some(address) if self.breakpoints.remove(&address) => {
+                    writeln!(output, "Deleted breakpoint at {}", self.location(address))?
+                }
+                _ => writeln!(output, "No such breakpoint")?,
+            },
+            "bl" | "breakpoints" => {
+                for address in self.breakpoints.iter() {
+                    writeln!(output, "{}", self.location(*address))?;
+                }
+            }
+            "w" | "watch" => match args.first().and_then(|a| parse_range(a, &self.symbols)) {
+                Some((first, last)) => {
+                    let kinds = args.get(1).copied().unwrap_or("w");
+                    let id = game_boy.cpu_mut().bus_mut().watchpoints().add(Watchpoint {
+                        addresses: first..=last,
+                        read: kinds.contains('r'),
+                        write: kinds.contains('w'),
+                        execute: kinds.contains('x'),
+                        action: WatchAction::Pause,
+                    });
+                    writeln!(output, "Watchpoint {} at ${:04X}-${:04X}", id, first, last)?;
+                }
+                None => writeln!(output, "usage: watch ADDR[-END] [rwx]")?,
+            },
+            "wl" | "watchpoints" => {
+                for (id, watchpoint) in game_boy.cpu_mut().bus_mut().watchpoints().iter() {
+                    let flag = |set: bool, name: char| if set { name } else { '-' };
+                    writeln!(
+                        output,
+                        "{:<3} ${:04X}-${:04X} {}{}{}",
+                        id,
+                        watchpoint.addresses.start(),
+                        watchpoint.addresses.end(),
+                        flag(watchpoint.read, 'r'),
+                        flag(watchpoint.write, 'w'),
+                        flag(watchpoint.execute, 'x')
+                    )?;
+                }
+            }
+            "wd" => match args.first().and_then(|id| id.parse().ok()) {
+                Some(id) if game_boy.cpu_mut().bus_mut().watchpoints().remove(id) => {
+                    writeln!(output, "Deleted watchpoint {}", id)?
+                }
+                _ => writeln!(output, "No such watchpoint")?,
+            },
+            "bt" | "backtrace" => {
+                let cpu = game_boy.cpu();
+                writeln!(output, "#0  {}", self.location(cpu.pc()))?;
+                // each caller at the address it continues from
+                for (depth, frame) in cpu.call_stack().frames().iter().rev().enumerate() {
+                    writeln!(
+                        output,
+                        "#{:<2} {}",
+                        depth + 1,
+                        self.location(frame.return_address)
+                    )?;
+                }
+            }
+            "r" | "regs" => Debugger::print_registers(game_boy.cpu(), output)?,
+            "x" | "mem" => match args.first().and_then(|a| parse_location(a, &self.symbols)) {
+                Some(address) => {
+                    let count = args.get(1).and_then(|n| n.parse().ok());
+                    Debugger::dump(game_boy.cpu(), address, count.unwrap_or(DUMP_BYTES), output)?
+                }
+                None => writeln!(output, "usage: mem ADDR [N]")?,
+            },
+            "dis" | "disassemble" => {
+                let address = args.first().and_then(|a| parse_location(a, &self.symbols));
+                let count = args.get(1).and_then(|n| n.parse().ok());
+                self.disassemble(
+                    game_boy.cpu(),
+                    address.unwrap_or(game_boy.cpu().pc()),
+                    count.unwrap_or(DISASSEMBLE_INSTRUCTIONS),
+                    output,
+                )?;
+            }
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(output, "Unknown command {}, try help", name)?,
+        }
+        Ok(true)
+    }
+
+    // runs one instruction, false when the emulator panicked or hit a watchpoint
+    fn step(&self, game_boy: &mut GameBoy, output: &mut impl Write) -> io::Result<bool> {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            game_boy.step();
+        }));
+        if result.is_err() {
+            let pc = game_boy.cpu().pc();
+            writeln!(output, "Emulator panicked at {}", self.location(pc))?;
+        }
+        let hits = game_boy.cpu_mut().bus_mut().watchpoints().take_hits();
+        for hit in hits.iter() {
+            let access = match hit.access {
+                Access::Read => "read",
+                Access::Write => "write",
+                Access::Execute => "execute",
+            };
+            writeln!(
+                output,
+                "Watchpoint: {} {} = ${:02X} at PC {}",
+                access,
+                self.location(hit.address),
+                hit.value,
+                self.location(hit.pc)
+            )?;
+        }
+        Ok(result.is_ok() && hits.is_empty())
+    }
+
+    // steps until the condition holds or a breakpoint is hit
+    fn run_until(
+        &mut self,
+        game_boy: &mut GameBoy,
+        output: &mut impl Write,
+        done: impl Fn(&CPU) -> bool,
+    ) -> io::Result<()> {
+        loop {
+            if !self.step(game_boy, output)? || done(game_boy.cpu()) {
+                return Ok(());
+            }
+            let pc = game_boy.cpu().pc();
+            if self.breakpoints.contains(&pc) {
+                writeln!(output, "Breakpoint at {}", self.location(pc))?;
+                return Ok(());
+            }
+        }
+    }
+
+    // plain hex until a .sym file is loaded, then bank, address and symbol
+    fn location(&self, address: u16) -> String {
+        if self.symbols.is_empty() {
+            format!("${:04X}", address)
+        } else {
+            self.symbols.format(address)
+        }
+    }
+
+    fn print_location(&self, cpu: &CPU, output: &mut impl Write) -> io::Result<()> {
+        self.disassemble(cpu, cpu.pc(), 1, output)
+    }
+
+    fn print_registers(cpu: &CPU, output: &mut impl Write) -> io::Result<()> {
+        let registers = cpu.registers();
+        let f = registers.f;
+        writeln!(
+            output,
+            "AF={:02X}{:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X} PC={:04X}",
+            registers.a,
+            u8::from(f),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            cpu.sp(),
+            cpu.pc()
+        )?;
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        writeln!(
+            output,
+            "Flags {}{}{}{} IME={} HALT={}",
+            flag(f.zero, 'Z'),
+            flag(f.subtract, 'N'),
+            flag(f.half_carry, 'H'),
+            flag(f.carry, 'C'),
+            cpu.interrupts_enabled() as u8,
+            cpu.is_halted() as u8
+        )
+    }
+
+    fn dump(cpu: &CPU, address: u16, count: usize, output: &mut impl Write) -> io::Result<()> {
+        let bytes: Vec<u8> = (0..count)
+            .map(|i| cpu.bus().peek_byte(address.wrapping_add(i as u16)))
+            .collect();
+        for (line, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7E => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            let start = address.wrapping_add(line as u16 * 16);
+            writeln!(output, "${:04X}  {:<47}  {}", start, hex.join(" "), text)?;
+        }
+        Ok(())
+    }
+
+    fn disassemble(
+        &self,
+        cpu: &CPU,
+        address: u16,
+        count: usize,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let bus = cpu.bus();
+        let mut address = address;
+        for _ in 0..count {
+            let disassembly = disassembler::disassemble(address, |address| bus.peek_byte(address));
+            let bytes: Vec<String> = disassembly
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            if let Some(name) = self.symbols.name_at(address) {
+                writeln!(output, "{}:", name)?;
+            }
+            // where jumps and calls go, by name
+            let target = disassembly
+                .jump_target()
+                .and_then(|target| self.symbols.describe(target))
+                .map(|name| format!(" ; {}", name))
+                .unwrap_or_default();
+            let marker = if address == cpu.pc() { "=>" } else { "  " };
+            writeln!(
+                output,
+                "{} ${:04X}  {:<9} {}{}",
+                marker,
+                address,
+                bytes.join(" "),
+                disassembly,
+                target
+            )?;
+            address = disassembly.next_address();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+    use std::io::Cursor;
+
+    #[test]
+    fn commands_from_a_script() {
+        let mut game_boy = counter_game_boy();
+        let script = "break 103\ncontinue\nstep\n\nregs\nmem C000 4\ndis 100 3\nquit\n";
+        let mut output = Vec::new();
+        Debugger::new()
+            .run(&mut game_boy, Cursor::new(script), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            concat!(
+                "=> $0000  00        NOP\n",
+                "(rustboy) Breakpoint at $0103\n",
+                "(rustboy) Breakpoint at $0103\n",
+                "=> $0103  34        INC (HL)\n",
+                "(rustboy) => $0104  18 FD     JR $-1\n",
+                // the empty line steps again
+                "(rustboy) => $0103  34        INC (HL)\n",
+                "(rustboy) AF=0000 BC=0000 DE=0000 HL=C000 SP=0000 PC=0103\n",
+                "Flags ---- IME=1 HALT=0\n",
+                "(rustboy) $C000  01 00 00 00                                      ....\n",
+                "(rustboy)    $0100  21 00 C0  LD HL,$C000\n",
+                "=> $0103  34        INC (HL)\n",
+                "   $0104  18 FD     JR $-1\n",
+                "(rustboy) ",
+            )
+        );
+    }
+}
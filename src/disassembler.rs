@@ -0,0 +1,126 @@
+use crate::cpu::instruction::{Instruction, LoadType};
+use std::fmt;
+
+const PREFIX: u8 = 0xCB;
+// takes a second byte that isn't an operand
+const STOP: u8 = 0x10;
+
+/// One instruction decoded from memory with the bytes it spans. Displays as
+/// RGBDS source, `LD A,(HL+)` or `JR NZ,$-5`, unknown opcodes as `DB $D3`.
+pub struct Disassembly {
+    pub address: u16,
+    // opcode, operands included
+    pub bytes: Vec<u8>,
+    // None for the opcodes the SM83 doesn't have
+    pub instruction: Option<Instruction>,
+}
+
+// decodes the instruction at address, reading memory through read
+pub fn disassemble(address: u16, read: impl Fn(u16) -> u8) -> Disassembly {
+    let opcode = read(address);
+    let prefixed = opcode == PREFIX;
+    let byte = if prefixed {
+        read(address.wrapping_add(1))
+    } else {
+        opcode
+    };
+    let instruction = Instruction::from_byte(byte, prefixed);
+    let size = match instruction {
+        Some(_) => Instruction::size(byte, prefixed) as u16,
+        None => 1,
+    };
+    Disassembly {
+        address,
+        bytes: (0..size).map(|i| read(address.wrapping_add(i))).collect(),
+        instruction,
+    }
+}
+
+impl Disassembly {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.size())
+    }
+    // the value following the opcode, if it takes one
+    pub fn immediate(&self) -> Option<u16> {
+        match self.bytes.as_slice() {
+            [PREFIX, _] | [STOP, _] => None,
+            [_, value] => Some(*value as u16),
+            [_, lsb, msb] => Some(u16::from_le_bytes([*lsb, *msb])),
+            _ => None,
+        }
+    }
+    // where JR, JP, CALL and RST go, JP HL and returns aren't known without running
+    pub fn jump_target(&self) -> Option<u16> {
+        match self.instruction.as_ref()? {
+            Instruction::JR(_) => {
+                let offset = self.immediate()? as u8 as i8;
+                Some(self.next_address().wrapping_add(offset as u16))
+            }
+            Instruction::JP(_) | Instruction::CALL(_) => self.immediate(),
+            Instruction::RST(address) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = match &self.instruction {
+            Some(instruction) => instruction,
+            None => return write!(f, "DB ${:02X}", self.bytes[0]),
+        };
+        let text = instruction.to_string();
+        let value = match self.immediate() {
+            Some(value) => value,
+            None => return f.write_str(&text),
+        };
+        let offset = value as u8 as i8;
+        // the placeholder Instruction shows and what replaces it
+        let (placeholder, operand) = match instruction {
+            // relative to the start of the instruction, the way RGBDS reads $
+            Instruction::JR(_) => ("e8", format!("${:+}", offset as i16 + 2)),
+            Instruction::ADDSP => ("e8", offset.to_string()),
+            Instruction::LD(LoadType::HLFromSPN) => ("+e8", format!("{:+}", offset)),
+            _ if text.contains("a8") => ("a8", format!("${:04X}", 0xFF00 | value)),
+            _ if text.contains("n8") => ("n8", format!("${:02X}", value)),
+            _ if text.contains("n16") => ("n16", format!("${:04X}", value)),
+            _ => ("a16", format!("${:04X}", value)),
+        };
+        f.write_str(&text.replacen(placeholder, &operand, 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the text and the size of the instruction at 0100
+    fn disassemble_bytes(bytes: &[u8]) -> (String, u16) {
+        let disassembly = disassemble(0x100, |address| {
+            bytes.get((address - 0x100) as usize).copied().unwrap_or(0)
+        });
+        (disassembly.to_string(), disassembly.size())
+    }
+
+    #[test]
+    fn operands_are_written_the_rgbds_way() {
+        assert_eq!(disassemble_bytes(&[0x2A]), ("LD A,(HL+)".to_string(), 1));
+        assert_eq!(
+            disassemble_bytes(&[0x20, 0xF9]),
+            ("JR NZ,$-5".to_string(), 2)
+        );
+        assert_eq!(disassemble_bytes(&[0xCB, 0x7C]), ("BIT 7,H".to_string(), 2));
+        assert_eq!(
+            disassemble_bytes(&[0xE0, 0x44]),
+            ("LDH ($FF44),A".to_string(), 2)
+        );
+        assert_eq!(
+            disassemble_bytes(&[0xFA, 0x34, 0x12]),
+            ("LD A,($1234)".to_string(), 3)
+        );
+        assert_eq!(disassemble_bytes(&[0xD3]), ("DB $D3".to_string(), 1));
+    }
+}
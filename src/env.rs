@@ -0,0 +1,252 @@
+use crate::{
+    gpu::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    joypad::Buttons,
+    memory_bus::MemoryBus,
+    GameBoy,
+};
+use static_assertions::assert_impl_all;
+use std::{
+    io::{self, Error},
+    panic::{self, AssertUnwindSafe},
+};
+
+fn crashed(pc: u16) -> Error {
+    Error::other(format!(
+        "emulator crashed at ${:04X}, reset to start over",
+        pc
+    ))
+}
+
+/// Part of the reward of a step, read from memory once the step's frames ran.
+pub enum Reward {
+    // scale times how far the byte went up since the previous step, negative when it went down
+    Increase {
+        address: u16,
+        scale: f32,
+    },
+    // paid on every step that ends with the byte holding value
+    Equals {
+        address: u16,
+        value: u8,
+        reward: f32,
+    },
+    Custom(Box<dyn FnMut(&MemoryBus) -> f32 + Send>),
+}
+
+/// Ends the episode once it holds after a step.
+pub enum Termination {
+    Equals { address: u16, value: u8 },
+    Custom(Box<dyn FnMut(&MemoryBus) -> bool + Send>),
+}
+
+/// Gym style environment around a headless Game Boy. Each step holds the
+/// action's buttons for `frame_skip` frames and returns a grayscale screen
+/// shrunk by `downsample` in both directions, the sum of the rewards and
+/// whether a termination condition or the step limit was reached. It's Send,
+/// so every thread can run its own. A step on which the emulator crashed fails,
+/// and so do the following ones until the next reset.
+pub struct Env {
+    game_boy: GameBoy,
+    // what reset goes back to
+    start: Vec<u8>,
+    frame_skip: u32,
+    downsample: usize,
+    // with the byte each Increase reward saw after the previous step
+    rewards: Vec<(Reward, u8)>,
+    terminations: Vec<Termination>,
+    max_steps: Option<u32>,
+    steps: u32,
+    crashed: bool,
+}
+
+assert_impl_all!(Env: Send);
+
+impl Env {
+    pub fn new(boot_rom: Vec<u8>, rom: &[u8]) -> io::Result<Self> {
+        let game_boy = GameBoy::new(boot_rom, rom)?;
+        Ok(Env {
+            start: game_boy.save_state(),
+            game_boy,
+            frame_skip: 4,
+            downsample: 2,
+            rewards: Vec::new(),
+            terminations: Vec::new(),
+            max_steps: None,
+            steps: 0,
+            crashed: false,
+        })
+    }
+    pub fn game_boy(&self) -> &GameBoy {
+        &self.game_boy
+    }
+    pub fn game_boy_mut(&mut self) -> &mut GameBoy {
+        &mut self.game_boy
+    }
+    // episodes start from this save state instead of power on, past the title screen say
+    pub fn set_start_state(&mut self, state: Vec<u8>) -> io::Result<()> {
+        self.game_boy.load_state(&state)?;
+        self.start = state;
+        Ok(())
+    }
+    pub fn set_frame_skip(&mut self, frames: u32) {
+        self.frame_skip = frames.max(1);
+    }
+    // 1 keeps the full 160x144, the factor has to divide both
+    pub fn set_downsample(&mut self, factor: usize) {
+        assert!(
+            factor > 0
+                && SCREEN_WIDTH.is_multiple_of(factor)
+                && SCREEN_HEIGHT.is_multiple_of(factor),
+            "downsample factor {} doesn't divide the screen",
+            factor
+        );
+        self.downsample = factor;
+    }
+    pub fn add_reward(&mut self, reward: Reward) {
+        self.rewards.push((reward, 0));
+    }
+    pub fn add_termination(&mut self, termination: Termination) {
+        self.terminations.push(termination);
+    }
+    pub fn set_max_steps(&mut self, steps: Option<u32>) {
+        self.max_steps = steps;
+    }
+    // width and height of the observations
+    pub fn observation_shape(&self) -> (usize, usize) {
+        (
+            SCREEN_WIDTH / self.downsample,
+            SCREEN_HEIGHT / self.downsample,
+        )
+    }
+
+    // starts a new episode and returns its first observation
+    pub fn reset(&mut self) -> Vec<u8> {
+        self.game_boy
+            .load_state(&self.start)
+            .expect("start state was loaded before");
+        self.game_boy.set_buttons(Buttons::default());
+        self.steps = 0;
+        self.crashed = false;
+        let bus = self.game_boy.cpu().bus();
+        for (reward, last) in self.rewards.iter_mut() {
+            if let Reward::Increase { address, .. } = reward {
+                *last = bus.peek_byte(*address);
+            }
+        }
+        self.observation()
+    }
+
+    pub fn step(&mut self, action: Buttons) -> io::Result<(Vec<u8>, f32, bool)> {
+        let pc = self.game_boy.cpu().pc();
+        if self.crashed {
+            return Err(crashed(pc));
+        }
+        self.game_boy.set_buttons(action);
+        let (game_boy, frame_skip) = (&mut self.game_boy, self.frame_skip);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            for _ in 0..frame_skip {
+                game_boy.run_frame();
+            }
+        }));
+        if result.is_err() {
+            self.crashed = true;
+            return Err(crashed(self.game_boy.cpu().pc()));
+        }
+        self.steps += 1;
+        let bus = self.game_boy.cpu().bus();
+        let mut total = 0.0;
+        for (reward, last) in self.rewards.iter_mut() {
+            total += match reward {
+                Reward::Increase { address, scale } => {
+                    let byte = bus.peek_byte(*address);
+                    let increase = byte as f32 - *last as f32;
+                    *last = byte;
+                    increase * *scale
+                }
+                Reward::Equals {
+                    address,
+                    value,
+                    reward,
+                } if bus.peek_byte(*address) == *value => *reward,
+                Reward::Equals { .. } => 0.0,
+                Reward::Custom(reward) => reward(bus),
+            };
+        }
+        // every condition sees the step, custom ones may keep state of their own
+        let mut done = self.max_steps.is_some_and(|steps| self.steps >= steps);
+        for termination in self.terminations.iter_mut() {
+            done |= match termination {
+                Termination::Equals { address, value } => bus.peek_byte(*address) == *value,
+                Termination::Custom(termination) => termination(bus),
+            };
+        }
+        Ok((self.observation(), total, done))
+    }
+
+    // luma of the screen averaged over downsample x downsample blocks
+    fn observation(&self) -> Vec<u8> {
+        let factor = self.downsample;
+        let (width, height) = self.observation_shape();
+        let canvas = self.game_boy.framebuffer();
+        let mut observation = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0;
+                for row in y * factor..(y + 1) * factor {
+                    for pixel in &canvas[row * SCREEN_WIDTH + x * factor..][..factor] {
+                        let [_, red, green, blue] = pixel.to_be_bytes();
+                        sum += (red as u32 * 299 + green as u32 * 587 + blue as u32 * 114) / 1000;
+                    }
+                }
+                observation.push((sum / (factor * factor) as u32) as u8);
+            }
+        }
+        observation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::{rom_with, COUNTER};
+    use std::thread;
+
+    fn env(program: &[u8]) -> Env {
+        Env::new(vec![0; 0x100], &rom_with(program)).unwrap()
+    }
+
+    #[test]
+    fn instances_in_threads_run_the_same() {
+        let runs: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    let mut env = env(&COUNTER);
+                    env.add_reward(Reward::Increase {
+                        address: 0xC000,
+                        scale: 1.0,
+                    });
+                    env.set_max_steps(Some(5));
+                    env.reset();
+                    (0..5)
+                        .map(|_| env.step(Buttons::default()).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let runs: Vec<_> = runs.into_iter().map(|run| run.join().unwrap()).collect();
+        assert!(runs.iter().all(|run| *run == runs[0]));
+        assert_ne!(runs[0][0].1, 0.0);
+        assert!(runs[0][4].2, "done after the step limit");
+    }
+
+    #[test]
+    fn a_crash_fails_the_step_until_reset() {
+        // D3 isn't an instruction
+        let mut env = env(&[0xD3]);
+        let result = env.step(Buttons::default());
+        assert!(result.unwrap_err().to_string().contains("$0100"));
+        assert!(env.step(Buttons::default()).is_err());
+        env.reset();
+        assert!(!env.crashed);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    apu::SAMPLE_RATE,
+    cdl::CodeDataLog,
+    cpu::CPU,
+    gpu::CYCLES_PER_FRAME,
+    joypad::Buttons,
+    memory_bus::MemoryBus,
+    profiler::Profiler,
+    save_state::{self, read_u32, write_u32, SaveState},
+    serial::SerialDevice,
+    trace::{self, Tracer},
+};
+use std::{io, sync::OnceLock};
+
+/// The whole machine, everything a frontend needs to drive it.
+pub struct GameBoy {
+    cpu: CPU,
+    // cycles the last instruction of the previous frame ran past its end
+    frame_cycles: u32,
+}
+
+impl GameBoy {
+    pub const AUDIO_SAMPLE_RATE: u32 = SAMPLE_RATE;
+
+    // fails when the cartridge's mapper isn't emulated
+    pub fn new(boot_rom: Vec<u8>, rom: &[u8]) -> io::Result<Self> {
+        let mut bus = MemoryBus::new(boot_rom);
+        bus.load_cartridge(rom)?;
+        Ok(GameBoy {
+            cpu: CPU::new(bus),
+            frame_cycles: 0,
+        })
+    }
+    // power cycles the machine, the cartridge ram keeps its contents and its address
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.frame_cycles = 0;
+    }
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.bus_mut().connect_serial(device);
+    }
+    // None stops tracing and flushes what was logged
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        let doctor = tracer.as_ref().is_some_and(Tracer::doctor_mode);
+        self.cpu
+            .bus_mut()
+            .stub_line(doctor.then_some(trace::DOCTOR_LINE));
+        self.cpu.set_tracer(tracer);
+    }
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.cpu.set_profiler(profiler);
+    }
+    // stops profiling and hands over what was collected
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.cpu.take_profiler()
+    }
+    pub fn set_code_data_log(&mut self, log: Option<CodeDataLog>) {
+        self.cpu.bus_mut().set_code_data_log(log);
+    }
+    pub fn take_code_data_log(&mut self) -> Option<CodeDataLog> {
+        self.cpu.bus_mut().take_code_data_log()
+    }
+    pub fn step(&mut self) -> u8 {
+        let cycles = self.cpu.step();
+        self.frame_cycles += cycles as u32;
+        cycles
+    }
+    pub fn run_frame(&mut self) {
+        while !self.step_in_frame() {}
+    }
+    // one instruction of the frame, true when it completed the frame
+    pub fn step_in_frame(&mut self) -> bool {
+        if self.frame_cycles < CYCLES_PER_FRAME {
+            self.step();
+        }
+        if self.frame_cycles < CYCLES_PER_FRAME {
+            return false;
+        }
+        self.frame_cycles -= CYCLES_PER_FRAME;
+        true
+    }
+    // 160x144 pixels as 0x00RRGGBB
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.cpu.bus().gpu().canvas
+    }
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.cpu.bus_mut().set_buttons(buttons);
+    }
+    // interleaved stereo samples in -1.0..=1.0 at AUDIO_SAMPLE_RATE produced since the last call.
+    // At most a second of them is kept, so a frontend without audio can leave them alone
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus_mut().take_audio_samples()
+    }
+    // complete machine state, loading it back reproduces the following frames exactly
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        save_state::write_header(&mut state);
+        write_u32(&mut state, self.frame_cycles);
+        self.cpu.save_state(&mut state);
+        state
+    }
+    // a state that doesn't fit leaves the machine as it was
+    pub fn load_state(&mut self, mut state: &[u8]) -> io::Result<()> {
+        // the layout only depends on the cartridge, so the size is checked before
+        // any component is overwritten
+        let size = state.len();
+        save_state::read_header(&mut state)?;
+        let expected = fixed_state_size() + self.cpu.bus().cartridge().state_size();
+        if size != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "save state is {} bytes, this cartridge's are {}",
+                    size, expected
+                ),
+            ));
+        }
+        self.frame_cycles = read_u32(&mut state)?;
+        self.cpu.load_state(&mut state)
+    }
+}
+
+// the state of everything but the cartridge is the same size on every machine
+fn fixed_state_size() -> usize {
+    static SIZE: OnceLock<usize> = OnceLock::new();
+    *SIZE.get_or_init(|| {
+        let game_boy = GameBoy::new(vec![0; 0x100], &[]).unwrap();
+        game_boy.save_state().len() - game_boy.cpu.bus().cartridge().state_size()
+    })
+}
+
+// a ROM only cartridge running program at 0100, for the tests all over the crate
+#[cfg(test)]
+pub(crate) fn rom_with(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    rom
+}
+
+// LD HL,C000; INC (HL); JR -3 counts up at C000 forever
+#[cfg(test)]
+pub(crate) const COUNTER: [u8; 6] = [0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD];
+
+#[cfg(test)]
+pub(crate) fn counter_game_boy() -> GameBoy {
+    GameBoy::new(vec![0; 0x100], &rom_with(&COUNTER)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MBC1 with 8 KB of ram, every bank starts with its own number. The program
+    // enables the ram, selects bank 3, stores 42 at A000 and loops
+    fn game_boy() -> GameBoy {
+        let mut rom = vec![0; 8 * 0x4000];
+        for bank in 0..8 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x02;
+        rom[0x149] = 0x02;
+        #[rustfmt::skip]
+        let program = [
+            0x3E, 0x0A, 0xEA, 0x00, 0x00,
+            0x3E, 0x03, 0xEA, 0x00, 0x20,
+            0x3E, 0x42, 0xEA, 0x00, 0xA0,
+            0x18, 0xFE,
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        GameBoy::new(vec![0; 0x100], &rom).unwrap()
+    }
+
+    #[test]
+    fn load_state_restores_the_machine_and_the_mapper() {
+        let mut game_boy = game_boy();
+        game_boy.run_frame();
+        let state = game_boy.save_state();
+        let bus = game_boy.cpu_mut().bus_mut();
+        assert_eq!((bus.peek_byte(0x4000), bus.peek_byte(0xA000)), (3, 0x42));
+        bus.write_byte(0xA000, 0);
+        bus.write_byte(0x2000, 5);
+        bus.write_byte(0x0000, 0);
+        game_boy.run_frame();
+
+        game_boy.load_state(&state).unwrap();
+        assert_eq!(game_boy.save_state(), state);
+        let bus = game_boy.cpu_mut().bus_mut();
+        assert_eq!((bus.peek_byte(0x4000), bus.peek_byte(0xA000)), (3, 0x42));
+    }
+
+    #[test]
+    fn a_bad_state_changes_nothing() {
+        let mut game_boy = game_boy();
+        let state = game_boy.save_state();
+        game_boy.run_frame();
+        let before = game_boy.save_state();
+        assert!(game_boy.load_state(&state[..state.len() - 1]).is_err());
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(game_boy.load_state(&longer).is_err());
+        let mut other = state;
+        other[0] ^= 1;
+        assert!(game_boy.load_state(&other).is_err());
+        assert_eq!(game_boy.save_state(), before);
+    }
+
+    #[test]
+    fn the_state_size_follows_the_cartridge() {
+        // MBC3 with a clock and 32 KB of ram
+        let mut rom = vec![0; 4 * 0x4000];
+        rom[0x147] = 0x10;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x03;
+        let clock = GameBoy::new(vec![0; 0x100], &rom).unwrap();
+        for game_boy in [counter_game_boy(), game_boy(), clock] {
+            let cartridge = game_boy.cpu.bus().cartridge();
+            assert_eq!(
+                game_boy.save_state().len(),
+                fixed_state_size() + cartridge.state_size()
+            );
+        }
+    }
+}
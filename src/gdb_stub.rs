@@ -0,0 +1,476 @@
+use crate::{
+    cpu::CPU,
+    watchpoint::{Access, WatchAction, WatchHit, Watchpoint},
+    GameBoy,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+// the client asks to stop a running target with a bare ctrl-c byte
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+// instructions run between checks for an interrupt from the client
+const POLL_INTERVAL: u32 = 1024;
+// af, bc, de, hl, sp, pc: the first registers of gdb's z80 target
+const REGISTER_COUNT: usize = 6;
+const PACKET_SIZE: usize = 0x1000;
+
+enum Stop {
+    Signal(u8),
+    Watch(WatchHit),
+}
+
+/// GDB remote serial protocol server for the SM83 core. The registers are
+/// sent as little endian AF, BC, DE, HL, SP and PC, the way `gdb` lays them
+/// out with `set architecture z80`, so any RSP client can attach over TCP.
+pub struct GdbStub {
+    stream: TcpStream,
+    incoming: Receiver<u8>,
+    breakpoints: BTreeSet<u16>,
+    // bus watchpoint ids by the type, address and length gdb knows them by
+    watchpoints: HashMap<(u8, u16, u16), u32>,
+}
+
+impl GdbStub {
+    // blocks until a client connects
+    pub fn listen(address: &str) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+        let mut reader = stream.try_clone()?;
+        let (sender, incoming) = mpsc::channel();
+        // reading on its own thread lets a running target notice an interrupt
+        thread::spawn(move || {
+            let mut byte = [0];
+            while reader.read_exact(&mut byte).is_ok() {
+                if sender.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(GdbStub {
+            stream,
+            incoming,
+            breakpoints: BTreeSet::new(),
+            watchpoints: HashMap::new(),
+        })
+    }
+
+    // serves packets until the client detaches, kills the target or disconnects
+    pub fn run(&mut self, game_boy: &mut GameBoy) -> io::Result<()> {
+        while let Some(packet) = self.next_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'D') => {
+                    self.send_packet("OK")?;
+                    break;
+                }
+                Some(b'k') => break,
+                _ => self.execute(game_boy, &packet),
+            };
+            self.send_packet(&reply)?;
+        }
+        self.clear_watchpoints(game_boy);
+        Ok(())
+    }
+
+    // the reply to a packet, empty for anything unsupported
+    fn execute(&mut self, game_boy: &mut GameBoy, packet: &str) -> String {
+        let (command, args) = match packet.get(..1) {
+            Some(command) => (command, &packet[1..]),
+            None => return String::new(),
+        };
+        match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => hex(&GdbStub::read_registers(game_boy.cpu())),
+            "G" => match parse_hex(args) {
+                Some(bytes) => {
+                    for (index, value) in bytes.chunks_exact(2).enumerate() {
+                        let value = u16::from_le_bytes([value[0], value[1]]);
+                        GdbStub::write_register(game_boy.cpu_mut(), index, value);
+                    }
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(index) if index < REGISTER_COUNT => {
+                    let registers = GdbStub::read_registers(game_boy.cpu());
+                    hex(&registers[index * 2..index * 2 + 2])
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(index, value)| {
+                    let index = usize::from_str_radix(index, 16).ok()?;
+                    let value = parse_hex(value)?;
+                    Some((index, u16::from_le_bytes([*value.first()?, *value.get(1)?])))
+                });
+                match register {
+                    Some((index, value)) if index < REGISTER_COUNT => {
+                        GdbStub::write_register(game_boy.cpu_mut(), index, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_address_length(args) {
+                Some((address, length)) => {
+                    let bus = game_boy.cpu().bus();
+                    let bytes: Vec<u8> = (0..length.min(PACKET_SIZE as u16 / 2))
+                        .map(|i| bus.peek_byte(address.wrapping_add(i)))
+                        .collect();
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(target, data)| {
+                    Some((parse_address_length(target)?.0, parse_hex(data)?))
+                });
+                match write {
+                    Some((address, bytes)) => GdbStub::write_memory(game_boy, address, &bytes),
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => match parse_point(args) {
+                Some((kind, address, length)) => {
+                    let done = if command == "Z" {
+                        self.insert_point(game_boy, kind, address, length)
+                    } else {
+                        self.remove_point(game_boy, kind, address, length)
+                    };
+                    match done {
+                        Some(true) => "OK".to_string(),
+                        Some(false) => "E01".to_string(),
+                        None => String::new(),
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            "s" | "c" => {
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    game_boy.cpu_mut().set_pc(address);
+                }
+                match self.resume(game_boy, command == "s") {
+                    Stop::Signal(signal) => format!("S{:02x}", signal),
+                    Stop::Watch(hit) => {
+                        let kind = match hit.access {
+                            Access::Read => "rwatch",
+                            Access::Write => "watch",
+                            Access::Execute => return format!("S{:02x}", SIGTRAP),
+                        };
+                        format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.address)
+                    }
+                }
+            }
+            // there is only the one thread
+            "H" => "OK".to_string(),
+            "q" => match args {
+                _ if args.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    // runs one instruction or until a breakpoint, watchpoint or interrupt
+    fn resume(&mut self, game_boy: &mut GameBoy, single_step: bool) -> Stop {
+        let mut steps = 0u32;
+        loop {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                game_boy.step();
+            }));
+            if result.is_err() {
+                return Stop::Signal(SIGILL);
+            }
+            let hits = game_boy.cpu_mut().bus_mut().watchpoints().take_hits();
+            if let Some(hit) = hits.first() {
+                return Stop::Watch(*hit);
+            }
+            if single_step || self.breakpoints.contains(&game_boy.cpu().pc()) {
+                return Stop::Signal(SIGTRAP);
+            }
+            steps = steps.wrapping_add(1);
+            if steps.is_multiple_of(POLL_INTERVAL) && self.interrupted() {
+                return Stop::Signal(SIGINT);
+            }
+        }
+    }
+
+    // a vanished client counts as an interrupt, the next read notices it's gone
+    fn interrupted(&mut self) -> bool {
+        loop {
+            match self.incoming.try_recv() {
+                Ok(INTERRUPT) | Err(TryRecvError::Disconnected) => return true,
+                Ok(_) => continue,
+                Err(TryRecvError::Empty) => return false,
+            }
+        }
+    }
+
+    // None for kinds that aren't supported, otherwise whether it worked
+    fn insert_point(
+        &mut self,
+        game_boy: &mut GameBoy,
+        kind: u8,
+        address: u16,
+        length: u16,
+    ) -> Option<bool> {
+        match kind {
+            // software and hardware breakpoints are the same thing here
+            0 | 1 => {
+                self.breakpoints.insert(address);
+                Some(true)
+            }
+            2..=4 => {
+                let last = address.wrapping_add(length.max(1) - 1);
+                let id = game_boy.cpu_mut().bus_mut().watchpoints().add(Watchpoint {
+                    addresses: address..=last,
+                    read: kind != 2,
+                    write: kind != 3,
+                    execute: false,
+                    action: WatchAction::Pause,
+                });
+                self.watchpoints.insert((kind, address, length), id);
+                Some(true)
+            }
+            _ => None,
+        }
+    }
+
+    fn remove_point(
+        &mut self,
+        game_boy: &mut GameBoy,
+        kind: u8,
+        address: u16,
+        length: u16,
+    ) -> Option<bool> {
+        match kind {
+            0 | 1 => Some(self.breakpoints.remove(&address)),
+            2..=4 => Some(match self.watchpoints.remove(&(kind, address, length)) {
+                Some(id) => game_boy.cpu_mut().bus_mut().watchpoints().remove(id),
+                None => false,
+            }),
+            _ => None,
+        }
+    }
+
+    // the client's watchpoints shouldn't outlive the session
+    fn clear_watchpoints(&mut self, game_boy: &mut GameBoy) {
+        for (_, id) in self.watchpoints.drain() {
+            game_boy.cpu_mut().bus_mut().watchpoints().remove(id);
+        }
+    }
+
+    fn read_registers(cpu: &CPU) -> Vec<u8> {
+        let registers = cpu.registers();
+        let words = [
+            registers.get_af(),
+            registers.get_bc(),
+            registers.get_de(),
+            registers.get_hl(),
+            cpu.sp(),
+            cpu.pc(),
+        ];
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn write_register(cpu: &mut CPU, index: usize, value: u16) {
+        match index {
+            0 => cpu.registers_mut().set_af(value),
+            1 => cpu.registers_mut().set_bc(value),
+            2 => cpu.registers_mut().set_de(value),
+            3 => cpu.registers_mut().set_hl(value),
+            4 => cpu.set_sp(value),
+            5 => cpu.set_pc(value),
+            _ => {}
+        }
+    }
+
+    // writes go through the bus, so rom and unmapped addresses fail instead of panicking
+    fn write_memory(game_boy: &mut GameBoy, address: u16, bytes: &[u8]) -> String {
+        let bus = game_boy.cpu_mut().bus_mut();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            for (i, byte) in bytes.iter().enumerate() {
+                bus.write_byte(address.wrapping_add(i as u16), *byte);
+            }
+        }));
+        // the client's own writes shouldn't stop the target later
+        bus.watchpoints().take_hits();
+        match result {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E0e".to_string(),
+        }
+    }
+
+    // waits for the next well formed packet, None once the client is gone
+    fn next_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // acks, nacks and stray interrupts all come outside of packets
+            loop {
+                match self.incoming.recv() {
+                    Ok(b'$') => break,
+                    Ok(_) => continue,
+                    Err(_) => return Ok(None),
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.incoming.recv() {
+                    Ok(b'#') => break,
+                    Ok(byte) => data.push(byte),
+                    Err(_) => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.incoming.recv() {
+                    Ok(byte) => *digit = byte,
+                    Err(_) => return Ok(None),
+                }
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// ADDR,LENGTH
+fn parse_address_length(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        u16::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+// TYPE,ADDR,KIND where KIND is the length for watchpoints
+fn parse_point(text: &str) -> Option<(u8, u16, u16)> {
+    let (kind, rest) = text.split_once(',')?;
+    let (address, length) = parse_address_length(rest)?;
+    Some((kind.parse().ok()?, address, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+    use std::sync::mpsc::Sender;
+
+    // a stub fed through a channel, with the client end of a loopback connection
+    fn stub() -> (GdbStub, Sender<u8>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (sender, incoming) = mpsc::channel();
+        let stub = GdbStub {
+            stream,
+            incoming,
+            breakpoints: BTreeSet::new(),
+            watchpoints: HashMap::new(),
+        };
+        (stub, sender, client)
+    }
+
+    #[test]
+    fn hex_and_arguments_parse() {
+        assert_eq!(checksum_of(b"qSupported"), 0x37);
+        assert_eq!(hex(&[0x00, 0xAB, 0x10]), "00ab10");
+        assert_eq!(parse_hex("00aB10"), Some(vec![0x00, 0xAB, 0x10]));
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("zz"), None);
+        assert_eq!(parse_address_length("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_address_length("10000,1"), None);
+        assert_eq!(parse_point("2,c000,1"), Some((2, 0xC000, 1)));
+        assert_eq!(parse_point("x,c000,1"), None);
+    }
+
+    #[test]
+    fn packets_are_acked_or_nacked_by_checksum() {
+        let (mut stub, sender, mut client) = stub();
+        // an interrupt and an ack before a packet with a bad checksum, then a good one
+        for byte in b"\x03+$g#00$m0,2#fb".iter() {
+            sender.send(*byte).unwrap();
+        }
+        assert_eq!(stub.next_packet().unwrap().as_deref(), Some("m0,2"));
+        stub.send_packet("OK").unwrap();
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"-+$OK#9a");
+
+        drop(sender);
+        assert_eq!(stub.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn registers_memory_and_breakpoints() {
+        let (mut stub, _sender, _client) = stub();
+        let mut game_boy = counter_game_boy();
+        game_boy.cpu_mut().set_pc(0x100);
+        assert_eq!(stub.execute(&mut game_boy, "p5"), "0001");
+        assert_eq!(stub.execute(&mut game_boy, "p6"), "E01");
+        assert_eq!(stub.execute(&mut game_boy, "P3=3412"), "OK");
+        assert_eq!(game_boy.cpu().registers().get_hl(), 0x1234);
+        // af bc de hl sp pc, little endian
+        let registers = stub.execute(&mut game_boy, "g");
+        assert_eq!(&registers[12..16], "3412");
+        assert_eq!(&registers[20..24], "0001");
+
+        assert_eq!(stub.execute(&mut game_boy, "Mc000,2:abcd"), "OK");
+        assert_eq!(stub.execute(&mut game_boy, "mc000,2"), "abcd");
+        assert_eq!(stub.execute(&mut game_boy, "m0100,3"), "2100c0");
+
+        assert_eq!(stub.execute(&mut game_boy, "Z0,104,1"), "OK");
+        assert_eq!(
+            stub.execute(&mut game_boy, "c"),
+            format!("S{:02x}", SIGTRAP)
+        );
+        assert_eq!(game_boy.cpu().pc(), 0x104);
+        assert_eq!(stub.execute(&mut game_boy, "Z2,c000,1"), "OK");
+        assert_eq!(stub.execute(&mut game_boy, "z0,104,1"), "OK");
+        assert_eq!(stub.execute(&mut game_boy, "c"), "T05watch:c000;");
+        assert_eq!(stub.execute(&mut game_boy, "z2,c000,1"), "OK");
+        assert_eq!(stub.execute(&mut game_boy, "z2,c000,1"), "E01");
+        assert_eq!(stub.execute(&mut game_boy, "Z9,0,1"), "");
+    }
+}
@@ -1,21 +1,75 @@
-const SCREEN_WIDTH: usize = 160;
-const SCREEN_HEIGHT: usize = 144;
+use crate::{
+    interrupt::Interrupt,
+    save_state::{
+        read_bool, read_bytes, read_u32, read_u8, write_bool, write_bytes, write_u32, write_u8,
+        SaveState,
+    },
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+// 154 lines of 456 cycles each
+pub const CYCLES_PER_FRAME: u32 = 70224;
 pub const RAM_SIZE: usize = 0x2000;
 pub const SPRITE_SIZE: usize = 0xA0;
 
-const TILE_SET0_FIRST: usize = 0x800;
-const TILE_SET0_LAST: usize = 0x17FF;
-const TILE_SET1_FIRST: usize = 0x0;
-const TILE_SET1_LAST: usize = 0xFFF;
+pub const LCD_CONTROL: u16 = 0xFF40;
+pub const LCD_STATUS: u16 = 0xFF41;
+pub const SCROLL_Y: u16 = 0xFF42;
+pub const SCROLL_X: u16 = 0xFF43;
+pub const LINE: u16 = 0xFF44;
+pub const LINE_COMPARE: u16 = 0xFF45;
+// copies into the sprite table from anywhere, so the memory bus handles it
+pub const DMA: u16 = 0xFF46;
+pub const BACKGROUND_PALETTE: u16 = 0xFF47;
+pub const SPRITE_PALETTE0: u16 = 0xFF48;
+pub const SPRITE_PALETTE1: u16 = 0xFF49;
+pub const WINDOW_Y: u16 = 0xFF4A;
+pub const WINDOW_X: u16 = 0xFF4B;
+
+// LCD_CONTROL bits
+const LCD_ENABLE: u8 = 0x80;
+const WINDOW_TILE_MAP: u8 = 0x40;
+const WINDOW_ENABLE: u8 = 0x20;
+// tile numbers count from 8000 instead of signed from 9000
+const UNSIGNED_TILE_DATA: u8 = 0x10;
+const BACKGROUND_TILE_MAP: u8 = 0x08;
+const TALL_SPRITES: u8 = 0x04;
+const SPRITE_ENABLE: u8 = 0x02;
+const BACKGROUND_ENABLE: u8 = 0x01;
+
+// LCD_STATUS bits, the mode is in the lowest two
+const LINE_COMPARE_INTERRUPT: u8 = 0x40;
+const OAM_INTERRUPT: u8 = 0x20;
+const VERTICAL_BLANK_INTERRUPT: u8 = 0x10;
+const HORIZONTAL_BLANK_INTERRUPT: u8 = 0x08;
+const LINE_COMPARE_EQUAL: u8 = 0x04;
+const STATUS_INTERRUPTS: u8 = 0x78;
+
+// sprite attribute bits
+const SPRITE_BEHIND_BACKGROUND: u8 = 0x80;
+const SPRITE_FLIP_Y: u8 = 0x40;
+const SPRITE_FLIP_X: u8 = 0x20;
+const SPRITE_PALETTE: u8 = 0x10;
+
+const TILE_SET0_FIRST: usize = 0x1000;
 const TILE_MAP0_FIRST: usize = 0x1800;
-const TILE_MAP0_LAST: usize = 0x1BFF;
 const TILE_MAP1_FIRST: usize = 0x1C00;
-const TILE_MAP1_LAST: usize = 0x1FFF;
 
 const BYTES_PER_TILE: usize = 16;
-const PIXELS_PER_TILE: usize = 64;
-const TILE_COUNT: usize = 256;
 const BACKGROUND_WIDTH: usize = 256;
+const SPRITES_PER_LINE: usize = 10;
+
+const OAM_CYCLES: u32 = 80;
+const TRANSFER_CYCLES: u32 = 172;
+const HORIZONTAL_BLANK_CYCLES: u32 = 204;
+const LINE_CYCLES: u32 = 456;
+const LINES: u32 = 154;
 
 #[derive(Clone, Copy)]
 struct Color(u8, u8, u8);
@@ -35,22 +89,44 @@ impl From<u8> for Color {
         }
     }
 }
-type Tile = [Color; PIXELS_PER_TILE];
+
+impl From<Color> for u32 {
+    fn from(color: Color) -> Self {
+        (color.0 as u32) << 16 | (color.1 as u32) << 8 | color.2 as u32
+    }
+}
+
+// the shade a palette register gives a colour number
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
 
 pub struct GPU {
-    pub background: [u32; BACKGROUND_WIDTH * BACKGROUND_WIDTH],
-    pub canvas: [u32; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // boxed so a Game Boy fits on the stack of any thread
+    pub canvas: Box<[u32]>,
     pub ram: [u8; RAM_SIZE],
     pub sprite: [u8; SPRITE_SIZE],
-    tile_set: [[Tile; TILE_COUNT]; 2],
     read_mode: ReadMode,
     clock: u32,
     line: u32,
 
-    scroll_x: usize,
-    scroll_y: usize,
+    control: u8,
+    // only the interrupt enable bits, the rest is derived
+    status: u8,
+    scroll_x: u8,
+    scroll_y: u8,
+    line_compare: u8,
+    background_palette: u8,
+    sprite_palettes: [u8; 2],
+    window_x: u8,
+    window_y: u8,
+    // the window has a line counter of its own, it only advances on lines that show it
+    window_line: u32,
+    // STAT requests an interrupt when any of its enabled conditions starts to hold
+    status_line: bool,
 }
 
+#[derive(Clone, Copy, PartialEq)]
 enum ReadMode {
     HorizontalBlank,
     VerticalBlank,
@@ -58,96 +134,509 @@ enum ReadMode {
     ScanlineVRAM,
 }
 
+impl ReadMode {
+    // the mode in the lowest two bits of STAT
+    fn from_bits(status: u8) -> Self {
+        match status & 0b11 {
+            0 => ReadMode::HorizontalBlank,
+            1 => ReadMode::VerticalBlank,
+            2 => ReadMode::ScanlineOAM,
+            _ => ReadMode::ScanlineVRAM,
+        }
+    }
+}
+
+impl Default for GPU {
+    fn default() -> Self {
+        GPU::new()
+    }
+}
+
 impl GPU {
     pub fn new() -> Self {
         GPU {
-            background: [0xFFFF; BACKGROUND_WIDTH * BACKGROUND_WIDTH],
-            canvas: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            canvas: vec![COLOR_WHITE.into(); SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
             ram: [0; RAM_SIZE],
             sprite: [0; SPRITE_SIZE],
-            tile_set: [[[COLOR_WHITE; PIXELS_PER_TILE]; TILE_COUNT]; 2],
             read_mode: ReadMode::HorizontalBlank,
             clock: 0,
             line: 0,
+            control: 0,
+            status: 0,
             scroll_x: 0,
             scroll_y: 0,
+            line_compare: 0,
+            background_palette: 0,
+            sprite_palettes: [0; 2],
+            window_x: 0,
+            window_y: 0,
+            window_line: 0,
+            status_line: false,
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            LCD_CONTROL => self.control,
+            LCD_STATUS => {
+                let equal = match self.line == self.line_compare as u32 {
+                    true => LINE_COMPARE_EQUAL,
+                    false => 0,
+                };
+                0x80 | self.status | equal | self.read_mode as u8
+            }
+            SCROLL_Y => self.scroll_y,
+            SCROLL_X => self.scroll_x,
+            LINE => self.line as u8,
+            LINE_COMPARE => self.line_compare,
+            BACKGROUND_PALETTE => self.background_palette,
+            SPRITE_PALETTE0 => self.sprite_palettes[0],
+            SPRITE_PALETTE1 => self.sprite_palettes[1],
+            WINDOW_Y => self.window_y,
+            WINDOW_X => self.window_x,
+            _ => panic!("invalid gpu address: {:x}", address),
+        }
+    }
+    pub fn write_byte(&mut self, address: u16, byte: u8) {
+        match address {
+            LCD_CONTROL => {
+                let was_enabled = self.control & LCD_ENABLE != 0;
+                self.control = byte;
+                match (was_enabled, byte & LCD_ENABLE != 0) {
+                    // switched off the screen goes blank and LY stays at 0
+                    (true, false) => {
+                        self.read_mode = ReadMode::HorizontalBlank;
+                        self.clock = 0;
+                        self.line = 0;
+                        self.window_line = 0;
+                        self.canvas.fill(COLOR_WHITE.into());
+                    }
+                    (false, true) => self.read_mode = ReadMode::ScanlineOAM,
+                    _ => {}
+                }
+            }
+            LCD_STATUS => self.status = byte & STATUS_INTERRUPTS,
+            SCROLL_Y => self.scroll_y = byte,
+            SCROLL_X => self.scroll_x = byte,
+            // read only
+            LINE => {}
+            LINE_COMPARE => self.line_compare = byte,
+            BACKGROUND_PALETTE => self.background_palette = byte,
+            SPRITE_PALETTE0 => self.sprite_palettes[0] = byte,
+            SPRITE_PALETTE1 => self.sprite_palettes[1] = byte,
+            WINDOW_Y => self.window_y = byte,
+            WINDOW_X => self.window_x = byte,
+            _ => panic!("invalid gpu address: {:x}", address),
         }
     }
 
-    fn step(&mut self) {
+    // sets FF40-FF4B from a snapshot taken by another emulator. It holds LY and the
+    // mode but not how far into the mode the PPU was, so the mode starts over
+    pub fn restore_registers(&mut self, registers: &[u8]) {
+        let register = |address: u16| registers[(address - LCD_CONTROL) as usize];
+        self.control = register(LCD_CONTROL);
+        self.status = register(LCD_STATUS) & STATUS_INTERRUPTS;
+        self.scroll_y = register(SCROLL_Y);
+        self.scroll_x = register(SCROLL_X);
+        self.line_compare = register(LINE_COMPARE);
+        self.background_palette = register(BACKGROUND_PALETTE);
+        self.sprite_palettes = [register(SPRITE_PALETTE0), register(SPRITE_PALETTE1)];
+        self.window_y = register(WINDOW_Y);
+        self.window_x = register(WINDOW_X);
+        self.clock = 0;
+        self.line = register(LINE) as u32 % LINES;
+        self.read_mode = match ReadMode::from_bits(register(LCD_STATUS)) {
+            _ if self.control & LCD_ENABLE == 0 => {
+                self.line = 0;
+                ReadMode::HorizontalBlank
+            }
+            _ if self.line >= SCREEN_HEIGHT as u32 => ReadMode::VerticalBlank,
+            ReadMode::VerticalBlank => ReadMode::ScanlineOAM,
+            mode => mode,
+        };
+        // lines the window showed on so far, assuming it was enabled all frame
+        self.window_line = match self.control & WINDOW_ENABLE {
+            0 => 0,
+            _ => self.line.saturating_sub(self.window_y as u32),
+        }
+        .min(SCREEN_HEIGHT as u32);
+        // a condition that already held doesn't raise another interrupt
+        self.update_status_line();
+    }
+
+    // returns the interrupts to request, as bits of IF
+    pub fn step(&mut self, cycles: u8) -> u8 {
+        if self.control & LCD_ENABLE == 0 {
+            return 0;
+        }
+        self.clock += cycles as u32;
+        let mut interrupts = 0;
         match self.read_mode {
             ReadMode::HorizontalBlank => {
-                if self.clock >= 204 {
-                    self.clock = 0;
+                if self.clock >= HORIZONTAL_BLANK_CYCLES {
+                    self.clock -= HORIZONTAL_BLANK_CYCLES;
                     self.line += 1;
-                    if self.line == SCREEN_HEIGHT as u32 - 1 {
+                    if self.line == SCREEN_HEIGHT as u32 {
                         self.read_mode = ReadMode::VerticalBlank;
-                        // todo: draw buffer
+                        interrupts |= Interrupt::VBlank.bit();
                     } else {
                         self.read_mode = ReadMode::ScanlineOAM;
                     }
                 }
             }
             ReadMode::VerticalBlank => {
-                if self.clock >= 456 {
-                    self.clock = 0;
+                if self.clock >= LINE_CYCLES {
+                    self.clock -= LINE_CYCLES;
                     self.line += 1;
-                    if self.line > 153 {
+                    if self.line == LINES {
                         self.read_mode = ReadMode::ScanlineOAM;
                         self.line = 0;
+                        self.window_line = 0;
                     }
                 }
             }
             ReadMode::ScanlineOAM => {
-                if self.clock >= 80 {
-                    self.clock = 0;
+                if self.clock >= OAM_CYCLES {
+                    self.clock -= OAM_CYCLES;
                     self.read_mode = ReadMode::ScanlineVRAM;
                 }
             }
             ReadMode::ScanlineVRAM => {
-                if self.clock >= 172 {
-                    self.clock = 0;
+                if self.clock >= TRANSFER_CYCLES {
+                    self.clock -= TRANSFER_CYCLES;
                     self.read_mode = ReadMode::HorizontalBlank;
                     self.render_scan_line();
                 }
             }
         }
+        if self.update_status_line() {
+            interrupts |= Interrupt::LcdStat.bit();
+        }
+        interrupts
+    }
+
+    // true when the STAT interrupt line went from low to high
+    fn update_status_line(&mut self) -> bool {
+        let mode_interrupt = match self.read_mode {
+            ReadMode::HorizontalBlank => HORIZONTAL_BLANK_INTERRUPT,
+            ReadMode::VerticalBlank => VERTICAL_BLANK_INTERRUPT,
+            ReadMode::ScanlineOAM => OAM_INTERRUPT,
+            ReadMode::ScanlineVRAM => 0,
+        };
+        let equal = self.line == self.line_compare as u32;
+        let line = self.status & mode_interrupt != 0
+            || (equal && self.status & LINE_COMPARE_INTERRUPT != 0);
+        let rising = line && !self.status_line;
+        self.status_line = line;
+        rising
+    }
+
+    // colour number 0-3 of pixel x, y of the tile at tile_address
+    fn tile_color(&self, tile_address: usize, x: usize, y: usize) -> u8 {
+        let low = self.ram[tile_address + y * 2];
+        let high = self.ram[tile_address + y * 2 + 1];
+        let bit = 7 - x;
+        ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
     }
 
-    fn read_tiles(&mut self, set_idx: usize) {
-        let address = match set_idx {
-            0 => TILE_SET0_FIRST..=TILE_SET0_LAST,
-            1 => TILE_SET1_FIRST..=TILE_SET1_LAST,
-            _ => panic!(),
+    // colour number of pixel x, y of the 256x256 background drawn by the tile map at map
+    fn map_color(&self, map: usize, x: usize, y: usize) -> u8 {
+        let tile = self.ram[map + (y / 8) * (BACKGROUND_WIDTH / 8) + x / 8];
+        let tile_address = if self.control & UNSIGNED_TILE_DATA != 0 {
+            tile as usize * BYTES_PER_TILE
+        } else {
+            (TILE_SET0_FIRST as isize + tile as i8 as isize * BYTES_PER_TILE as isize) as usize
         };
-        self.ram[address]
-            .chunks(BYTES_PER_TILE)
-            .enumerate()
-            .for_each(|(tile_pos, chunk)| {
-                chunk
-                    .chunks(2)
-                    .flat_map(|b| GPU::tile_line_color(b[0], b[1]))
-                    .enumerate()
-                    .for_each(|(n, colors)| {
-                        self.tile_set[set_idx][tile_pos][n] = colors;
-                    });
-            });
+        self.tile_color(tile_address, x % 8, y % 8)
     }
 
     fn render_scan_line(&mut self) {
-        let addr = self.line;
-    }
-
-    fn tile_line_color(b1: u8, b2: u8) -> [Color; 8] {
-        [
-            Color::from(b1 & 0b10000000 | b2 & 0b10000000),
-            Color::from(b1 & 0b01000000 | b2 & 0b01000000),
-            Color::from(b1 & 0b00100000 | b2 & 0b00100000),
-            Color::from(b1 & 0b00010000 | b2 & 0b00010000),
-            Color::from(b1 & 0b00001000 | b2 & 0b00001000),
-            Color::from(b1 & 0b00000100 | b2 & 0b00000100),
-            Color::from(b1 & 0b00000010 | b2 & 0b00000010),
-            Color::from(b1 & 0b00000001 | b2 & 0b00000001),
-        ]
+        let y = self.line as usize;
+        // colour numbers of background and window, sprites behind them only show over 0
+        let mut background = [0; SCREEN_WIDTH];
+        let mut shades = [0; SCREEN_WIDTH];
+        if self.control & BACKGROUND_ENABLE != 0 {
+            let map = match self.control & BACKGROUND_TILE_MAP {
+                0 => TILE_MAP0_FIRST,
+                _ => TILE_MAP1_FIRST,
+            };
+            let map_y = (y + self.scroll_y as usize) % BACKGROUND_WIDTH;
+            for (x, color) in background.iter_mut().enumerate() {
+                let map_x = (x + self.scroll_x as usize) % BACKGROUND_WIDTH;
+                *color = self.map_color(map, map_x, map_y);
+            }
+            // WX is the screen column plus 7
+            let window_x = self.window_x as usize;
+            if self.control & WINDOW_ENABLE != 0
+                && y >= self.window_y as usize
+                && window_x < SCREEN_WIDTH + 7
+            {
+                let map = match self.control & WINDOW_TILE_MAP {
+                    0 => TILE_MAP0_FIRST,
+                    _ => TILE_MAP1_FIRST,
+                };
+                let window_y = self.window_line as usize;
+                let first = window_x.saturating_sub(7);
+                for (x, color) in background.iter_mut().enumerate().skip(first) {
+                    *color = self.map_color(map, x + 7 - window_x, window_y);
+                }
+                self.window_line += 1;
+            }
+            for (pixel, &color) in shades.iter_mut().zip(background.iter()) {
+                *pixel = shade(self.background_palette, color);
+            }
+        }
+        if self.control & SPRITE_ENABLE != 0 {
+            self.render_sprites(y, &background, &mut shades);
+        }
+        let row = &mut self.canvas[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+        for (pixel, &shade) in row.iter_mut().zip(shades.iter()) {
+            *pixel = Color::from(shade).into();
+        }
+    }
+
+    fn render_sprites(
+        &self,
+        y: usize,
+        background: &[u8; SCREEN_WIDTH],
+        shades: &mut [u8; SCREEN_WIDTH],
+    ) {
+        let height = match self.control & TALL_SPRITES {
+            0 => 8,
+            _ => 16,
+        };
+        // the first ten on the line in OAM order, the leftmost wins where they overlap
+        // and OAM order breaks ties
+        let mut sprites: Vec<&[u8]> = self
+            .sprite
+            .chunks(4)
+            .filter(|sprite| {
+                let top = sprite[0] as isize - 16;
+                (top..top + height).contains(&(y as isize))
+            })
+            .take(SPRITES_PER_LINE)
+            .collect();
+        sprites.sort_by_key(|sprite| sprite[1]);
+        // back to front, so the one with priority is drawn last
+        for sprite in sprites.iter().rev() {
+            let (top, left, flags) = (sprite[0] as isize - 16, sprite[1] as isize - 8, sprite[3]);
+            let mut row = (y as isize - top) as usize;
+            if flags & SPRITE_FLIP_Y != 0 {
+                row = height as usize - 1 - row;
+            }
+            // tall sprites ignore the lowest bit of the tile number
+            let tile = match height {
+                16 => sprite[2] & 0xFE,
+                _ => sprite[2],
+            };
+            let palette = self.sprite_palettes[(flags & SPRITE_PALETTE != 0) as usize];
+            for column in 0..8 {
+                let x = left + column as isize;
+                if !(0..SCREEN_WIDTH as isize).contains(&x) {
+                    continue;
+                }
+                let x = x as usize;
+                let tile_x = match flags & SPRITE_FLIP_X {
+                    0 => column,
+                    _ => 7 - column,
+                };
+                let color = self.tile_color(tile as usize * BYTES_PER_TILE, tile_x, row);
+                // colour 0 is transparent
+                if color == 0 || (flags & SPRITE_BEHIND_BACKGROUND != 0 && background[x] != 0) {
+                    continue;
+                }
+                shades[x] = shade(palette, color);
+            }
+        }
+    }
+}
+
+// canvas pixels are 0x00RRGGBB
+pub fn write_png(canvas: &[u32], path: &Path) -> Result<(), png::EncodingError> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = canvas
+        .iter()
+        .flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+        .collect();
+    encoder.write_header()?.write_image_data(&data)
+}
+
+impl SaveState for GPU {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bytes(state, &self.ram);
+        write_bytes(state, &self.sprite);
+        for pixel in self.canvas.iter() {
+            write_u32(state, *pixel);
+        }
+        write_u8(state, self.read_mode as u8);
+        write_u32(state, self.clock);
+        write_u32(state, self.line);
+        write_bytes(
+            state,
+            &[
+                self.control,
+                self.status,
+                self.scroll_x,
+                self.scroll_y,
+                self.line_compare,
+                self.background_palette,
+                self.sprite_palettes[0],
+                self.sprite_palettes[1],
+                self.window_x,
+                self.window_y,
+            ],
+        );
+        write_u32(state, self.window_line);
+        write_bool(state, self.status_line);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        read_bytes(state, &mut self.ram)?;
+        read_bytes(state, &mut self.sprite)?;
+        for pixel in self.canvas.iter_mut() {
+            *pixel = read_u32(state)?;
+        }
+        self.read_mode = ReadMode::from_bits(read_u8(state)?);
+        self.clock = read_u32(state)?;
+        self.line = read_u32(state)? % LINES;
+        let mut registers = [0; 10];
+        read_bytes(state, &mut registers)?;
+        let [control, status, scroll_x, scroll_y, line_compare, background_palette, palette0, palette1, window_x, window_y] =
+            registers;
+        self.control = control;
+        self.status = status & STATUS_INTERRUPTS;
+        self.scroll_x = scroll_x;
+        self.scroll_y = scroll_y;
+        self.line_compare = line_compare;
+        self.background_palette = background_palette;
+        self.sprite_palettes = [palette0, palette1];
+        self.window_x = window_x;
+        self.window_y = window_y;
+        self.window_line = read_u32(state)?.min(SCREEN_HEIGHT as u32);
+        self.status_line = read_bool(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u32 = 0xFFFFFF;
+    const BLACK: u32 = 0x000000;
+    const LIGHTGREY: u32 = 0xC0C0C0;
+
+    // runs whole lines until LY reaches line, collecting the interrupts requested
+    fn run_to_line(gpu: &mut GPU, line: u8) -> u8 {
+        let mut interrupts = 0;
+        while gpu.read_byte(LINE) != line {
+            interrupts |= gpu.step(4);
+        }
+        interrupts
+    }
+
+    // tile 1 is a column of colour 3 on the left, then colour 1
+    fn gpu_with_tile() -> GPU {
+        let mut gpu = GPU::new();
+        for row in 0..8 {
+            gpu.ram[BYTES_PER_TILE + row * 2] = 0xFF;
+            gpu.ram[BYTES_PER_TILE + row * 2 + 1] = 0x80;
+        }
+        gpu.write_byte(BACKGROUND_PALETTE, 0b11_10_01_00);
+        gpu.write_byte(SPRITE_PALETTE0, 0b11_10_01_00);
+        gpu
+    }
+
+    #[test]
+    fn lines_advance_and_vertical_blank_is_requested_after_the_last_visible_line() {
+        let mut gpu = GPU::new();
+        assert_eq!(gpu.step(80), 0, "a switched off LCD stands still");
+        gpu.write_byte(LCD_CONTROL, LCD_ENABLE);
+        assert_eq!(gpu.read_byte(LCD_STATUS) & 0b11, 2);
+        assert_eq!(run_to_line(&mut gpu, 143), 0);
+        assert_eq!(run_to_line(&mut gpu, 144), Interrupt::VBlank.bit());
+        assert_eq!(gpu.read_byte(LCD_STATUS) & 0b11, 1);
+        run_to_line(&mut gpu, 0);
+        assert_eq!(gpu.read_byte(LCD_STATUS) & 0b11, 2);
+    }
+
+    #[test]
+    fn line_compare_requests_the_stat_interrupt() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(LINE_COMPARE, 10);
+        gpu.write_byte(LCD_STATUS, LINE_COMPARE_INTERRUPT);
+        gpu.write_byte(LCD_CONTROL, LCD_ENABLE);
+        assert_eq!(run_to_line(&mut gpu, 9), 0);
+        assert_eq!(run_to_line(&mut gpu, 10), Interrupt::LcdStat.bit());
+        assert_ne!(gpu.read_byte(LCD_STATUS) & LINE_COMPARE_EQUAL, 0);
+        assert_eq!(run_to_line(&mut gpu, 11), 0);
+    }
+
+    #[test]
+    fn background_is_drawn_with_scrolling_and_palette() {
+        let mut gpu = gpu_with_tile();
+        gpu.ram[TILE_MAP0_FIRST] = 1;
+        gpu.write_byte(SCROLL_X, 4);
+        gpu.write_byte(
+            LCD_CONTROL,
+            LCD_ENABLE | UNSIGNED_TILE_DATA | BACKGROUND_ENABLE,
+        );
+        run_to_line(&mut gpu, 1);
+        // tile 1 fills columns 0-7 of the map, scrolled 4 to the left
+        assert_eq!(&gpu.canvas[0..4], &[LIGHTGREY; 4]);
+        assert_eq!(gpu.canvas[4], WHITE);
+        gpu.write_byte(SCROLL_X, 0);
+        run_to_line(&mut gpu, 2);
+        assert_eq!(gpu.canvas[SCREEN_WIDTH], BLACK);
+        assert_eq!(gpu.canvas[SCREEN_WIDTH + 1], LIGHTGREY);
+        assert_eq!(gpu.canvas[SCREEN_WIDTH + 8], WHITE);
+    }
+
+    #[test]
+    fn sprites_are_flipped_and_colour_0_is_transparent() {
+        let mut gpu = gpu_with_tile();
+        // at screen 0, 0 flipped horizontally
+        gpu.sprite[..4].copy_from_slice(&[16, 8, 1, SPRITE_FLIP_X]);
+        gpu.write_byte(LCD_CONTROL, LCD_ENABLE | SPRITE_ENABLE);
+        run_to_line(&mut gpu, 1);
+        assert_eq!(&gpu.canvas[0..7], &[LIGHTGREY; 7]);
+        assert_eq!(gpu.canvas[7], BLACK);
+        assert_eq!(gpu.canvas[8], WHITE);
+    }
+
+    #[test]
+    fn switching_the_lcd_off_blanks_the_screen() {
+        let mut gpu = gpu_with_tile();
+        gpu.ram[TILE_MAP0_FIRST] = 1;
+        gpu.write_byte(
+            LCD_CONTROL,
+            LCD_ENABLE | UNSIGNED_TILE_DATA | BACKGROUND_ENABLE,
+        );
+        run_to_line(&mut gpu, 5);
+        assert_eq!(gpu.canvas[0], BLACK);
+        gpu.write_byte(LCD_CONTROL, 0);
+        assert_eq!(gpu.read_byte(LINE), 0);
+        assert!(gpu.canvas.iter().all(|&pixel| pixel == WHITE));
+    }
+
+    #[test]
+    fn save_state_round_trips() {
+        let mut gpu = gpu_with_tile();
+        gpu.write_byte(LCD_CONTROL, LCD_ENABLE | BACKGROUND_ENABLE);
+        run_to_line(&mut gpu, 20);
+        let mut state = Vec::new();
+        gpu.save_state(&mut state);
+        let mut loaded = GPU::new();
+        loaded.load_state(&mut state.as_slice()).unwrap();
+        let mut again = Vec::new();
+        loaded.save_state(&mut again);
+        assert_eq!(state, again);
+        assert_eq!(loaded.read_byte(LINE), 20);
     }
 }
@@ -0,0 +1,35 @@
+pub const INTERRUPT_FLAG: u16 = 0xFF0F;
+pub const INTERRUPT_ENABLE: u16 = 0xFFFF;
+
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LcdStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self as u16
+    }
+    // lower bits have higher priority
+    pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+        INTERRUPTS
+            .iter()
+            .copied()
+            .find(|interrupt| pending & interrupt.bit() != 0)
+    }
+}
@@ -0,0 +1,169 @@
+use crate::save_state::{read_u8, write_u8, SaveState};
+use std::io;
+
+pub const JOYPAD: u16 = 0xFF00;
+
+const SELECT_DIRECTIONS: u8 = 0b0001_0000;
+const SELECT_ACTIONS: u8 = 0b0010_0000;
+const JOYPAD_UNUSED_BITS: u8 = 0b1100_0000;
+
+// in the bit order of Buttons::bits
+pub const BUTTON_NAMES: [&str; 8] = ["a", "b", "select", "start", "right", "left", "up", "down"];
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl Buttons {
+    // pressed buttons as bits, directions in the upper nibble
+    pub fn bits(&self) -> u8 {
+        [
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+            self.right,
+            self.left,
+            self.up,
+            self.down,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &pressed)| bits | (pressed as u8) << i)
+    }
+    pub fn from_bits(bits: u8) -> Self {
+        Buttons {
+            a: bits & 0x01 != 0,
+            b: bits & 0x02 != 0,
+            select: bits & 0x04 != 0,
+            start: bits & 0x08 != 0,
+            right: bits & 0x10 != 0,
+            left: bits & 0x20 != 0,
+            up: bits & 0x40 != 0,
+            down: bits & 0x80 != 0,
+        }
+    }
+    // the buttons named in BUTTON_NAMES, the first unknown name as the error
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, &'a str> {
+        let mut bits = 0;
+        for name in names {
+            let index = BUTTON_NAMES
+                .iter()
+                .position(|&button| button == name)
+                .ok_or(name)?;
+            bits |= 1 << index;
+        }
+        Ok(Buttons::from_bits(bits))
+    }
+    pub fn names(&self) -> Vec<&'static str> {
+        let bits = self.bits();
+        BUTTON_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| bits & 1 << i != 0)
+            .map(|(_, &name)| name)
+            .collect()
+    }
+}
+
+pub struct Joypad {
+    select: u8,
+    buttons: Buttons,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            buttons: Buttons::default(),
+        }
+    }
+    pub fn read_byte(&self) -> u8 {
+        // selection and buttons are active low
+        let bits = self.buttons.bits();
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= bits >> 4;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            pressed |= bits & 0x0F;
+        }
+        JOYPAD_UNUSED_BITS | self.select | (!pressed & 0x0F)
+    }
+    pub fn write_byte(&mut self, byte: u8) {
+        self.select = byte & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    }
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+    // returns true when a button got pressed and the joypad interrupt should be requested
+    pub fn set_buttons(&mut self, buttons: Buttons) -> bool {
+        let newly_pressed = buttons.bits() & !self.buttons.bits();
+        self.buttons = buttons;
+        newly_pressed != 0
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_u8(state, self.select);
+        write_u8(state, self.buttons.bits());
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.select = read_u8(state)?;
+        self.buttons = Buttons::from_bits(read_u8(state)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_read_the_selected_buttons_active_low() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(Buttons {
+            a: true,
+            down: true,
+            ..Buttons::default()
+        });
+        assert_eq!(joypad.read_byte(), 0xFF, "nothing selected");
+        joypad.write_byte(SELECT_ACTIONS);
+        assert_eq!(joypad.read_byte(), 0xE7, "directions, down");
+        joypad.write_byte(SELECT_DIRECTIONS);
+        assert_eq!(joypad.read_byte(), 0xDE, "actions, a");
+        joypad.write_byte(0);
+        assert_eq!(joypad.read_byte(), 0xC6, "both rows");
+    }
+
+    #[test]
+    fn only_a_new_press_requests_the_interrupt() {
+        let mut joypad = Joypad::new();
+        let start = Buttons {
+            start: true,
+            ..Buttons::default()
+        };
+        assert!(joypad.set_buttons(start));
+        assert!(!joypad.set_buttons(start), "held");
+        assert!(!joypad.set_buttons(Buttons::default()), "released");
+        assert!(joypad.set_buttons(Buttons {
+            left: true,
+            ..start
+        }));
+    }
+}
@@ -0,0 +1,38 @@
+pub mod apu;
+pub mod bess;
+pub mod cartridge;
+pub mod cdl;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod env;
+pub mod game_boy;
+pub mod gdb_stub;
+pub mod gpu;
+pub mod interrupt;
+pub mod joypad;
+#[cfg(feature = "libretro")]
+mod libretro;
+pub mod link_cable;
+pub mod memory_bus;
+pub mod movie;
+pub mod movie_import;
+pub mod printer;
+pub mod profiler;
+#[cfg(feature = "python")]
+mod python;
+pub mod rewind;
+pub mod rom_disassembler;
+#[cfg(all(unix, feature = "rpc"))]
+pub mod rpc;
+pub mod rtc;
+pub mod save_state;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod serial;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod watchpoint;
+
+pub use game_boy::GameBoy;
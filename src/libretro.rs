@@ -0,0 +1,486 @@
+// the core RetroArch and other libretro frontends load, built with
+// `cargo build --release --features libretro`. dmg_boot.bin has to be in the
+// frontend's system directory.
+use crate::{
+    apu::SAMPLE_RATE,
+    gpu::{CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH},
+    joypad::Buttons,
+    GameBoy,
+};
+use std::{
+    ffi::{c_char, c_uint, c_void, CStr},
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr, slice,
+    sync::{Mutex, MutexGuard},
+};
+
+const API_VERSION: c_uint = 1;
+const CLOCK_RATE: f64 = 4_194_304.0;
+// looked for in the frontend's system directory
+const BOOT_ROM: &str = "dmg_boot.bin";
+
+const ENVIRONMENT_GET_SYSTEM_DIRECTORY: c_uint = 9;
+const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const ENVIRONMENT_SET_MEMORY_MAPS: c_uint = 36 | 0x10000;
+// 0x00RRGGBB like the canvas, frames go out without converting
+const PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+const DEVICE_JOYPAD: c_uint = 1;
+const JOYPAD_B: c_uint = 0;
+const JOYPAD_SELECT: c_uint = 2;
+const JOYPAD_START: c_uint = 3;
+const JOYPAD_UP: c_uint = 4;
+const JOYPAD_DOWN: c_uint = 5;
+const JOYPAD_LEFT: c_uint = 6;
+const JOYPAD_RIGHT: c_uint = 7;
+const JOYPAD_A: c_uint = 8;
+
+const MEMORY_SAVE_RAM: c_uint = 0;
+const MEMORY_SYSTEM_RAM: c_uint = 2;
+const MEMORY_VIDEO_RAM: c_uint = 3;
+const MEMDESC_SAVE_RAM: u64 = 1 << 3;
+const MEMDESC_SYSTEM_RAM: u64 = 1 << 2;
+const MEMDESC_VIDEO_RAM: u64 = 1 << 4;
+
+const REGION_NTSC: c_uint = 0;
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct MemoryDescriptor {
+    flags: u64,
+    ptr: *mut c_void,
+    offset: usize,
+    start: usize,
+    select: usize,
+    disconnect: usize,
+    len: usize,
+    addrspace: *const c_char,
+}
+
+#[repr(C)]
+struct MemoryMap {
+    descriptors: *const MemoryDescriptor,
+    num_descriptors: c_uint,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    // boxed so the memory handed to the frontend stays put
+    game_boy: Box<GameBoy>,
+    // after a panic the last frame is shown until the next reset
+    crashed: bool,
+}
+
+// frontends drive a core from one thread, the locks only make the statics safe
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> MutexGuard<'static, Callbacks> {
+    CALLBACKS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn core() -> MutexGuard<'static, Option<Core>> {
+    CORE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(environment) => unsafe { environment(cmd, data) },
+        None => false,
+    }
+}
+
+fn system_directory() -> Option<String> {
+    let mut directory: *const c_char = ptr::null();
+    let found = environment(
+        ENVIRONMENT_GET_SYSTEM_DIRECTORY,
+        &mut directory as *mut _ as *mut c_void,
+    );
+    if !found || directory.is_null() {
+        return None;
+    }
+    let directory = unsafe { CStr::from_ptr(directory) };
+    Some(directory.to_string_lossy().into_owned())
+}
+
+fn read_buttons(input_state: InputStateFn) -> Buttons {
+    let pressed = |id| unsafe { input_state(0, DEVICE_JOYPAD, 0, id) != 0 };
+    Buttons {
+        right: pressed(JOYPAD_RIGHT),
+        left: pressed(JOYPAD_LEFT),
+        up: pressed(JOYPAD_UP),
+        down: pressed(JOYPAD_DOWN),
+        a: pressed(JOYPAD_A),
+        b: pressed(JOYPAD_B),
+        select: pressed(JOYPAD_SELECT),
+        start: pressed(JOYPAD_START),
+    }
+}
+
+// tells cheat finders and achievement tools where the RAM lives
+fn set_memory_maps(game_boy: &mut GameBoy) {
+    let bus = game_boy.cpu_mut().bus_mut();
+    let descriptor = |flags, memory: &mut [u8], start| MemoryDescriptor {
+        flags,
+        ptr: memory.as_mut_ptr() as *mut c_void,
+        offset: 0,
+        start,
+        select: 0,
+        disconnect: 0,
+        len: memory.len(),
+        addrspace: ptr::null(),
+    };
+    // cartridges without ram have no save ram to map
+    let descriptors: Vec<MemoryDescriptor> = [
+        descriptor(MEMDESC_VIDEO_RAM, bus.video_ram_mut(), 0x8000),
+        descriptor(MEMDESC_SAVE_RAM, bus.cartridge_ram_mut(), 0xA000),
+        descriptor(MEMDESC_SYSTEM_RAM, bus.working_ram_mut(), 0xC000),
+        descriptor(MEMDESC_SYSTEM_RAM, bus.high_ram_mut(), 0xFF80),
+    ]
+    .into_iter()
+    .filter(|descriptor| descriptor.len > 0)
+    .collect();
+    let mut map = MemoryMap {
+        descriptors: descriptors.as_ptr(),
+        num_descriptors: descriptors.len() as c_uint,
+    };
+    // frontends copy the descriptors during the call
+    environment(
+        ENVIRONMENT_SET_MEMORY_MAPS,
+        &mut map as *mut _ as *mut c_void,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    callbacks().environment = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    callbacks().video_refresh = Some(callback);
+}
+
+// samples only go out in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    callbacks().audio_sample_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    callbacks().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    callbacks().input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *core() = None;
+}
+
+/// # Safety
+/// `info` has to point to a SystemInfo the frontend owns.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    unsafe {
+        *info = SystemInfo {
+            library_name: c"RustBoy".as_ptr(),
+            library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            valid_extensions: c"gb".as_ptr(),
+            need_fullpath: false,
+            block_extract: false,
+        };
+    }
+}
+
+/// # Safety
+/// `info` has to point to a SystemAvInfo the frontend owns.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    unsafe {
+        *info = SystemAvInfo {
+            geometry: GameGeometry {
+                base_width: SCREEN_WIDTH as c_uint,
+                base_height: SCREEN_HEIGHT as c_uint,
+                max_width: SCREEN_WIDTH as c_uint,
+                max_height: SCREEN_HEIGHT as c_uint,
+                aspect_ratio: SCREEN_WIDTH as f32 / SCREEN_HEIGHT as f32,
+            },
+            timing: SystemTiming {
+                fps: CLOCK_RATE / CYCLES_PER_FRAME as f64,
+                sample_rate: SAMPLE_RATE as f64,
+            },
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = core().as_mut() {
+        // in place, the memory maps keep pointing at the same Game Boy
+        core.game_boy.reset();
+        core.crashed = false;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    let mut core = core();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return,
+    };
+    if let (Some(input_poll), Some(input_state)) = (callbacks.input_poll, callbacks.input_state) {
+        unsafe { input_poll() };
+        core.game_boy.set_buttons(read_buttons(input_state));
+    }
+    if !core.crashed {
+        let game_boy = &mut core.game_boy;
+        if panic::catch_unwind(AssertUnwindSafe(|| game_boy.run_frame())).is_err() {
+            eprintln!("RustBoy crashed at ${:04X}", game_boy.cpu().pc());
+            core.crashed = true;
+        }
+    }
+    let samples: Vec<i16> = core
+        .game_boy
+        .audio_samples()
+        .iter()
+        .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect();
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        // the frontend may take fewer frames than offered
+        let mut sent = 0;
+        while sent < samples.len() {
+            let frames = (samples.len() - sent) / 2;
+            let taken = unsafe { audio_sample_batch(samples[sent..].as_ptr(), frames) };
+            if taken == 0 {
+                break;
+            }
+            sent += taken * 2;
+        }
+    }
+    if let Some(video_refresh) = callbacks.video_refresh {
+        let canvas = core.game_boy.framebuffer();
+        unsafe {
+            video_refresh(
+                canvas.as_ptr() as *const c_void,
+                SCREEN_WIDTH as c_uint,
+                SCREEN_HEIGHT as c_uint,
+                SCREEN_WIDTH * 4,
+            );
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    core()
+        .as_ref()
+        .map_or(0, |core| core.game_boy.save_state().len())
+}
+
+/// # Safety
+/// `data` has to be valid for writing `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = core();
+    let state = match core.as_ref() {
+        Some(core) => core.game_boy.save_state(),
+        None => return false,
+    };
+    if state.len() > size {
+        return false;
+    }
+    unsafe { ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len()) };
+    true
+}
+
+/// # Safety
+/// `data` has to be valid for reading `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let state = unsafe { slice::from_raw_parts(data as *const u8, size) };
+    match core().as_mut() {
+        Some(core) => {
+            core.crashed = false;
+            core.game_boy.load_state(state).is_ok()
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` has to point to a GameInfo holding the rom's data.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let game = match unsafe { game.as_ref() } {
+        Some(game) if !game.data.is_null() => game,
+        _ => return false,
+    };
+    let rom = unsafe { slice::from_raw_parts(game.data as *const u8, game.size) }.to_vec();
+    let boot_rom = match system_directory()
+        .and_then(|directory| fs::read(Path::new(&directory).join(BOOT_ROM)).ok())
+    {
+        Some(boot_rom) => boot_rom,
+        None => {
+            eprintln!("RustBoy needs {} in the system directory", BOOT_ROM);
+            return false;
+        }
+    };
+    let mut format = PIXEL_FORMAT_XRGB8888;
+    if !environment(
+        ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut format as *mut _ as *mut c_void,
+    ) {
+        return false;
+    }
+    let mut game_boy = match GameBoy::new(boot_rom, &rom) {
+        Ok(game_boy) => Box::new(game_boy),
+        Err(err) => {
+            eprintln!("RustBoy can't load the game: {}", err);
+            return false;
+        }
+    };
+    set_memory_maps(&mut game_boy);
+    *core() = Some(Core {
+        game_boy,
+        crashed: false,
+    });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *core() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    let mut core = core();
+    let bus = match core.as_mut() {
+        Some(core) => core.game_boy.cpu_mut().bus_mut(),
+        None => return ptr::null_mut(),
+    };
+    match id {
+        MEMORY_SAVE_RAM if bus.cartridge_ram_mut().is_empty() => ptr::null_mut(),
+        MEMORY_SAVE_RAM => bus.cartridge_ram_mut().as_mut_ptr() as *mut c_void,
+        MEMORY_SYSTEM_RAM => bus.working_ram_mut().as_mut_ptr() as *mut c_void,
+        MEMORY_VIDEO_RAM => bus.video_ram_mut().as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    let mut core = core();
+    let bus = match core.as_mut() {
+        Some(core) => core.game_boy.cpu_mut().bus_mut(),
+        None => return 0,
+    };
+    match id {
+        MEMORY_SAVE_RAM => bus.cartridge_ram_mut().len(),
+        MEMORY_SYSTEM_RAM => bus.working_ram_mut().len(),
+        MEMORY_VIDEO_RAM => bus.video_ram_mut().len(),
+        _ => 0,
+    }
+}
@@ -0,0 +1,143 @@
+use crate::serial::SerialDevice;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+// every message is a kind byte followed by the data byte
+const MESSAGE_TRANSFER: u8 = 0x01;
+const MESSAGE_REPLY: u8 = 0x02;
+// give up on a silent partner so a closed window doesn't hang the other side
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+const UNIX_PREFIX: &str = "unix:";
+
+/// Connects two emulator instances over a local socket. The side that clocks a
+/// transfer sends its byte and blocks until the partner answers with its own SB,
+/// which stalls emulation for up to 500 ms before a silent partner reads as $FF.
+/// Addresses are `host:port` for TCP or `unix:/path` for a Unix socket.
+pub struct LinkCable {
+    writer: Box<dyn Write + Send>,
+    incoming: Receiver<[u8; 2]>,
+}
+
+impl LinkCable {
+    pub fn listen(address: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            let _ = std::fs::remove_file(path);
+            let (stream, _) = UnixListener::bind(path)?.accept()?;
+            return Ok(LinkCable::new(stream.try_clone()?, stream));
+        }
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(stream.try_clone()?, stream))
+    }
+    pub fn connect(address: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            let stream = UnixStream::connect(path)?;
+            return Ok(LinkCable::new(stream.try_clone()?, stream));
+        }
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(stream.try_clone()?, stream))
+    }
+    fn new<S: Read + Write + Send + 'static>(mut reader: S, writer: S) -> Self {
+        let (sender, incoming) = mpsc::channel();
+        // reading happens on its own thread so polling from the emulation loop stays cheap
+        thread::spawn(move || {
+            let mut message = [0; 2];
+            while reader.read_exact(&mut message).is_ok() {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        LinkCable {
+            writer: Box::new(writer),
+            incoming,
+        }
+    }
+    fn send(&mut self, kind: u8, byte: u8) {
+        // a dropped connection shows up as a closed channel on the reading side
+        let _ = self.writer.write_all(&[kind, byte]);
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.send(MESSAGE_TRANSFER, byte);
+        loop {
+            match self.incoming.recv_timeout(REPLY_TIMEOUT) {
+                Ok([MESSAGE_REPLY, received]) => return received,
+                // both sides clocked at once, neither one is listening
+                Ok([MESSAGE_TRANSFER, _]) => self.send(MESSAGE_REPLY, 0xFF),
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return 0xFF
+                }
+            }
+        }
+    }
+    fn poll(&mut self, pending: Option<u8>) -> Option<u8> {
+        match self.incoming.try_recv() {
+            Ok([MESSAGE_TRANSFER, received]) => {
+                // without a transfer armed on the external clock nothing gets shifted
+                self.send(MESSAGE_REPLY, pending.unwrap_or(0xFF));
+                pending.map(|_| received)
+            }
+            Ok(_) | Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // both ends of a loopback TCP connection
+    fn pair() -> (LinkCable, LinkCable) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connecting = thread::spawn(move || LinkCable::connect(&address).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let listening = LinkCable::new(stream.try_clone().unwrap(), stream);
+        (listening, connecting.join().unwrap())
+    }
+
+    // clocks byte out of master while slave waits for it with its own armed
+    fn exchange(
+        master: LinkCable,
+        slave: &mut LinkCable,
+        byte: u8,
+        armed: u8,
+    ) -> (LinkCable, u8, u8) {
+        let transfer = thread::spawn(move || {
+            let mut master = master;
+            let received = master.transfer(byte);
+            (master, received)
+        });
+        let slave_received = loop {
+            if let Some(received) = slave.poll(Some(armed)) {
+                break received;
+            }
+        };
+        let (master, master_received) = transfer.join().unwrap();
+        (master, master_received, slave_received)
+    }
+
+    #[test]
+    fn bytes_cross_in_both_directions() {
+        let (listening, mut connecting) = pair();
+        let (mut listening, received, sent) = exchange(listening, &mut connecting, 0x12, 0x34);
+        assert_eq!((received, sent), (0x34, 0x12));
+
+        let (_, received, sent) = exchange(connecting, &mut listening, 0x56, 0x78);
+        assert_eq!((received, sent), (0x78, 0x56));
+    }
+}
@@ -1,9 +1,34 @@
-mod cpu;
-mod gpu;
-mod memory_bus;
-use crate::{cpu::CPU, gpu::GPU, memory_bus::MemoryBus};
-use minifb::{Key, Window, WindowOptions};
-use std::{fs::OpenOptions, io::Read};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+#[cfg(all(unix, feature = "rpc"))]
+use rust_boy::rpc::RpcServer;
+#[cfg(feature = "script")]
+use rust_boy::script::Script;
+use rust_boy::{
+    bess,
+    cdl::CodeDataLog,
+    debugger::Debugger,
+    disassembler,
+    gdb_stub::GdbStub,
+    gpu::{self, SCREEN_HEIGHT, SCREEN_WIDTH},
+    joypad::Buttons,
+    link_cable::LinkCable,
+    movie::{Movie, MoviePlayer, MovieRecorder},
+    movie_import,
+    printer::GameBoyPrinter,
+    profiler::Profiler,
+    rewind::Rewind,
+    rom_disassembler::RomDisassembler,
+    serial::SerialCapture,
+    symbols::Symbols,
+    trace::Tracer,
+    GameBoy,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufWriter, Read},
+    path::Path,
+    sync::Arc,
+};
 
 fn read_buffer(path: &str) -> std::io::Result<Vec<u8>> {
     let mut file = OpenOptions::new().read(true).open(path)?;
@@ -12,20 +37,542 @@ fn read_buffer(path: &str) -> std::io::Result<Vec<u8>> {
     Ok(buf)
 }
 
+struct Options {
+    rom: Option<String>,
+    // print whatever the game sends over the serial port to stdout
+    serial: bool,
+    // wait for the other instance on this address, or connect to it
+    link_listen: Option<String>,
+    link_connect: Option<String>,
+    // directory the Game Boy Printer writes its strips to
+    printer: Option<String>,
+    // run without a window and write the last frame to this png
+    headless: Option<String>,
+    frames: Option<u32>,
+    // stop once the serial output contains this text
+    until: Option<String>,
+    // BESS state to start from, and where to write one on exit
+    load_bess: Option<String>,
+    save_bess: Option<String>,
+    // megabytes of history kept for rewinding
+    rewind_memory: usize,
+    record_movie: Option<String>,
+    play_movie: Option<String>,
+    // interactive debugger on the terminal instead of the window
+    debug: bool,
+    // wait for a gdb remote protocol client on this address
+    gdb: Option<String>,
+    // gameboy-doctor log of every instruction
+    trace: Option<String>,
+    // labels in the trace, which then no longer diffs against gameboy-doctor logs.
+    // Implied by --sym, a <rom>.sym found next to the rom alone doesn't add them
+    trace_symbols: bool,
+    // LY stuck at $90 while tracing, the way gameboy-doctor's logs were made
+    trace_doctor: bool,
+    // RGBLINK symbols, <rom>.sym when it exists otherwise
+    sym: Option<String>,
+    // cycles per routine and pc, and the collapsed stacks for flamegraphs
+    profile: Option<String>,
+    profile_stacks: Option<String>,
+    // code/data log, added to when the file exists
+    cdl: Option<String>,
+    // Rhai script driving the emulator instead of the window
+    script: Option<String>,
+    // JSON-RPC server on this unix socket instead of the window
+    rpc: Option<String>,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        rom: None,
+        serial: false,
+        link_listen: None,
+        link_connect: None,
+        printer: None,
+        headless: None,
+        frames: None,
+        until: None,
+        load_bess: None,
+        save_bess: None,
+        rewind_memory: 64,
+        record_movie: None,
+        play_movie: None,
+        debug: false,
+        gdb: None,
+        trace: None,
+        trace_symbols: false,
+        trace_doctor: false,
+        sym: None,
+        profile: None,
+        profile_stacks: None,
+        cdl: None,
+        script: None,
+        rpc: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--serial" => options.serial = true,
+            "--link-listen" => options.link_listen = args.next(),
+            "--link-connect" => options.link_connect = args.next(),
+            "--printer" => options.printer = args.next(),
+            "--headless" => options.headless = args.next(),
+            "--frames" => {
+                options.frames = args.next().map(|n| n.parse().expect("invalid frame count"))
+            }
+            "--until" => options.until = args.next(),
+            "--load-bess" => options.load_bess = args.next(),
+            "--save-bess" => options.save_bess = args.next(),
+            "--rewind-memory" => {
+                options.rewind_memory = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("invalid rewind memory")
+            }
+            "--record-movie" => options.record_movie = args.next(),
+            "--play-movie" => options.play_movie = args.next(),
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = args.next(),
+            "--trace" => options.trace = args.next(),
+            "--trace-symbols" => options.trace_symbols = true,
+            "--trace-doctor" => options.trace_doctor = true,
+            "--sym" => options.sym = args.next(),
+            "--profile" => options.profile = args.next(),
+            "--profile-stacks" => options.profile_stacks = args.next(),
+            "--cdl" => options.cdl = args.next(),
+            "--script" => options.script = args.next(),
+            "--rpc" => options.rpc = args.next(),
+            _ if arg.starts_with("--") => panic!("unknown option: {}", arg),
+            _ => options.rom = Some(arg),
+        }
+    }
+    if options.record_movie.is_some() && options.play_movie.is_some() {
+        panic!("--record-movie and --play-movie can't be combined");
+    }
+    // without a window nothing else would ever stop the run
+    if options.headless.is_some()
+        && options.frames.is_none()
+        && options.until.is_none()
+        && options.play_movie.is_none()
+    {
+        panic!("--headless needs --frames, --until or --play-movie");
+    }
+    if options.trace_doctor && options.trace.is_none() {
+        panic!("--trace-doctor needs --trace");
+    }
+    // each of them plugs its own device into the one serial port
+    let serial_devices = [
+        options.serial || options.until.is_some(),
+        options.printer.is_some(),
+        options.link_listen.is_some(),
+        options.link_connect.is_some(),
+    ];
+    if serial_devices.iter().filter(|&&used| used).count() > 1 {
+        panic!(
+            "--serial or --until, --printer, --link-listen and --link-connect can't be combined"
+        );
+    }
+    #[cfg(not(feature = "script"))]
+    if options.script.is_some() {
+        panic!("--script needs a build with the script feature");
+    }
+    #[cfg(not(all(unix, feature = "rpc")))]
+    if options.rpc.is_some() {
+        panic!("--rpc needs a unix build with the rpc feature");
+    }
+    options
+}
+
+fn read_buttons(window: &Window) -> Buttons {
+    Buttons {
+        right: window.is_key_down(Key::Right),
+        left: window.is_key_down(Key::Left),
+        up: window.is_key_down(Key::Up),
+        down: window.is_key_down(Key::Down),
+        a: window.is_key_down(Key::X),
+        b: window.is_key_down(Key::Z),
+        select: window.is_key_down(Key::Backspace),
+        start: window.is_key_down(Key::Enter),
+    }
+}
+
+const SLOT_KEYS: [Key; 9] = [
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+];
+
+// states live next to the rom as <rom>.ss1 to <rom>.ss9
+fn state_path(rom: &Option<String>, slot: usize) -> String {
+    format!("{}.ss{}", rom.as_deref().unwrap_or("rustboy"), slot)
+}
+
+// shift + number saves to that slot, the number alone loads it
+fn handle_save_states(window: &Window, game_boy: &mut GameBoy, rom: &Option<String>) {
+    let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+    for (i, key) in SLOT_KEYS.iter().enumerate() {
+        if !window.is_key_pressed(*key, KeyRepeat::No) {
+            continue;
+        }
+        let path = state_path(rom, i + 1);
+        let result = if shift {
+            fs::write(&path, game_boy.save_state())
+        } else {
+            fs::read(&path).and_then(|state| game_boy.load_state(&state))
+        };
+        match result {
+            Ok(()) if shift => println!("Saved state to {}", path),
+            Ok(()) => println!("Loaded state from {}", path),
+            Err(err) => eprintln!("Save state {} failed: {}", path, err),
+        }
+    }
+}
+
+// a snapshot every few frames, holding the rewind key walks back one per frame
+const REWIND_INTERVAL: u32 = 4;
+
+fn save_bess(game_boy: &GameBoy, path: &Option<String>) {
+    if let Some(path) = path {
+        fs::write(path, bess::save_bess(game_boy)).expect("Write BESS state failed");
+    }
+}
+
+// runs one frame, taking the input from the movie while one is playing
+fn run_frame(
+    game_boy: &mut GameBoy,
+    buttons: Buttons,
+    player: &mut Option<MoviePlayer>,
+    recorder: &mut Option<MovieRecorder>,
+) {
+    if let Some(player) = player {
+        if player.run_frame(game_boy) {
+            return;
+        }
+    }
+    match recorder {
+        Some(recorder) => recorder.run_frame(game_boy, buttons),
+        None => {
+            game_boy.set_buttons(buttons);
+            game_boy.run_frame();
+        }
+    }
+}
+
+// false when the replay didn't match the recording
+fn report_movie(player: &MoviePlayer) -> bool {
+    match player.desync() {
+        Some(frame) => {
+            eprintln!("Movie desynced at frame {}", frame);
+            false
+        }
+        None => {
+            println!(
+                "Movie replayed {} frames, all frame hashes match",
+                player.frame()
+            );
+            true
+        }
+    }
+}
+
+// BizHawk and VBA movies are told apart by their extension
+fn load_movie(path: &str) -> std::io::Result<Movie> {
+    let movie = read_buffer(path)?;
+    match Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("bk2") => movie_import::load_bk2(&movie),
+        Some("vbm") => movie_import::load_vbm(&movie),
+        _ => Movie::load(&movie),
+    }
+}
+
+fn save_movie(recorder: Option<MovieRecorder>, path: &Option<String>) {
+    if let (Some(recorder), Some(path)) = (recorder, path) {
+        fs::write(path, recorder.finish().save()).expect("Write movie failed");
+    }
+}
+
+// rust-boy disasm ROM [ADDR] [COUNT]: a listing straight from the rom file
+fn disassemble_rom(args: &[String]) {
+    let path = args.first().expect("usage: disasm ROM [ADDR] [COUNT]");
+    let rom = read_buffer(path).expect("Open rom failed");
+    let mut address = args.get(1).map_or(0x100, |address| {
+        let digits = address.trim_start_matches('$').trim_start_matches("0x");
+        u16::from_str_radix(digits, 16).expect("invalid address")
+    });
+    let count = args
+        .get(2)
+        .map_or(32, |count| count.parse().expect("invalid count"));
+    let read = |address: u16| rom.get(address as usize).copied().unwrap_or(0xFF);
+    for _ in 0..count {
+        let disassembly = disassembler::disassemble(address, read);
+        let bytes: Vec<String> = disassembly
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        println!("${:04X}  {:<9} {}", address, bytes.join(" "), disassembly);
+        address = disassembly.next_address();
+    }
+}
+
+// rust-boy disasm-rom ROM [OUT]: RGBDS source of the whole rom, to stdout without OUT,
+// guided by <rom>.cdl when there is one
+fn export_rgbds(args: &[String]) {
+    let path = args.first().expect("usage: disasm-rom ROM [OUT]");
+    let rom = read_buffer(path).expect("Open rom failed");
+    let log = fs::read(Path::new(path).with_extension("cdl"))
+        .ok()
+        .map(|log| CodeDataLog::load(&log, rom.len()).expect("Load code/data log failed"));
+    let disassembler = match &log {
+        Some(log) => RomDisassembler::with_code_data_log(&rom, log),
+        None => RomDisassembler::new(&rom),
+    };
+    match args.get(1) {
+        Some(out) => {
+            let mut file = fs::File::create(out).expect("Create source file failed");
+            disassembler.write(&mut file)
+        }
+        None => disassembler.write(&mut std::io::stdout().lock()),
+    }
+    .expect("Write source failed");
+}
+
+fn load_symbols(options: &Options) -> Symbols {
+    let path = match (&options.sym, &options.rom) {
+        (Some(path), _) => path.clone(),
+        (None, Some(rom)) => Path::new(rom)
+            .with_extension("sym")
+            .to_string_lossy()
+            .into_owned(),
+        (None, None) => return Symbols::new(),
+    };
+    match fs::read_to_string(&path) {
+        Ok(text) => match Symbols::parse(&text) {
+            Ok(symbols) => symbols,
+            Err(err) if options.sym.is_some() => panic!("Load symbols failed: {}", err),
+            // a .sym that happens to sit next to the rom shouldn't stop it from running
+            Err(err) => {
+                eprintln!("Ignoring symbols in {}: {}", path, err);
+                Symbols::new()
+            }
+        },
+        Err(_) if options.sym.is_none() => Symbols::new(),
+        Err(err) => panic!("Open symbols failed: {}", err),
+    }
+}
+
+fn write_profile(game_boy: &mut GameBoy, options: &Options, symbols: &Symbols) {
+    let profiler = match game_boy.take_profiler() {
+        Some(profiler) => profiler,
+        None => return,
+    };
+    if let Some(path) = &options.profile {
+        let file = fs::File::create(path).expect("Create profile failed");
+        profiler
+            .write_flat(symbols, &mut BufWriter::new(file))
+            .expect("Write profile failed");
+    }
+    if let Some(path) = &options.profile_stacks {
+        let file = fs::File::create(path).expect("Create profile failed");
+        profiler
+            .write_collapsed(symbols, &mut BufWriter::new(file))
+            .expect("Write profile failed");
+    }
+}
+
+fn save_code_data_log(game_boy: &mut GameBoy, path: &Option<String>) {
+    if let (Some(log), Some(path)) = (game_boy.take_code_data_log(), path) {
+        fs::write(path, log.save()).expect("Write code/data log failed");
+    }
+}
+
+// writes out whatever the options asked for once the emulator stops, whichever
+// frontend ran it
+fn finish(
+    game_boy: &mut GameBoy,
+    options: &Options,
+    symbols: &Symbols,
+    recorder: Option<MovieRecorder>,
+) {
+    save_bess(game_boy, &options.save_bess);
+    write_profile(game_boy, options, symbols);
+    save_code_data_log(game_boy, &options.cdl);
+    save_movie(recorder, &options.record_movie);
+    // exiting skips the drop that would flush the trace
+    game_boy.set_tracer(None);
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("disasm") => return disassemble_rom(&args[1..]),
+        Some("disasm-rom") => return export_rgbds(&args[1..]),
+        _ => {}
+    }
+    let options = parse_args();
     let boot_rom = read_buffer("dmg_boot.bin").expect("Open boot_rom failed");
-    let mut gpu = GPU::new();
-    let mut memory_bus = MemoryBus::new(boot_rom, &mut gpu.ram, &mut gpu.sprite);
-    let mut cpu = CPU::new(&mut memory_bus);
+    let rom = match &options.rom {
+        Some(path) => read_buffer(path).expect("Open rom failed"),
+        None => Vec::new(),
+    };
+    let mut game_boy = GameBoy::new(boot_rom.clone(), &rom).expect("Load rom failed");
+    let mut serial_output = None;
+    if options.serial || options.until.is_some() {
+        let capture = SerialCapture::new(options.serial);
+        serial_output = Some(capture.output());
+        game_boy.connect_serial(Box::new(capture));
+    }
+    if let Some(dir) = &options.printer {
+        game_boy.connect_serial(Box::new(GameBoyPrinter::new(dir.into())));
+    }
+    if let Some(address) = &options.link_listen {
+        let link = LinkCable::listen(address).expect("Link cable listen failed");
+        game_boy.connect_serial(Box::new(link));
+    } else if let Some(address) = &options.link_connect {
+        let link = LinkCable::connect(address).expect("Link cable connect failed");
+        game_boy.connect_serial(Box::new(link));
+    }
+    let symbols = Arc::new(load_symbols(&options));
+    if let Some(path) = &options.trace {
+        let file = fs::File::create(path).expect("Create trace file failed");
+        let mut tracer = Tracer::new(Box::new(file));
+        if options.trace_symbols || options.sym.is_some() {
+            tracer.set_symbols(symbols.clone());
+        }
+        tracer.set_doctor_mode(options.trace_doctor);
+        game_boy.set_tracer(Some(tracer));
+    }
+    if let Some(path) = &options.cdl {
+        let log = match fs::read(path) {
+            Ok(log) => CodeDataLog::load(&log, rom.len()).expect("Load code/data log failed"),
+            Err(_) => CodeDataLog::new(rom.len()),
+        };
+        game_boy.set_code_data_log(Some(log));
+    }
+    if options.profile.is_some() || options.profile_stacks.is_some() {
+        game_boy.set_profiler(Some(Profiler::new()));
+    }
+    if let Some(path) = &options.load_bess {
+        let state = read_buffer(path).expect("Open BESS state failed");
+        bess::load_bess(&mut game_boy, &state).expect("Load BESS state failed");
+    }
+    let mut player = options.play_movie.as_ref().map(|path| {
+        MoviePlayer::new(load_movie(path).expect("Open movie failed"), &mut game_boy)
+            .expect("Load movie start state failed")
+    });
+    let mut recorder = options
+        .record_movie
+        .as_ref()
+        .map(|_| MovieRecorder::new(&game_boy, options.load_bess.is_none()));
+
+    if options.debug {
+        let stdin = std::io::stdin();
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols.clone());
+        debugger
+            .run(&mut game_boy, stdin.lock(), &mut std::io::stdout())
+            .expect("Debugger failed");
+        finish(&mut game_boy, &options, &symbols, recorder);
+        return;
+    }
+
+    if let Some(address) = &options.gdb {
+        println!("Waiting for gdb on {}", address);
+        GdbStub::listen(address)
+            .and_then(|mut stub| stub.run(&mut game_boy))
+            .expect("GDB stub failed");
+        finish(&mut game_boy, &options, &symbols, recorder);
+        return;
+    }
+
+    #[cfg(all(unix, feature = "rpc"))]
+    if let Some(path) = &options.rpc {
+        println!("Serving JSON-RPC on {}", path);
+        RpcServer::bind(path, boot_rom)
+            .and_then(|mut server| server.run(&mut game_boy))
+            .expect("RPC server failed");
+        finish(&mut game_boy, &options, &symbols, recorder);
+        return;
+    }
+
+    #[cfg(feature = "script")]
+    if let Some(path) = &options.script {
+        let source = fs::read_to_string(path).expect("Open script failed");
+        let script = Script::new(game_boy, symbols.clone());
+        let result = script.run(&source);
+        let mut game_boy = script.finish();
+        finish(&mut game_boy, &options, &symbols, recorder);
+        if let Err(err) = result {
+            eprintln!("Script {} failed: {}", path, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = &options.headless {
+        let finished = || match (&options.until, &serial_output) {
+            (Some(text), Some(output)) => output.lock().unwrap().contains(text.as_str()),
+            _ => false,
+        };
+        let mut frames = 0;
+        while options.frames.is_none_or(|n| frames < n)
+            && !finished()
+            && !player.as_ref().is_some_and(|player| player.is_finished())
+        {
+            run_frame(
+                &mut game_boy,
+                Buttons::default(),
+                &mut player,
+                &mut recorder,
+            );
+            frames += 1;
+        }
+        gpu::write_png(game_boy.framebuffer(), Path::new(path)).expect("Write screenshot failed");
+        finish(&mut game_boy, &options, &symbols, recorder);
+        if player.is_some_and(|player| !report_movie(&player)) {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    const WIDTH: usize = 160;
-    const HEIGHT: usize = 144;
-    let mut window = Window::new("RustBoy", WIDTH, HEIGHT, WindowOptions::default()).unwrap();
+    let mut window = Window::new(
+        "RustBoy",
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
+        WindowOptions::default(),
+    )
+    .unwrap();
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+    let mut rewind = Rewind::new(REWIND_INTERVAL, options.rewind_memory << 20);
+    // jumping around in time would break the movie
+    let movie_active = player.is_some() || recorder.is_some();
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        cpu.step();
+        if !movie_active {
+            handle_save_states(&window, &mut game_boy, &options.rom);
+        }
+        if !movie_active && window.is_key_down(Key::R) {
+            rewind.step_back(&mut game_boy);
+        } else {
+            let buttons = read_buttons(&window);
+            run_frame(&mut game_boy, buttons, &mut player, &mut recorder);
+            rewind.record(&game_boy);
+        }
+        // the replay is over, carry on with the keyboard
+        if player.as_ref().is_some_and(|player| player.is_finished()) {
+            report_movie(&player.take().unwrap());
+        }
         window
-            .update_with_buffer(&gpu.canvas, WIDTH, HEIGHT)
+            .update_with_buffer(game_boy.framebuffer(), SCREEN_WIDTH, SCREEN_HEIGHT)
             .unwrap();
     }
+    finish(&mut game_boy, &options, &symbols, recorder);
 }
@@ -1,13 +1,25 @@
-use crate::{gpu, gpu::GPU};
+use crate::{
+    apu::{APU, APU_FIRST, APU_LAST},
+    cartridge::Cartridge,
+    cdl::{self, CodeDataLog},
+    cpu::instruction::Instruction,
+    gpu::{self, DMA, GPU, LCD_CONTROL, WINDOW_X},
+    interrupt::{Interrupt, INTERRUPT_ENABLE, INTERRUPT_FLAG},
+    joypad::{Buttons, Joypad, JOYPAD},
+    save_state::{read_bool, read_bytes, write_bool, write_bytes, SaveState},
+    serial::{Serial, SerialDevice, SERIAL_CONTROL, SERIAL_DATA},
+    timer::{Timer, DIVIDER, TIMER_CONTROL, TIMER_COUNTER},
+    watchpoint::{Access, Watchpoints},
+};
 use static_assertions::const_assert;
+use std::{cell::RefCell, io};
 
 const BOOT_ROM_FIRST: usize = 0x0;
 const BOOT_ROM_LAST: usize = 0xFF;
 const BOOT_ROM_SIZE: usize = BOOT_ROM_LAST - BOOT_ROM_FIRST + 1;
 
-const CARTRIDGE_ROM_FIRST: usize = 0x100;
+const CARTRIDGE_ROM_FIRST: usize = 0x0;
 const CARTRIDGE_ROM_LAST: usize = 0x7FFF;
-const CARTRIDGE_ROM_SIZE: usize = CARTRIDGE_ROM_LAST - CARTRIDGE_ROM_FIRST + 1;
 
 const GRAPHICS_RAM_FIRST: usize = 0x8000;
 const GRAPHICS_RAM_LAST: usize = 0x9FFF;
@@ -15,103 +27,406 @@ const_assert!(gpu::RAM_SIZE == GRAPHICS_RAM_LAST - GRAPHICS_RAM_FIRST + 1);
 
 const CARTRIDGE_RAM_FIRST: usize = 0xA000;
 const CARTRIDGE_RAM_LAST: usize = 0xBFFF;
-const CARTRIDGE_RAM_SIZE: usize = CARTRIDGE_RAM_LAST - CARTRIDGE_RAM_FIRST + 1;
 
 const WORKING_RAM_FIRST: usize = 0xC000;
 const WORKING_RAM_LAST: usize = 0xFDFF;
 const WORKING_RAM_SIZE: usize = WORKING_RAM_LAST - WORKING_RAM_FIRST + 1;
+const ECHO_RAM_FIRST: usize = 0xE000;
 
 const GRAPHICS_SPRITE_FIRST: usize = 0xFE00;
 const GRAPHICS_SPRITE_LAST: usize = 0xFE9F;
 const_assert!(gpu::SPRITE_SIZE == GRAPHICS_SPRITE_LAST - GRAPHICS_SPRITE_FIRST + 1);
 
+// prohibited, reads see 0 and writes are dropped
+const UNUSABLE_FIRST: usize = 0xFEA0;
+const UNUSABLE_LAST: usize = 0xFEFF;
+
 const IO_FIRST: usize = 0xFF00;
 const IO_LAST: usize = 0xFF7F;
-const IO_SIZE: usize = IO_LAST - IO_FIRST + 1;
+pub const IO_SIZE: usize = IO_LAST - IO_FIRST + 1;
 
 const ZERO_PAGE_FIRST: usize = 0xFF80;
 const ZERO_PAGE_LAST: usize = 0xFFFF;
 const ZERO_PAGE_SIZE: usize = ZERO_PAGE_LAST - ZERO_PAGE_FIRST + 1;
 
-pub struct MemoryBus<'a> {
+// writing any non-zero value unmaps the boot rom until the next reset
+const BOOT_ROM_DISABLE: u16 = 0xFF50;
+
+pub struct MemoryBus {
     boot_rom: [u8; BOOT_ROM_SIZE],
-    cartridge_rom: [u8; CARTRIDGE_ROM_SIZE],
+    boot_rom_enabled: bool,
+    cartridge: Cartridge,
     memory: [u8; WORKING_RAM_SIZE],
-    cartridge_ram: [u8; CARTRIDGE_RAM_SIZE],
-    gpu_ram: &'a mut [u8; gpu::RAM_SIZE],
-    gpu_sprite: &'a mut [u8; gpu::SPRITE_SIZE],
+    gpu: GPU,
+    io: [u8; IO_SIZE],
+    zero_page: [u8; ZERO_PAGE_SIZE],
+    serial: Serial,
+    timer: Timer,
+    apu: APU,
+    joypad: Joypad,
+    // reads only borrow the bus but still have to report hits
+    watchpoints: RefCell<Watchpoints>,
+    code_data_log: RefCell<Option<CodeDataLog>>,
+    // what LY reads instead of the current line, for gameboy-doctor
+    stubbed_line: Option<u8>,
 }
 
-impl<'a> MemoryBus<'a> {
-    pub fn new(
-        boot_rom: Vec<u8>,
-        vram: &'a mut [u8; gpu::RAM_SIZE],
-        gpu_sprite: &'a mut [u8; gpu::SPRITE_SIZE],
-    ) -> Self {
+impl MemoryBus {
+    pub fn new(boot_rom: Vec<u8>) -> Self {
         MemoryBus {
             boot_rom: boot_rom.try_into().unwrap(),
-            cartridge_rom: [0; CARTRIDGE_ROM_SIZE],
+            boot_rom_enabled: true,
+            cartridge: Cartridge::default(),
             memory: [0; WORKING_RAM_SIZE],
-            cartridge_ram: [0; CARTRIDGE_RAM_SIZE],
-            gpu_ram: vram,
-            gpu_sprite,
+            gpu: GPU::new(),
+            io: [0; IO_SIZE],
+            zero_page: [0; ZERO_PAGE_SIZE],
+            serial: Serial::new(),
+            timer: Timer::new(),
+            apu: APU::new(),
+            joypad: Joypad::new(),
+            watchpoints: RefCell::new(Watchpoints::new()),
+            code_data_log: RefCell::new(None),
+            stubbed_line: None,
+        }
+    }
+    pub fn watchpoints(&mut self) -> &mut Watchpoints {
+        self.watchpoints.get_mut()
+    }
+    pub fn set_code_data_log(&mut self, log: Option<CodeDataLog>) {
+        *self.code_data_log.get_mut() = log;
+    }
+    pub fn take_code_data_log(&mut self) -> Option<CodeDataLog> {
+        self.code_data_log.get_mut().take()
+    }
+    pub fn stub_line(&mut self, line: Option<u8>) {
+        self.stubbed_line = line;
+    }
+    // called by the CPU before it fetches the instruction at pc, false when an
+    // execute watchpoint paused so the instruction doesn't run yet
+    pub fn begin_instruction(&mut self, pc: u16) -> bool {
+        let opcode = self.peek_byte(pc);
+        if self.watchpoints.get_mut().check_execute(pc, opcode) {
+            return false;
+        }
+        if self.code_data_log.get_mut().is_some() {
+            let size = match opcode {
+                0xCB => 2,
+                _ => Instruction::size(opcode, false) as u16,
+            };
+            self.log_rom(pc, cdl::OPCODE);
+            for i in 1..size {
+                self.log_rom(pc.wrapping_add(i), cdl::OPERAND);
+            }
+        }
+        true
+    }
+    // marks the rom file byte behind address in the current bank, the boot rom isn't logged
+    fn log_rom(&self, address: u16, flag: u8) {
+        let in_boot_rom = address as usize <= BOOT_ROM_LAST && self.boot_rom_enabled;
+        if address as usize > CARTRIDGE_ROM_LAST || in_boot_rom {
+            return;
+        }
+        if let Some(log) = self.code_data_log.borrow_mut().as_mut() {
+            log.mark(self.cartridge.rom_offset(address), flag);
+        }
+    }
+    pub fn gpu(&self) -> &GPU {
+        &self.gpu
+    }
+    // the memory behind C000-DFFF, A000-BFFF, 8000-9FFF and FF80-FFFE, for
+    // frontends that read and write it in place
+    pub fn working_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[..ECHO_RAM_FIRST - WORKING_RAM_FIRST]
+    }
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+    // empty when the cartridge header declares no ram
+    pub fn cartridge_ram_mut(&mut self) -> &mut [u8] {
+        self.cartridge.ram_mut()
+    }
+    pub fn video_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.gpu.ram
+    }
+    pub fn high_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.zero_page[..ZERO_PAGE_SIZE - 1]
+    }
+    pub fn buttons(&self) -> Buttons {
+        self.joypad.buttons()
+    }
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        if self.joypad.set_buttons(buttons) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+    pub fn boot_rom_enabled(&self) -> bool {
+        self.boot_rom_enabled
+    }
+    // fails for mappers that aren't emulated and roms too big for theirs
+    pub fn load_cartridge(&mut self, rom: &[u8]) -> io::Result<()> {
+        self.cartridge = Cartridge::new(rom)?;
+        Ok(())
+    }
+    // back to power on with the same cartridge, keeping its ram, the serial device
+    // and the debugging aids
+    pub fn reset(&mut self) {
+        let boot_rom = self.boot_rom.to_vec();
+        let mut bus = MemoryBus::new(boot_rom);
+        bus.serial.connect(self.serial.disconnect());
+        std::mem::swap(&mut bus.cartridge, &mut self.cartridge);
+        std::mem::swap(&mut bus.watchpoints, &mut self.watchpoints);
+        std::mem::swap(&mut bus.code_data_log, &mut self.code_data_log);
+        bus.stubbed_line = self.stubbed_line;
+        bus.cartridge.reset();
+        *self = bus;
+    }
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.connect(device);
+    }
+    pub fn step(&mut self, cycles: u8) {
+        self.cartridge.step(cycles);
+        self.io[INTERRUPT_FLAG as usize - IO_FIRST] |= self.gpu.step(cycles);
+        if self.timer.step(cycles) {
+            self.request_interrupt(Interrupt::Timer);
+        }
+        if self.serial.step(cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+        self.apu.step(cycles);
+    }
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.io[INTERRUPT_FLAG as usize - IO_FIRST] |= interrupt.bit();
+    }
+    pub fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        self.io[INTERRUPT_FLAG as usize - IO_FIRST] &= !interrupt.bit();
+    }
+    // interrupts that are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.peek_byte(INTERRUPT_FLAG) & self.peek_byte(INTERRUPT_ENABLE) & 0x1F
+    }
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            JOYPAD => self.joypad.read_byte(),
+            SERIAL_DATA | SERIAL_CONTROL => self.serial.read_byte(address),
+            DIVIDER..=TIMER_CONTROL => self.timer.read_byte(address),
+            APU_FIRST..=APU_LAST => self.apu.read_byte(address),
+            DMA => self.io[address as usize - IO_FIRST],
+            gpu::LINE => self
+                .stubbed_line
+                .unwrap_or_else(|| self.gpu.read_byte(address)),
+            LCD_CONTROL..=WINDOW_X => self.gpu.read_byte(address),
+            // upper 3 bits of IF are unused and always read 1
+            INTERRUPT_FLAG => self.io[address as usize - IO_FIRST] | 0xE0,
+            _ => self.io[address as usize - IO_FIRST],
+        }
+    }
+    fn write_io(&mut self, address: u16, byte: u8) {
+        match address {
+            JOYPAD => self.joypad.write_byte(byte),
+            SERIAL_DATA | SERIAL_CONTROL => self.serial.write_byte(address, byte),
+            DIVIDER..=TIMER_CONTROL => self.timer.write_byte(address, byte),
+            APU_FIRST..=APU_LAST => self.apu.write_byte(address, byte),
+            DMA => {
+                self.io[address as usize - IO_FIRST] = byte;
+                self.dma(byte);
+            }
+            LCD_CONTROL..=WINDOW_X => self.gpu.write_byte(address, byte),
+            BOOT_ROM_DISABLE if byte != 0 => self.boot_rom_enabled = false,
+            _ => self.io[address as usize - IO_FIRST] = byte,
+        }
+    }
+    // copies the 160 bytes at XX00 into the sprite table, at once rather than over 160 cycles
+    fn dma(&mut self, source: u8) {
+        let first = (source as u16) << 8;
+        for i in 0..gpu::SPRITE_SIZE {
+            self.gpu.sprite[i] = self.peek_byte(first + i as u16);
+        }
+    }
+    // sets the I/O registers from a snapshot taken by another emulator. The bytes are
+    // stored as they are, without the side effects a write from the CPU would have
+    // (resetting DIV, starting a transfer or a DMA, switching the LCD), and the
+    // devices then work out their internal state from them
+    pub fn restore_io_registers(&mut self, registers: &[u8; IO_SIZE]) {
+        let register = |address: u16| registers[address as usize - IO_FIRST];
+        for (i, &byte) in registers.iter().enumerate() {
+            let address = (IO_FIRST + i) as u16;
+            match address {
+                JOYPAD => self.joypad.write_byte(byte),
+                TIMER_COUNTER..=TIMER_CONTROL => self.timer.write_byte(address, byte),
+                DIVIDER => self.timer.restore_divider(byte),
+                BOOT_ROM_DISABLE => self.boot_rom_enabled = byte == 0,
+                // restored as a whole below
+                SERIAL_DATA | SERIAL_CONTROL | APU_FIRST..=APU_LAST => {}
+                LCD_CONTROL..=WINDOW_X if address != DMA => {}
+                _ => self.io[i] = byte,
+            }
         }
+        self.serial
+            .restore_registers(register(SERIAL_DATA), register(SERIAL_CONTROL));
+        let apu = (APU_FIRST as usize - IO_FIRST)..=(APU_LAST as usize - IO_FIRST);
+        self.apu.restore_registers(&registers[apu]);
+        let gpu = (LCD_CONTROL as usize - IO_FIRST)..=(WINDOW_X as usize - IO_FIRST);
+        self.gpu.restore_registers(&registers[gpu]);
     }
+    // a data access of the CPU, seen by watchpoints
     pub fn read_byte(&self, address: u16) -> u8 {
+        let byte = self.peek_byte(address);
+        self.watchpoints
+            .borrow_mut()
+            .check(Access::Read, address, byte);
+        self.log_rom(address, cdl::DATA);
+        byte
+    }
+    // reads without being noticed, for instruction fetches and tools looking at memory
+    pub fn peek_byte(&self, address: u16) -> u8 {
         let address = address as usize;
         match address {
-            BOOT_ROM_FIRST..=BOOT_ROM_LAST => self.boot_rom[address],
-            CARTRIDGE_ROM_FIRST..=CARTRIDGE_ROM_LAST => {
-                self.cartridge_rom[address - CARTRIDGE_ROM_FIRST]
-            }
-            GRAPHICS_RAM_FIRST..=GRAPHICS_RAM_LAST => self.gpu_ram[address - GRAPHICS_RAM_FIRST],
-            CARTRIDGE_RAM_FIRST..=CARTRIDGE_RAM_LAST => panic!(),
+            BOOT_ROM_FIRST..=BOOT_ROM_LAST if self.boot_rom_enabled => self.boot_rom[address],
+            CARTRIDGE_ROM_FIRST..=CARTRIDGE_ROM_LAST => self.cartridge.read_rom(address as u16),
+            GRAPHICS_RAM_FIRST..=GRAPHICS_RAM_LAST => self.gpu.ram[address - GRAPHICS_RAM_FIRST],
+            CARTRIDGE_RAM_FIRST..=CARTRIDGE_RAM_LAST => self.cartridge.read_ram(address as u16),
             WORKING_RAM_FIRST..=WORKING_RAM_LAST => self.memory[address - WORKING_RAM_FIRST],
             GRAPHICS_SPRITE_FIRST..=GRAPHICS_SPRITE_LAST => {
-                self.gpu_sprite[address - GRAPHICS_SPRITE_FIRST]
+                self.gpu.sprite[address - GRAPHICS_SPRITE_FIRST]
             }
-            IO_FIRST..=IO_LAST => panic!(),
-            ZERO_PAGE_FIRST..=ZERO_PAGE_LAST => panic!(),
+            UNUSABLE_FIRST..=UNUSABLE_LAST => 0,
+            IO_FIRST..=IO_LAST => self.read_io(address as u16),
+            ZERO_PAGE_FIRST..=ZERO_PAGE_LAST => self.zero_page[address - ZERO_PAGE_FIRST],
             _ => panic!("invalid read address: {}", address),
         }
     }
     pub fn write_byte(&mut self, address: u16, byte: u8) {
+        self.watchpoints
+            .get_mut()
+            .check(Access::Write, address, byte);
         let address = address as usize;
         match address {
+            // the rom can't be written, the bank controller takes the byte instead
+            CARTRIDGE_ROM_FIRST..=CARTRIDGE_ROM_LAST => {
+                self.cartridge.write_rom(address as u16, byte)
+            }
             GRAPHICS_RAM_FIRST..=GRAPHICS_RAM_LAST => {
-                self.gpu_ram[address - GRAPHICS_RAM_FIRST] = byte
+                self.gpu.ram[address - GRAPHICS_RAM_FIRST] = byte
+            }
+            CARTRIDGE_RAM_FIRST..=CARTRIDGE_RAM_LAST => {
+                self.cartridge.write_ram(address as u16, byte)
             }
-            CARTRIDGE_RAM_FIRST..=CARTRIDGE_RAM_LAST => panic!(),
             WORKING_RAM_FIRST..=WORKING_RAM_LAST => self.memory[address - WORKING_RAM_FIRST] = byte,
             GRAPHICS_SPRITE_FIRST..=GRAPHICS_SPRITE_LAST => {
-                self.gpu_sprite[address - GRAPHICS_SPRITE_FIRST] = byte
+                self.gpu.sprite[address - GRAPHICS_SPRITE_FIRST] = byte
             }
-            IO_FIRST..=IO_LAST => panic!(),
-            ZERO_PAGE_FIRST..=ZERO_PAGE_LAST => panic!(),
+            UNUSABLE_FIRST..=UNUSABLE_LAST => {}
+            IO_FIRST..=IO_LAST => self.write_io(address as u16, byte),
+            ZERO_PAGE_FIRST..=ZERO_PAGE_LAST => self.zero_page[address - ZERO_PAGE_FIRST] = byte,
             _ => panic!("invalid write address: {}", address),
         }
     }
     pub fn write_word(&mut self, address: u16, word: u16) {
-        let address = address as usize;
-        let lsb = (word & 0xFF) as u8;
-        let msb = ((word & 0xFF00) >> 8) as u8;
-        match address {
-            GRAPHICS_RAM_FIRST..=GRAPHICS_RAM_LAST => {
-                self.gpu_ram[address - GRAPHICS_RAM_FIRST] = lsb;
-                self.gpu_ram[address - GRAPHICS_RAM_FIRST + 1] = msb;
-            }
-            CARTRIDGE_RAM_FIRST..=CARTRIDGE_RAM_LAST => panic!(),
-            WORKING_RAM_FIRST..=WORKING_RAM_LAST => {
-                self.memory[address - WORKING_RAM_FIRST] = lsb;
-                self.memory[address - WORKING_RAM_FIRST + 1] = msb;
-            }
-            GRAPHICS_SPRITE_FIRST..=GRAPHICS_SPRITE_LAST => {
-                self.gpu_sprite[address - GRAPHICS_SPRITE_FIRST] = lsb;
-                self.gpu_sprite[address - GRAPHICS_SPRITE_FIRST + 1] = msb;
-            }
-            IO_FIRST..=IO_LAST => panic!(),
-            ZERO_PAGE_FIRST..=ZERO_PAGE_LAST => panic!(),
-            _ => panic!("invalid write address: {}", address),
+        let [lsb, msb] = word.to_le_bytes();
+        self.write_byte(address, lsb);
+        // LD (FFFF),SP puts the high byte on 0000 like the 16 bit address bus does,
+        // where the bank controller takes it instead of the rom
+        let next = match address {
+            0xFFFF => CARTRIDGE_ROM_FIRST as u16,
+            _ => address + 1,
         };
+        self.write_byte(next, msb);
+    }
+}
+
+impl SaveState for MemoryBus {
+    // roms are loaded from their files and aren't part of the state
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bool(state, self.boot_rom_enabled);
+        write_bytes(state, &self.memory);
+        self.cartridge.save_state(state);
+        write_bytes(state, &self.io);
+        write_bytes(state, &self.zero_page);
+        self.gpu.save_state(state);
+        self.serial.save_state(state);
+        self.timer.save_state(state);
+        self.apu.save_state(state);
+        self.joypad.save_state(state);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.boot_rom_enabled = read_bool(state)?;
+        read_bytes(state, &mut self.memory)?;
+        self.cartridge.load_state(state)?;
+        read_bytes(state, &mut self.io)?;
+        read_bytes(state, &mut self.zero_page)?;
+        self.gpu.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.joypad.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::SerialCapture;
+
+    #[test]
+    fn write_word_at_ffff_wraps_to_the_bank_controller() {
+        let mut rom = vec![0; 0x8000];
+        // MBC1 with 8 KB of ram
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut bus = MemoryBus::new(vec![0; BOOT_ROM_SIZE]);
+        bus.load_cartridge(&rom).unwrap();
+        bus.write_word(0xFFFF, 0x0A1F);
+        assert_eq!(bus.peek_byte(INTERRUPT_ENABLE), 0x1F);
+        // 0A at 0000 enabled the cartridge ram
+        bus.write_byte(0xA000, 0x42);
+        assert_eq!(bus.peek_byte(0xA000), 0x42);
+        assert_eq!(bus.peek_byte(0x0000), 0);
+    }
+
+    #[test]
+    fn restoring_io_registers_has_no_side_effects() {
+        let mut bus = MemoryBus::new(vec![0; BOOT_ROM_SIZE]);
+        let capture = SerialCapture::new(false);
+        let output = capture.output();
+        bus.connect_serial(Box::new(capture));
+        bus.write_byte(0xC000, 0x11);
+        bus.write_byte(0xFE00, 0x22);
+        let mut registers = [0; IO_SIZE];
+        let mut set = |address: u16, byte: u8| registers[address as usize - IO_FIRST] = byte;
+        set(SERIAL_DATA, b'X');
+        set(SERIAL_CONTROL, 0x81);
+        set(DIVIDER, 0xAB);
+        set(DMA, 0xC0);
+        set(LCD_CONTROL, 0x91);
+        // LY 50 while drawing
+        set(gpu::LCD_STATUS, 0x03);
+        set(gpu::LINE, 0x50);
+        set(BOOT_ROM_DISABLE, 1);
+        bus.restore_io_registers(&registers);
+
+        assert!(output.lock().unwrap().is_empty(), "no transfer started");
+        assert_eq!(bus.peek_byte(DIVIDER), 0xAB);
+        assert_eq!(bus.peek_byte(DMA), 0xC0);
+        assert_eq!(
+            bus.peek_byte(0xFE00),
+            0x22,
+            "no DMA copied over the sprites"
+        );
+        assert_eq!(bus.peek_byte(gpu::LINE), 0x50);
+        assert_eq!(bus.peek_byte(gpu::LCD_STATUS) & 0b11, 3);
+        assert!(!bus.boot_rom_enabled());
+        // the transfer in flight still completes
+        for _ in 0..8 {
+            bus.step(128);
+        }
+        assert_eq!(bus.peek_byte(SERIAL_DATA), 0xFF);
+        assert_eq!(bus.peek_byte(SERIAL_CONTROL) & 0x80, 0);
     }
 }
@@ -0,0 +1,256 @@
+use crate::{
+    joypad::Buttons,
+    save_state::{read_bytes, read_u16, read_u32, read_u8, write_bytes, write_u16, write_u32},
+    GameBoy,
+};
+use std::io::{self, Error, ErrorKind};
+
+const MAGIC: &[u8; 8] = b"RBMOVIE\0";
+// 2 hashes the whole machine instead of the screen
+const VERSION: u16 = 2;
+
+const START_POWER_ON: u8 = 0;
+const START_SAVE_STATE: u8 = 1;
+
+// FNV-1a, stable across platforms and compiler versions unlike std's hasher
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+// hashes the save state rather than the screen, a run can go off track in memory
+// long before anything shows, or while the screen is blank
+pub fn state_hash(game_boy: &GameBoy) -> u64 {
+    game_boy
+        .save_state()
+        .iter()
+        .fold(FNV_OFFSET, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+pub enum MovieStart {
+    // a freshly created GameBoy
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+#[derive(Clone, Copy)]
+pub struct MovieFrame {
+    pub buttons: Buttons,
+    // hash of the machine state once the frame ran, None for imported movies
+    pub hash: Option<u64>,
+}
+
+/// Joypad input of every frame from a known starting point, replaying it
+/// reproduces the recorded run exactly.
+pub struct Movie {
+    pub start: MovieStart,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn save(&self) -> Vec<u8> {
+        let mut movie = Vec::new();
+        write_bytes(&mut movie, MAGIC);
+        write_u16(&mut movie, VERSION);
+        match &self.start {
+            MovieStart::PowerOn => movie.push(START_POWER_ON),
+            MovieStart::SaveState(state) => {
+                movie.push(START_SAVE_STATE);
+                write_u32(&mut movie, state.len() as u32);
+                write_bytes(&mut movie, state);
+            }
+        }
+        write_u32(&mut movie, self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            movie.push(frame.buttons.bits());
+            match frame.hash {
+                Some(hash) => {
+                    movie.push(1);
+                    write_bytes(&mut movie, &hash.to_le_bytes());
+                }
+                None => movie.push(0),
+            }
+        }
+        movie
+    }
+
+    pub fn load(mut movie: &[u8]) -> io::Result<Movie> {
+        let movie = &mut movie;
+        let mut magic = [0; 8];
+        read_bytes(movie, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a movie"));
+        }
+        let version = read_u16(movie)?;
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported movie version {}", version),
+            ));
+        }
+        let start = match read_u8(movie)? {
+            START_POWER_ON => MovieStart::PowerOn,
+            START_SAVE_STATE => {
+                // checked before allocating, the length comes from the file
+                let size = read_u32(movie)? as usize;
+                if size > movie.len() {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "movie truncated"));
+                }
+                let mut state = vec![0; size];
+                read_bytes(movie, &mut state)?;
+                MovieStart::SaveState(state)
+            }
+            start => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown movie start {}", start),
+                ))
+            }
+        };
+        let count = read_u32(movie)? as usize;
+        let mut frames = Vec::with_capacity(count.min(movie.len()));
+        for _ in 0..count {
+            let buttons = Buttons::from_bits(read_u8(movie)?);
+            let hash = if read_u8(movie)? != 0 {
+                let mut hash = [0; 8];
+                read_bytes(movie, &mut hash)?;
+                Some(u64::from_le_bytes(hash))
+            } else {
+                None
+            };
+            frames.push(MovieFrame { buttons, hash });
+        }
+        Ok(Movie { start, frames })
+    }
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    // starts from the current state of the machine, or from power on when it was just created
+    pub fn new(game_boy: &GameBoy, power_on: bool) -> Self {
+        let start = if power_on {
+            MovieStart::PowerOn
+        } else {
+            MovieStart::SaveState(game_boy.save_state())
+        };
+        MovieRecorder {
+            movie: Movie {
+                start,
+                frames: Vec::new(),
+            },
+        }
+    }
+    pub fn run_frame(&mut self, game_boy: &mut GameBoy, buttons: Buttons) {
+        game_boy.set_buttons(buttons);
+        game_boy.run_frame();
+        self.movie.frames.push(MovieFrame {
+            buttons,
+            hash: Some(state_hash(game_boy)),
+        });
+    }
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    desync: Option<usize>,
+}
+
+impl MoviePlayer {
+    // a movie starting at power on expects a freshly created GameBoy
+    pub fn new(movie: Movie, game_boy: &mut GameBoy) -> io::Result<Self> {
+        if let MovieStart::SaveState(state) = &movie.start {
+            game_boy.load_state(state)?;
+        }
+        Ok(MoviePlayer {
+            movie,
+            frame: 0,
+            desync: None,
+        })
+    }
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+    // first frame whose hash didn't match the recording
+    pub fn desync(&self) -> Option<usize> {
+        self.desync
+    }
+    // runs the next frame of the movie, false once it's over
+    pub fn run_frame(&mut self, game_boy: &mut GameBoy) -> bool {
+        let frame = match self.movie.frames.get(self.frame) {
+            Some(frame) => *frame,
+            None => return false,
+        };
+        game_boy.set_buttons(frame.buttons);
+        game_boy.run_frame();
+        if self.desync.is_none() && frame.hash.is_some_and(|hash| hash != state_hash(game_boy)) {
+            self.desync = Some(self.frame);
+        }
+        self.frame += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+
+    fn record(game_boy: &mut GameBoy, power_on: bool) -> Movie {
+        let mut recorder = MovieRecorder::new(game_boy, power_on);
+        for frame in 0..4u8 {
+            recorder.run_frame(game_boy, Buttons::from_bits(frame));
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn saved_movies_load_and_replay() {
+        let mut game_boy = counter_game_boy();
+        game_boy.run_frame();
+        let movie = record(&mut game_boy, false);
+        let loaded = Movie::load(&movie.save()).unwrap();
+        assert_eq!(loaded.frames.len(), 4);
+        assert_eq!(loaded.frames[3].buttons.bits(), 3);
+
+        let mut player = MoviePlayer::new(loaded, &mut game_boy).unwrap();
+        while player.run_frame(&mut game_boy) {}
+        assert!(player.is_finished());
+        assert_eq!(player.desync(), None);
+    }
+
+    #[test]
+    fn changes_off_screen_are_desyncs() {
+        let mut game_boy = counter_game_boy();
+        let movie = record(&mut game_boy, true);
+        let mut game_boy = self::counter_game_boy();
+        let mut player = MoviePlayer::new(movie, &mut game_boy).unwrap();
+        player.run_frame(&mut game_boy);
+        // the screen is off, only the counter differs
+        game_boy.cpu_mut().bus_mut().write_byte(0xC001, 1);
+        player.run_frame(&mut game_boy);
+        assert_eq!(player.desync(), Some(1));
+    }
+
+    #[test]
+    fn truncated_or_oversized_movies_are_refused() {
+        let mut game_boy = counter_game_boy();
+        let movie = record(&mut game_boy, false).save();
+        assert!(Movie::load(&movie[..movie.len() - 1]).is_err());
+        // a state length far past the end of the file
+        let mut oversized = movie[..11].to_vec();
+        oversized.extend_from_slice(&u32::MAX.to_le_bytes());
+        let err = Movie::load(&oversized).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(Movie::load(b"RBMOVIE\0\x01\x00").is_err(), "old version");
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{
+    joypad::Buttons,
+    movie::{Movie, MovieFrame, MovieStart},
+    save_state::{read_bytes, read_u32},
+};
+use std::io::{self, Cursor, Error, ErrorKind, Read};
+
+// Movies from other emulators carry no frame hashes, they're replayed from
+// power on and only their input is taken over. Frames are counted from the
+// first one after power on, emulators that skip the boot rom start later.
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+const BK2_HEADER: &str = "Header.txt";
+const BK2_INPUT_LOG: &str = "Input Log.txt";
+
+/// BizHawk movie, a zip archive with the input log of the Gameboy core as
+/// one line per frame with a column for every button.
+pub fn load_bk2(data: &[u8]) -> io::Result<Movie> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let mut header = String::new();
+    archive.by_name(BK2_HEADER)?.read_to_string(&mut header)?;
+    for line in header.lines() {
+        match line.split_once(' ') {
+            Some(("Platform", platform)) if platform.trim() != "GB" => {
+                return Err(invalid(format!(
+                    "BK2: platform {} isn't supported",
+                    platform
+                )))
+            }
+            Some(("StartsFromSavestate", value)) if value.trim() == "True" => {
+                return Err(invalid(
+                    "BK2: movies starting from a save state aren't supported".into(),
+                ))
+            }
+            Some(("StartsFromSaveRam", value)) if value.trim() == "True" => {
+                return Err(invalid(
+                    "BK2: movies starting from save ram aren't supported".into(),
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    let mut log = String::new();
+    archive.by_name(BK2_INPUT_LOG)?.read_to_string(&mut log)?;
+    // LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|, one character per button follows
+    let mut columns = Vec::new();
+    let mut frames = Vec::new();
+    for line in log.lines() {
+        if let Some(key) = line.strip_prefix("LogKey:") {
+            columns = key
+                .split(['#', '|'])
+                .filter(|name| !name.is_empty())
+                .map(|name| name.trim_start_matches("P1 ").to_string())
+                .collect();
+            continue;
+        }
+        if !line.starts_with('|') {
+            continue;
+        }
+        let mut buttons = Buttons::default();
+        let inputs = line.chars().filter(|&c| c != '|');
+        for (column, input) in columns.iter().zip(inputs) {
+            let pressed = input != '.' && input != ' ';
+            match column.as_str() {
+                "Up" => buttons.up = pressed,
+                "Down" => buttons.down = pressed,
+                "Left" => buttons.left = pressed,
+                "Right" => buttons.right = pressed,
+                "Start" => buttons.start = pressed,
+                "Select" => buttons.select = pressed,
+                "B" => buttons.b = pressed,
+                "A" => buttons.a = pressed,
+                "Power" if pressed => {
+                    return Err(invalid(format!(
+                        "BK2: power cycle at frame {} isn't supported",
+                        frames.len()
+                    )))
+                }
+                _ => {}
+            }
+        }
+        frames.push(MovieFrame {
+            buttons,
+            hash: None,
+        });
+    }
+    Ok(Movie {
+        start: MovieStart::PowerOn,
+        frames,
+    })
+}
+
+const VBM_SIGNATURE: &[u8; 4] = b"VBM\x1A";
+const VBM_VERSION: u32 = 1;
+const VBM_START_FLAGS: usize = 0x14;
+const VBM_CONTROLLER_FLAGS: usize = 0x15;
+const VBM_SYSTEM_FLAGS: usize = 0x16;
+const VBM_INPUT_OFFSET: usize = 0x3C;
+const VBM_HEADER_SIZE: usize = 0x40;
+// bits 8 and up are the GBA shoulder buttons, resets and motion sensors
+const VBM_RESET: u16 = 0b0000_1100_0000_0000;
+
+/// VisualBoyAdvance movie, a fixed header followed by two bytes of input
+/// per frame and controller. The low byte matches our button bits.
+pub fn load_vbm(data: &[u8]) -> io::Result<Movie> {
+    if data.len() < VBM_HEADER_SIZE || &data[..4] != VBM_SIGNATURE {
+        return Err(invalid("VBM: not a VBA movie".into()));
+    }
+    let mut header = &data[4..];
+    if read_u32(&mut header)? != VBM_VERSION {
+        return Err(invalid("VBM: unsupported version".into()));
+    }
+    read_u32(&mut header)?;
+    let count = read_u32(&mut header)? as usize;
+    if data[VBM_START_FLAGS] & 0b11 != 0 {
+        return Err(invalid(
+            "VBM: movies starting from a save state or sram aren't supported".into(),
+        ));
+    }
+    if data[VBM_SYSTEM_FLAGS] & 0b111 != 0 {
+        return Err(invalid(
+            "VBM: only original Game Boy movies are supported".into(),
+        ));
+    }
+    let controllers = (data[VBM_CONTROLLER_FLAGS] & 0x0F).count_ones().max(1) as usize;
+    let mut offset = &data[VBM_INPUT_OFFSET..];
+    let mut input = data
+        .get(read_u32(&mut offset)? as usize..)
+        .ok_or_else(|| invalid("VBM: input data out of range".into()))?;
+
+    let mut frames = Vec::with_capacity(count.min(input.len() / 2));
+    for frame in 0..count {
+        // only the first controller drives a Game Boy
+        let mut bytes = vec![0; controllers * 2];
+        read_bytes(&mut input, &mut bytes)?;
+        let bits = u16::from_le_bytes([bytes[0], bytes[1]]);
+        if bits & VBM_RESET != 0 {
+            return Err(invalid(format!(
+                "VBM: reset at frame {} isn't supported",
+                frame
+            )));
+        }
+        frames.push(MovieFrame {
+            buttons: Buttons::from_bits(bits as u8),
+            hash: None,
+        });
+    }
+    Ok(Movie {
+        start: MovieStart::PowerOn,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn bk2(header: &str, log: &str) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, text) in [(BK2_HEADER, header), (BK2_INPUT_LOG, log)] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    const LOG_KEY: &str =
+        "LogKey:#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|P1 Power|\n";
+
+    #[test]
+    fn bk2_columns_map_to_buttons() {
+        let log = format!("[Input]\n{}|U..R...A.|\n|.........|\n[/Input]\n", LOG_KEY);
+        let movie = load_bk2(&bk2("Platform GB\n", &log)).unwrap();
+        assert!(matches!(movie.start, MovieStart::PowerOn));
+        assert_eq!(movie.frames.len(), 2);
+        let buttons = movie.frames[0].buttons;
+        assert!(buttons.up && buttons.right && buttons.a);
+        assert!(!buttons.down && !buttons.b && !buttons.start);
+        assert_eq!(movie.frames[1].buttons.bits(), Buttons::default().bits());
+        assert!(movie.frames[0].hash.is_none());
+    }
+
+    #[test]
+    fn bk2_refuses_what_it_cant_replay() {
+        let log = format!("{}|.........|\n", LOG_KEY);
+        assert!(load_bk2(&bk2("Platform NES\n", &log)).is_err());
+        assert!(load_bk2(&bk2("StartsFromSavestate True\n", &log)).is_err());
+        let power = format!("{}|.........|\n|........P|\n", LOG_KEY);
+        let err = load_bk2(&bk2("Platform GB\n", &power)).err().unwrap();
+        assert!(err.to_string().contains("frame 1"));
+        assert!(load_bk2(b"not a zip").is_err());
+    }
+
+    fn vbm(inputs: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; VBM_HEADER_SIZE];
+        data[..4].copy_from_slice(VBM_SIGNATURE);
+        data[4..8].copy_from_slice(&VBM_VERSION.to_le_bytes());
+        data[12..16].copy_from_slice(&(inputs.len() as u32).to_le_bytes());
+        data[VBM_CONTROLLER_FLAGS] = 0b1;
+        data[VBM_INPUT_OFFSET..].copy_from_slice(&(VBM_HEADER_SIZE as u32).to_le_bytes());
+        for input in inputs {
+            data.extend_from_slice(&input.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn vbm_input_is_read_per_frame() {
+        let movie = load_vbm(&vbm(&[0x01, 0x88, 0x00])).unwrap();
+        let bits: Vec<u8> = movie
+            .frames
+            .iter()
+            .map(|frame| frame.buttons.bits())
+            .collect();
+        assert_eq!(bits, [0x01, 0x88, 0x00]);
+
+        // a second controller doubles the bytes per frame, only the first counts
+        let mut data = vbm(&[0x02, 0xFF, 0x04, 0xFF]);
+        data[VBM_CONTROLLER_FLAGS] = 0b11;
+        data[12..16].copy_from_slice(&2u32.to_le_bytes());
+        let bits: Vec<u8> = load_vbm(&data)
+            .unwrap()
+            .frames
+            .iter()
+            .map(|frame| frame.buttons.bits())
+            .collect();
+        assert_eq!(bits, [0x02, 0x04]);
+    }
+
+    #[test]
+    fn vbm_refuses_bad_or_unsupported_movies() {
+        let data = vbm(&[0x01, 0x02]);
+        assert!(
+            load_vbm(&data[..data.len() - 1]).is_err(),
+            "truncated input"
+        );
+        assert!(load_vbm(&data[..VBM_HEADER_SIZE - 1]).is_err());
+        assert!(load_vbm(&vbm(&[VBM_RESET])).is_err());
+
+        let mut from_state = data.clone();
+        from_state[VBM_START_FLAGS] = 0b01;
+        assert!(load_vbm(&from_state).is_err());
+        let mut color = data.clone();
+        color[VBM_SYSTEM_FLAGS] = 0b010;
+        assert!(load_vbm(&color).is_err());
+        let mut offset = data;
+        offset[VBM_INPUT_OFFSET..VBM_INPUT_OFFSET + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(load_vbm(&offset).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use crate::serial::SerialDevice;
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_IMAGE_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED: u8 = 0b0000_1000;
+
+const TILES_PER_ROW: usize = 20;
+const BYTES_PER_TILE: usize = 16;
+const PAGE_WIDTH: usize = TILES_PER_ROW * 8;
+// one data packet holds two rows of tiles, the printer buffers up to nine of them
+const DATA_PACKET_SIZE: usize = TILES_PER_ROW * BYTES_PER_TILE * 2;
+const BUFFER_SIZE: usize = DATA_PACKET_SIZE * 9;
+// margins are given in line feeds, rendered as a blank tile row each
+const MARGIN_LINE_HEIGHT: usize = 8;
+// status inquiries answered as busy after a print, games wait for this to clear
+const PRINT_BUSY_INQUIRIES: u8 = 4;
+const DEFAULT_PALETTE: u8 = 0b11_10_01_00;
+
+enum PacketState {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer on the serial port. Every finished strip of paper
+/// (a print whose bottom margin isn't zero) is written to
+/// `print_NNN.png` in the output directory.
+pub struct GameBoyPrinter {
+    output_dir: PathBuf,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_inquiries: u8,
+    buffer: Vec<u8>,
+    // grayscale pixels of the strip currently coming out of the printer
+    page: Vec<u8>,
+    pages_written: usize,
+}
+
+impl GameBoyPrinter {
+    pub fn new(output_dir: PathBuf) -> Self {
+        GameBoyPrinter {
+            output_dir,
+            state: PacketState::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy_inquiries: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            pages_written: 0,
+        }
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_inquiries = 0;
+            }
+            // an empty data packet only marks the end of the image
+            COMMAND_DATA if !self.data.is_empty() => {
+                let data = if self.compressed {
+                    GameBoyPrinter::decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                self.buffer.extend(data);
+                self.buffer.truncate(BUFFER_SIZE);
+                self.status |= STATUS_UNPROCESSED;
+                if self.buffer.len() >= BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            COMMAND_PRINT if self.data.len() >= 4 => {
+                let margins = self.data[1];
+                let palette = match self.data[2] {
+                    0 => DEFAULT_PALETTE,
+                    palette => palette,
+                };
+                self.print(margins >> 4, margins & 0x0F, palette);
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.status |= STATUS_PRINTING;
+                self.busy_inquiries = PRINT_BUSY_INQUIRIES;
+            }
+            COMMAND_STATUS if self.busy_inquiries > 0 => {
+                self.busy_inquiries -= 1;
+                if self.busy_inquiries == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // RLE: a control byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+    // otherwise the next n + 1 bytes are copied as they are
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let control = data[i] as usize;
+            i += 1;
+            if control & 0x80 != 0 {
+                if let Some(&byte) = data.get(i) {
+                    output.extend(std::iter::repeat_n(byte, (control & 0x7F) + 2));
+                }
+                i += 1;
+            } else {
+                let end = (i + control + 1).min(data.len());
+                output.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+        }
+        output
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        self.feed(margin_before);
+        let rows = self.buffer.len() / (TILES_PER_ROW * BYTES_PER_TILE);
+        for y in 0..rows * 8 {
+            for x in 0..PAGE_WIDTH {
+                let tile = (y / 8) * TILES_PER_ROW + x / 8;
+                let offset = tile * BYTES_PER_TILE + (y % 8) * 2;
+                let bit = 7 - x % 8;
+                let low = (self.buffer[offset] >> bit) & 1;
+                let high = (self.buffer[offset + 1] >> bit) & 1;
+                let shade = (palette >> ((high << 1 | low) * 2)) & 0b11;
+                self.page.push(GameBoyPrinter::gray(shade));
+            }
+        }
+        self.buffer.clear();
+        // a print without bottom margin continues on the same strip
+        if margin_after > 0 {
+            self.feed(margin_after);
+            self.write_page();
+        }
+    }
+
+    fn feed(&mut self, lines: u8) {
+        let pixels = lines as usize * MARGIN_LINE_HEIGHT * PAGE_WIDTH;
+        self.page
+            .extend(std::iter::repeat_n(GameBoyPrinter::gray(0), pixels));
+    }
+
+    fn gray(shade: u8) -> u8 {
+        255 - shade * 85
+    }
+
+    fn write_page(&mut self) {
+        let page = std::mem::take(&mut self.page);
+        if page.is_empty() {
+            return;
+        }
+        let path = self
+            .output_dir
+            .join(format!("print_{:03}.png", self.pages_written));
+        self.pages_written += 1;
+        let height = (page.len() / PAGE_WIDTH) as u32;
+        let result = File::create(&path)
+            .map_err(png::EncodingError::from)
+            .and_then(|file| {
+                let mut encoder =
+                    png::Encoder::new(BufWriter::new(file), PAGE_WIDTH as u32, height);
+                encoder.set_color(png::ColorType::Grayscale);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.write_header()?.write_image_data(&page)
+            });
+        if let Err(err) = result {
+            eprintln!("Writing {} failed: {}", path.display(), err);
+        }
+    }
+}
+
+impl SerialDevice for GameBoyPrinter {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            PacketState::Magic(i) if byte == MAGIC[i] => {
+                if i + 1 == MAGIC.len() {
+                    PacketState::Command
+                } else {
+                    PacketState::Magic(i + 1)
+                }
+            }
+            PacketState::Magic(_) => PacketState::Magic(if byte == MAGIC[0] { 1 } else { 0 }),
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.process_packet();
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                response = ALIVE;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                response = self.status;
+                PacketState::Magic(0)
+            }
+        };
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    // sends a whole packet and returns the alive and status bytes
+    fn send(printer: &mut GameBoyPrinter, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        send_with_checksum(printer, command, compressed, data, None)
+    }
+
+    fn send_with_checksum(
+        printer: &mut GameBoyPrinter,
+        command: u8,
+        compressed: bool,
+        data: &[u8],
+        checksum: Option<u16>,
+    ) -> (u8, u8) {
+        let mut body = vec![command, compressed as u8];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let sum = body
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        body.extend_from_slice(&checksum.unwrap_or(sum).to_le_bytes());
+        for byte in MAGIC.iter().chain(&body) {
+            assert_eq!(printer.transfer(*byte), 0);
+        }
+        (printer.transfer(0), printer.transfer(0))
+    }
+
+    #[test]
+    fn rle_repeats_and_copies_runs() {
+        assert_eq!(
+            GameBoyPrinter::decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34, 0x80, 0x00]),
+            [0xAA, 0xAA, 0xAA, 0x12, 0x34, 0x00, 0x00]
+        );
+        // runs cut short by the end of the packet
+        assert_eq!(GameBoyPrinter::decompress(&[0x03, 0x01]), [0x01]);
+        assert!(GameBoyPrinter::decompress(&[0x85]).is_empty());
+    }
+
+    #[test]
+    fn packets_are_checked_and_answered() {
+        let mut printer = GameBoyPrinter::new(env::temp_dir());
+        assert_eq!(send(&mut printer, COMMAND_INIT, false, &[]), (ALIVE, 0));
+        let (_, status) =
+            send_with_checksum(&mut printer, COMMAND_DATA, false, &[1, 2, 3], Some(0));
+        assert_eq!(status, STATUS_CHECKSUM_ERROR);
+        assert!(printer.buffer.is_empty());
+
+        // a stray byte before the magic is skipped
+        printer.transfer(0x88);
+        let (_, status) = send(&mut printer, COMMAND_DATA, true, &[0xFF, 0x55]);
+        assert_eq!(status, STATUS_UNPROCESSED);
+        assert_eq!(printer.buffer, [0x55; 0x81]);
+    }
+
+    #[test]
+    fn prints_strips_with_margins() {
+        let dir = env::temp_dir().join(format!("rust_boy_printer_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut printer = GameBoyPrinter::new(dir.clone());
+        send(&mut printer, COMMAND_INIT, false, &[]);
+        send(&mut printer, COMMAND_DATA, false, &[0xFF; DATA_PACKET_SIZE]);
+        send(&mut printer, COMMAND_DATA, false, &[]);
+        // no bottom margin, the strip continues
+        let (_, status) = send(&mut printer, COMMAND_PRINT, false, &[1, 0x10, 0, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(printer.page.len(), (1 + 2) * 8 * PAGE_WIDTH);
+        assert_eq!(printer.page[0], 255);
+        assert_eq!(printer.page[8 * PAGE_WIDTH], 0);
+        for _ in 0..PRINT_BUSY_INQUIRIES {
+            send(&mut printer, COMMAND_STATUS, false, &[]);
+        }
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[]).1, 0);
+
+        send(&mut printer, COMMAND_DATA, false, &[0x00; DATA_PACKET_SIZE]);
+        send(&mut printer, COMMAND_PRINT, false, &[1, 0x01, 0, 0x40]);
+        assert!(printer.page.is_empty());
+        let decoder = png::Decoder::new(File::open(dir.join("print_000.png")).unwrap());
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!(
+            (info.width, info.height),
+            (PAGE_WIDTH as u32, (1 + 2 + 2 + 1) * 8)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{cpu::call_stack::CallStack, symbols::Symbols};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+// stands in for the routine before the first call
+const ROOT: &str = "[root]";
+
+/// Cycles spent per PC and per routine on the CPU's shadow call stack, written
+/// out as a flat profile or as collapsed stacks for flamegraph tools.
+#[derive(Default)]
+pub struct Profiler {
+    by_pc: HashMap<u16, u64>,
+    // routine entry addresses from the outermost call in
+    by_stack: HashMap<Vec<u16>, u64>,
+    total: u64,
+    stack: Vec<u16>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+    // cycles of the instruction at pc, run by the routine on top of the stack
+    pub fn record(&mut self, pc: u16, cycles: u8, call_stack: &CallStack) {
+        let cycles = cycles as u64;
+        *self.by_pc.entry(pc).or_default() += cycles;
+        self.stack.clear();
+        self.stack
+            .extend(call_stack.frames().iter().map(|frame| frame.routine));
+        // runs for every instruction, only a new stack allocates
+        match self.by_stack.get_mut(self.stack.as_slice()) {
+            Some(stack_cycles) => *stack_cycles += cycles,
+            None => {
+                self.by_stack.insert(self.stack.clone(), cycles);
+            }
+        }
+        self.total += cycles;
+    }
+
+    fn name(routine: Option<u16>, symbols: &Symbols) -> String {
+        match routine {
+            Some(address) => symbols
+                .describe(address)
+                .unwrap_or_else(|| format!("${:04X}", address)),
+            None => ROOT.to_string(),
+        }
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        cycles as f64 * 100.0 / self.total.max(1) as f64
+    }
+
+    // routines by the cycles spent in them alone and with what they called,
+    // then every PC that ran
+    pub fn write_flat(&self, symbols: &Symbols, output: &mut impl Write) -> io::Result<()> {
+        let mut routines: HashMap<Option<u16>, (u64, u64)> = HashMap::new();
+        for (stack, &cycles) in self.by_stack.iter() {
+            routines.entry(stack.last().copied()).or_default().0 += cycles;
+            routines.entry(None).or_default().1 += cycles;
+            let mut seen = Vec::new();
+            for routine in stack {
+                // recursion counts once
+                if !seen.contains(routine) {
+                    seen.push(*routine);
+                    routines.entry(Some(*routine)).or_default().1 += cycles;
+                }
+            }
+        }
+        let mut routines: Vec<_> = routines.into_iter().collect();
+        routines.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(&b.0)));
+        writeln!(
+            output,
+            "{:>12} {:>7} {:>12} {:>7}  routine",
+            "self", "%", "total", "%"
+        )?;
+        for (routine, (self_cycles, total_cycles)) in routines {
+            writeln!(
+                output,
+                "{:>12} {:>6.2}% {:>12} {:>6.2}%  {}",
+                self_cycles,
+                self.percent(self_cycles),
+                total_cycles,
+                self.percent(total_cycles),
+                Profiler::name(routine, symbols)
+            )?;
+        }
+        let mut pcs: Vec<_> = self.by_pc.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(output, "\n{:>12} {:>7}  pc", "cycles", "%")?;
+        for (&pc, &cycles) in pcs {
+            writeln!(
+                output,
+                "{:>12} {:>6.2}%  {}",
+                cycles,
+                self.percent(cycles),
+                symbols.format(pc)
+            )?;
+        }
+        Ok(())
+    }
+
+    // `[root];Main;Main.draw 1234` a line per stack, what flamegraph.pl and inferno read
+    pub fn write_collapsed(&self, symbols: &Symbols, output: &mut impl Write) -> io::Result<()> {
+        let mut stacks: Vec<(String, u64)> = self
+            .by_stack
+            .iter()
+            .map(|(stack, &cycles)| {
+                let mut names = vec![ROOT.to_string()];
+                names.extend(
+                    stack
+                        .iter()
+                        .map(|&routine| Profiler::name(Some(routine), symbols)),
+                );
+                (names.join(";"), cycles)
+            })
+            .collect();
+        stacks.sort();
+        for (stack, cycles) in stacks {
+            writeln!(output, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 cycles at the root, 20 in Main, 30 in Main's call to $0300
+    fn profiler() -> Profiler {
+        let mut profiler = Profiler::new();
+        let mut call_stack = CallStack::new();
+        profiler.record(0x0100, 10, &call_stack);
+        call_stack.enter(0x0200, 0x0103);
+        profiler.record(0x0200, 20, &call_stack);
+        call_stack.enter(0x0300, 0x0203);
+        profiler.record(0x0300, 12, &call_stack);
+        profiler.record(0x0301, 18, &call_stack);
+        profiler
+    }
+
+    fn symbols() -> Symbols {
+        Symbols::parse("00:0200 Main\n").unwrap()
+    }
+
+    #[test]
+    fn collapsed_stacks_name_every_routine() {
+        let mut output = Vec::new();
+        profiler().write_collapsed(&symbols(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[root] 10\n[root];Main 20\n[root];Main;Main+256 30\n"
+        );
+    }
+
+    #[test]
+    fn the_flat_profile_adds_callees_to_the_total() {
+        let mut output = Vec::new();
+        profiler().write_flat(&symbols(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[1],
+            "          30  50.00%           30  50.00%  Main+256"
+        );
+        assert_eq!(lines[2], "          20  33.33%           50  83.33%  Main");
+        assert_eq!(
+            lines[3],
+            "          10  16.67%           60 100.00%  [root]"
+        );
+        assert_eq!(lines[6], "          20  33.33%  00:0200 Main");
+        assert_eq!(lines[7], "          18  30.00%  00:0301 Main+257");
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    gpu::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    joypad::Buttons,
+};
+use pyo3::{
+    exceptions::{PyBufferError, PyIOError, PyValueError},
+    ffi,
+    prelude::*,
+    types::PyBytes,
+};
+use std::{
+    ffi::{c_int, c_void, CStr},
+    fs,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+const CHANNELS: usize = 3;
+// unsigned bytes in the struct module's notation
+const FORMAT: &CStr = c"B";
+
+fn io_error(path: &str, err: std::io::Error) -> PyErr {
+    PyIOError::new_err(format!("{}: {}", path, err))
+}
+
+/// A copy of the screen as height x width x RGB bytes, exposed through the
+/// buffer protocol so `numpy.asarray(frame)` has the right shape without a
+/// copy. It stays the same when the emulator moves on.
+#[pyclass(frozen, module = "rust_boy")]
+struct Frame {
+    pixels: Vec<u8>,
+    shape: [ffi::Py_ssize_t; 3],
+    strides: [ffi::Py_ssize_t; 3],
+}
+
+impl Frame {
+    fn new(framebuffer: &[u32]) -> Self {
+        let pixels = framebuffer
+            .iter()
+            .flat_map(|pixel| {
+                let [_, red, green, blue] = pixel.to_be_bytes();
+                [red, green, blue]
+            })
+            .collect();
+        let row = (SCREEN_WIDTH * CHANNELS) as ffi::Py_ssize_t;
+        Frame {
+            pixels,
+            shape: [
+                SCREEN_HEIGHT as ffi::Py_ssize_t,
+                SCREEN_WIDTH as ffi::Py_ssize_t,
+                CHANNELS as ffi::Py_ssize_t,
+            ],
+            strides: [row, CHANNELS as ffi::Py_ssize_t, 1],
+        }
+    }
+}
+
+#[pymethods]
+impl Frame {
+    #[getter]
+    fn shape(&self) -> (usize, usize, usize) {
+        (SCREEN_HEIGHT, SCREEN_WIDTH, CHANNELS)
+    }
+
+    fn __len__(&self) -> usize {
+        SCREEN_HEIGHT
+    }
+
+    fn tobytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.pixels)
+    }
+
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("no view to fill"));
+        }
+        if flags & ffi::PyBUF_WRITABLE == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("frames are read only"));
+        }
+        let frame = slf.get();
+        // the view keeps the frame alive, its pixels, shape and strides with it
+        unsafe {
+            (*view).buf = frame.pixels.as_ptr() as *mut c_void;
+            (*view).len = frame.pixels.len() as ffi::Py_ssize_t;
+            (*view).readonly = 1;
+            (*view).itemsize = 1;
+            (*view).format = match flags & ffi::PyBUF_FORMAT == ffi::PyBUF_FORMAT {
+                true => FORMAT.as_ptr() as *mut _,
+                false => ptr::null_mut(),
+            };
+            // without the shape the consumer sees a flat run of bytes
+            (*view).ndim = 1;
+            (*view).shape = ptr::null_mut();
+            if flags & ffi::PyBUF_ND == ffi::PyBUF_ND {
+                (*view).ndim = 3;
+                (*view).shape = frame.shape.as_ptr() as *mut _;
+            }
+            (*view).strides = match flags & ffi::PyBUF_STRIDES == ffi::PyBUF_STRIDES {
+                true => frame.strides.as_ptr() as *mut _,
+                false => ptr::null_mut(),
+            };
+            (*view).suboffsets = ptr::null_mut();
+            (*view).internal = ptr::null_mut();
+            (*view).obj = slf.into_any().into_ptr();
+        }
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, _view: *mut ffi::Py_buffer) {}
+}
+
+/// The emulator for Python, started from a rom and boot rom on disk.
+/// Buttons are held until the next `set_buttons`, emulator panics raise
+/// `pyo3_runtime.PanicException`.
+#[pyclass(name = "GameBoy", module = "rust_boy")]
+struct PyGameBoy {
+    // Python objects have to be Sync, the bus isn't
+    game_boy: Mutex<crate::GameBoy>,
+    boot_rom: Vec<u8>,
+}
+
+impl PyGameBoy {
+    // a panic in the emulator already became an exception, the state is still usable
+    fn game_boy(&self) -> MutexGuard<'_, crate::GameBoy> {
+        self.game_boy.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn game_boy_mut(&mut self) -> &mut crate::GameBoy {
+        self.game_boy
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[pymethods]
+impl PyGameBoy {
+    #[new]
+    #[pyo3(signature = (rom, boot_rom = "dmg_boot.bin"))]
+    fn new(rom: &str, boot_rom: &str) -> PyResult<Self> {
+        let boot_rom = fs::read(boot_rom).map_err(|err| io_error(boot_rom, err))?;
+        let game_boy =
+            crate::GameBoy::new(boot_rom.clone(), &[]).map_err(|err| io_error("rom", err))?;
+        let game_boy = Mutex::new(game_boy);
+        let mut game_boy = PyGameBoy { game_boy, boot_rom };
+        game_boy.load_rom(rom)?;
+        Ok(game_boy)
+    }
+
+    // starts over with another cartridge
+    fn load_rom(&mut self, path: &str) -> PyResult<()> {
+        let rom = fs::read(path).map_err(|err| io_error(path, err))?;
+        *self.game_boy_mut() =
+            crate::GameBoy::new(self.boot_rom.clone(), &rom).map_err(|err| io_error(path, err))?;
+        Ok(())
+    }
+
+    // runs without the GIL, so threads with a Game Boy each run in parallel
+    #[pyo3(signature = (count = 1))]
+    fn run_frames(&mut self, py: Python<'_>, count: u32) {
+        let game_boy = self.game_boy_mut();
+        py.detach(|| {
+            for _ in 0..count {
+                game_boy.run_frame();
+            }
+        });
+    }
+
+    // one instruction, the cycles it took
+    fn step(&mut self) -> u8 {
+        self.game_boy_mut().step()
+    }
+
+    fn set_buttons(&mut self, buttons: Vec<String>) -> PyResult<()> {
+        let buttons = Buttons::from_names(buttons.iter().map(String::as_str))
+            .map_err(|name| PyValueError::new_err(format!("unknown button: {}", name)))?;
+        self.game_boy_mut().set_buttons(buttons);
+        Ok(())
+    }
+
+    fn buttons(&self) -> Vec<&'static str> {
+        self.game_boy().cpu().bus().buttons().names()
+    }
+
+    #[pyo3(signature = (address, length = 1))]
+    fn read_memory<'py>(
+        &self,
+        py: Python<'py>,
+        address: u16,
+        length: usize,
+    ) -> Bound<'py, PyBytes> {
+        let game_boy = self.game_boy();
+        let bus = game_boy.cpu().bus();
+        let bytes: Vec<u8> = (0..length)
+            .map(|i| bus.peek_byte(address.wrapping_add(i as u16)))
+            .collect();
+        PyBytes::new(py, &bytes)
+    }
+
+    fn write_memory(&mut self, address: u16, data: &[u8]) -> PyResult<()> {
+        let bus = self.game_boy_mut().cpu_mut().bus_mut();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            for (i, byte) in data.iter().enumerate() {
+                bus.write_byte(address.wrapping_add(i as u16), *byte);
+            }
+        }));
+        // the caller's own writes shouldn't stop anyone later
+        bus.watchpoints().take_hits();
+        result.map_err(|_| {
+            PyValueError::new_err(format!(
+                "writing {} bytes at ${:04X} failed",
+                data.len(),
+                address
+            ))
+        })
+    }
+
+    fn register(&self, name: &str) -> PyResult<u16> {
+        self.game_boy()
+            .cpu()
+            .register(name)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown register: {}", name)))
+    }
+
+    fn set_register(&mut self, name: &str, value: u16) -> PyResult<()> {
+        match self.game_boy_mut().cpu_mut().set_register(name, value) {
+            true => Ok(()),
+            false => Err(PyValueError::new_err(format!("unknown register: {}", name))),
+        }
+    }
+
+    fn framebuffer(&self) -> Frame {
+        Frame::new(self.game_boy().framebuffer())
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.game_boy().save_state())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        self.game_boy_mut()
+            .load_state(state)
+            .map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+}
+
+#[pymodule]
+fn rust_boy(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyGameBoy>()?;
+    module.add_class::<Frame>()?;
+    module.add("SCREEN_WIDTH", SCREEN_WIDTH)?;
+    module.add("SCREEN_HEIGHT", SCREEN_HEIGHT)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+    use pyo3::buffer::PyBuffer;
+
+    #[test]
+    fn frames_are_height_by_width_by_rgb_buffers() {
+        let mut game_boy = counter_game_boy();
+        game_boy.run_frame();
+        let framebuffer = game_boy.framebuffer();
+        Python::initialize();
+        Python::attach(|py| {
+            let frame = Bound::new(py, Frame::new(framebuffer)).unwrap();
+            let buffer = PyBuffer::<u8>::get(frame.as_any()).unwrap();
+            assert_eq!(buffer.shape(), [SCREEN_HEIGHT, SCREEN_WIDTH, CHANNELS]);
+            assert_eq!(
+                buffer.strides(),
+                [(SCREEN_WIDTH * CHANNELS) as isize, CHANNELS as isize, 1]
+            );
+            assert_eq!(buffer.len_bytes(), SCREEN_HEIGHT * SCREEN_WIDTH * CHANNELS);
+            assert!(buffer.readonly());
+            let pixels = buffer.to_vec(py).unwrap();
+            let [_, red, green, blue] = framebuffer[SCREEN_WIDTH + 1].to_be_bytes();
+            let at = (SCREEN_WIDTH + 1) * CHANNELS;
+            assert_eq!(pixels[at..at + CHANNELS], [red, green, blue]);
+            assert_eq!(frame.len().unwrap(), SCREEN_HEIGHT);
+        });
+    }
+}
@@ -0,0 +1,206 @@
+use crate::GameBoy;
+use std::collections::VecDeque;
+
+/// Rolling history of save states to step backwards in time. Only the newest
+/// state is kept whole, every older one is stored as the difference to the
+/// state after it, so the history is walked back from the newest end and the
+/// oldest entries are dropped once the memory budget is used up.
+pub struct Rewind {
+    // frames between two snapshots
+    interval: u32,
+    budget: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // call once per emulated frame
+    pub fn record(&mut self, game_boy: &GameBoy) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+        let state = game_boy.save_state();
+        if let Some(previous) = &self.newest {
+            if previous.len() == state.len() {
+                let delta = Rewind::compress(previous, &state);
+                self.used += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // the state layout changed, older states can't be rebuilt from this one
+                self.deltas.clear();
+                self.used = 0;
+            }
+        }
+        self.newest = Some(state);
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // loads the snapshot before the newest one, false once the history is exhausted
+    pub fn step_back(&mut self, game_boy: &mut GameBoy) -> bool {
+        let (newest, delta) = match (&mut self.newest, self.deltas.pop_back()) {
+            (Some(newest), Some(delta)) => (newest, delta),
+            _ => return false,
+        };
+        self.used -= delta.len();
+        Rewind::decompress(&delta, newest);
+        self.frames = 0;
+        game_boy.load_state(newest).is_ok()
+    }
+
+    // bytes taken by the compressed history
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    // xor of both states as runs of unchanged bytes followed by the changed ones,
+    // each run prefixed by its length as a little endian base 128 number
+    fn compress(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut delta = Vec::new();
+        let mut i = 0;
+        while i < new.len() {
+            let unchanged = old[i..]
+                .iter()
+                .zip(&new[i..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            i += unchanged;
+            let changed = old[i..]
+                .iter()
+                .zip(&new[i..])
+                .take_while(|(a, b)| a != b)
+                .count();
+            Rewind::write_length(&mut delta, unchanged);
+            Rewind::write_length(&mut delta, changed);
+            delta.extend(
+                old[i..i + changed]
+                    .iter()
+                    .zip(&new[i..])
+                    .map(|(a, b)| a ^ b),
+            );
+            i += changed;
+        }
+        delta
+    }
+
+    // applies a delta to a state in place, turning it into the one it was made from
+    fn decompress(delta: &[u8], state: &mut [u8]) {
+        let mut delta = delta;
+        let mut i = 0;
+        while !delta.is_empty() {
+            i += Rewind::read_length(&mut delta);
+            let changed = Rewind::read_length(&mut delta);
+            for (byte, xor) in state[i..i + changed].iter_mut().zip(&delta[..changed]) {
+                *byte ^= xor;
+            }
+            delta = &delta[changed..];
+            i += changed;
+        }
+    }
+
+    fn write_length(delta: &mut Vec<u8>, mut length: usize) {
+        while length >= 0x80 {
+            delta.push(length as u8 | 0x80);
+            length >>= 7;
+        }
+        delta.push(length as u8);
+    }
+
+    fn read_length(delta: &mut &[u8]) -> usize {
+        let mut length = 0;
+        let mut shift = 0;
+        while let Some((&byte, rest)) = delta.split_first() {
+            *delta = rest;
+            length |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+
+    #[test]
+    fn deltas_restore_the_older_state() {
+        let old: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        // a long changed run and unchanged ones with multi byte lengths
+        for byte in &mut new[200..500] {
+            *byte ^= 0x5A;
+        }
+        new[999] = !new[999];
+        let delta = Rewind::compress(&old, &new);
+        assert!(delta.len() < 320);
+        let mut state = new.clone();
+        Rewind::decompress(&delta, &mut state);
+        assert_eq!(state, old);
+
+        assert_eq!(Rewind::compress(&old, &old), [0xE8, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn lengths_take_seven_bits_per_byte() {
+        for length in [0, 0x7F, 0x80, 0x3FFF, 0x4000, 123_456_789] {
+            let mut bytes = Vec::new();
+            Rewind::write_length(&mut bytes, length);
+            let mut rest = &bytes[..];
+            assert_eq!(Rewind::read_length(&mut rest), length);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn steps_back_through_the_recorded_states() {
+        let mut game_boy = counter_game_boy();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let mut states = Vec::new();
+        for _ in 0..4 {
+            game_boy.run_frame();
+            rewind.record(&game_boy);
+            states.push(game_boy.save_state());
+        }
+        for expected in states.iter().rev().skip(1) {
+            assert!(rewind.step_back(&mut game_boy));
+            assert_eq!(&game_boy.save_state(), expected);
+        }
+        assert!(!rewind.step_back(&mut game_boy));
+        assert_eq!(rewind.memory_used(), 0);
+    }
+
+    #[test]
+    fn states_over_the_budget_are_dropped() {
+        let mut game_boy = counter_game_boy();
+        let mut rewind = Rewind::new(1, 0);
+        for _ in 0..3 {
+            game_boy.run_frame();
+            rewind.record(&game_boy);
+        }
+        assert_eq!(rewind.memory_used(), 0);
+        assert!(!rewind.step_back(&mut game_boy));
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    cdl::{self, CodeDataLog},
+    cpu::instruction::{Instruction, JumpTest},
+    disassembler::{self, Disassembly},
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+const BANK_SIZE: usize = 0x4000;
+const ROMX_FIRST: u16 = 0x4000;
+const ROMX_LAST: u16 = 0x7FFF;
+const DATA_PER_LINE: usize = 16;
+const OPCODE_STOP: u8 = 0x10;
+const OPCODE_LD_A16_A: u8 = 0xEA;
+const OPCODE_LD_A_A16: u8 = 0xFA;
+const HIGH_PAGE: u16 = 0xFF00;
+
+// where the hardware starts running code, besides whatever they lead to
+const ENTRY_POINTS: [(u16, &str); 14] = [
+    (0x00, "RST_00"),
+    (0x08, "RST_08"),
+    (0x10, "RST_10"),
+    (0x18, "RST_18"),
+    (0x20, "RST_20"),
+    (0x28, "RST_28"),
+    (0x30, "RST_30"),
+    (0x38, "RST_38"),
+    (0x40, "VBlankInterrupt"),
+    (0x48, "LCDCInterrupt"),
+    (0x50, "TimerOverflowInterrupt"),
+    (0x58, "SerialTransferCompleteInterrupt"),
+    (0x60, "JoypadTransitionInterrupt"),
+    (0x100, "Boot"),
+];
+
+fn bank_of(offset: usize) -> usize {
+    offset / BANK_SIZE
+}
+
+fn address_of(offset: usize) -> u16 {
+    match bank_of(offset) {
+        0 => offset as u16,
+        _ => ROMX_FIRST + (offset % BANK_SIZE) as u16,
+    }
+}
+
+// rom offset of an address as seen from code in the given bank, the switchable
+// area holds bank 1 for code in bank 0 since that is what it starts out with
+fn offset_of(bank: usize, address: u16) -> Option<usize> {
+    match address {
+        0..=0x3FFF => Some(address as usize),
+        ROMX_FIRST..=ROMX_LAST => Some(bank.max(1) * BANK_SIZE + (address - ROMX_FIRST) as usize),
+        _ => None,
+    }
+}
+
+// the flow doesn't carry on to the next instruction
+fn ends_flow(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::JP(JumpTest::Always)
+            | Instruction::JR(JumpTest::Always)
+            | Instruction::RET(JumpTest::Always)
+            | Instruction::JPHL
+            | Instruction::RETI
+    )
+}
+
+/// Splits a ROM into code and data by following the flow from the entry point
+/// and the interrupt vectors, then writes RGBDS source that assembles back to
+/// the same bytes. Calls and RSTs are assumed to return, code behind a `JP HL`
+/// or in banks other than 0 and 1 is only found when something jumps to it,
+/// unless a code/data log saw it run.
+pub struct RomDisassembler<'a> {
+    rom: &'a [u8],
+    log: Option<&'a CodeDataLog>,
+    // instructions by rom offset
+    code: BTreeMap<usize, Disassembly>,
+    // rom offsets that belong to an instruction
+    covered: Vec<bool>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl<'a> RomDisassembler<'a> {
+    pub fn new(rom: &'a [u8]) -> Self {
+        RomDisassembler::analyse(rom, None)
+    }
+
+    // also follows every opcode the log saw and never decodes what it saw read as data
+    pub fn with_code_data_log(rom: &'a [u8], log: &'a CodeDataLog) -> Self {
+        RomDisassembler::analyse(rom, Some(log))
+    }
+
+    fn analyse(rom: &'a [u8], log: Option<&'a CodeDataLog>) -> Self {
+        let mut disassembler = RomDisassembler {
+            rom,
+            log,
+            code: BTreeMap::new(),
+            covered: vec![false; rom.len()],
+            labels: BTreeMap::new(),
+        };
+        for (address, name) in ENTRY_POINTS {
+            if (address as usize) < rom.len() {
+                disassembler
+                    .labels
+                    .insert(address as usize, name.to_string());
+                disassembler.trace(address as usize);
+            }
+        }
+        if let Some(log) = log {
+            for offset in 0..rom.len() {
+                if log.flags(offset) & cdl::OPCODE != 0 {
+                    disassembler.trace(offset);
+                }
+            }
+        }
+        disassembler
+    }
+
+    // decodes everything reachable from offset
+    fn trace(&mut self, offset: usize) {
+        let mut pending = vec![offset];
+        while let Some(mut offset) = pending.pop() {
+            while let Some(disassembly) = self.decode(offset) {
+                let size = disassembly.size() as usize;
+                self.covered[offset..offset + size].fill(true);
+                let instruction = disassembly.instruction.as_ref().unwrap();
+                let flow_ends = ends_flow(instruction);
+                if let Some(target) = disassembly.jump_target() {
+                    if let Some(target_offset) = offset_of(bank_of(offset), target) {
+                        if target_offset < self.rom.len() {
+                            self.add_label(target_offset, instruction);
+                            pending.push(target_offset);
+                        }
+                    }
+                }
+                self.code.insert(offset, disassembly);
+                if flow_ends {
+                    break;
+                }
+                offset += size;
+            }
+        }
+    }
+
+    // the instruction at offset, unless it's unknown, runs out of its bank or
+    // overlaps one decoded before
+    fn decode(&self, offset: usize) -> Option<Disassembly> {
+        if offset >= self.rom.len() || self.covered[offset] {
+            return None;
+        }
+        let bank = bank_of(offset);
+        let bank_end = ((bank + 1) * BANK_SIZE).min(self.rom.len());
+        let read = |address: u16| match offset_of(bank, address) {
+            Some(offset) if offset < bank_end => self.rom[offset],
+            _ => 0xFF,
+        };
+        let disassembly = disassembler::disassemble(address_of(offset), read);
+        let end = offset + disassembly.size() as usize;
+        let logged_data = |offset| self.log.is_some_and(|log| log.is_data(offset));
+        if disassembly.instruction.is_none()
+            || end > bank_end
+            || self.covered[offset..end].iter().any(|&covered| covered)
+            || (offset..end).any(logged_data)
+        {
+            return None;
+        }
+        Some(disassembly)
+    }
+
+    fn add_label(&mut self, offset: usize, instruction: &Instruction) {
+        let kind = match instruction {
+            Instruction::CALL(_) | Instruction::RST(_) => "Call",
+            _ => "Jump",
+        };
+        let name = format!(
+            "{}_{:02X}_{:04X}",
+            kind,
+            bank_of(offset),
+            address_of(offset)
+        );
+        // calls win over jumps, entry points over both
+        match self.labels.get(&offset) {
+            Some(existing) if !existing.starts_with("Jump_") => {}
+            _ => {
+                self.labels.insert(offset, name);
+            }
+        }
+    }
+
+    pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            if offset % BANK_SIZE == 0 {
+                if offset > 0 {
+                    writeln!(output)?;
+                }
+                match bank_of(offset) {
+                    0 => writeln!(output, "SECTION \"ROM Bank $00\", ROM0[$0000]")?,
+                    bank => writeln!(
+                        output,
+                        "SECTION \"ROM Bank ${:02X}\", ROMX[$4000], BANK[${:02X}]",
+                        bank, bank
+                    )?,
+                }
+            }
+            if let Some(label) = self.labels.get(&offset) {
+                if self.code.contains_key(&offset) {
+                    writeln!(output, "\n{}:", label)?;
+                }
+            }
+            match self.code.get(&offset) {
+                Some(disassembly) => {
+                    writeln!(output, "    {}", self.source(offset, disassembly))?;
+                    offset += disassembly.size() as usize;
+                }
+                None => offset = self.write_data(offset, output)?,
+            }
+        }
+        Ok(())
+    }
+
+    // a line of data up to the next instruction or bank, returns where it stopped
+    fn write_data(&self, offset: usize, output: &mut impl Write) -> io::Result<usize> {
+        let bank_end = (bank_of(offset) + 1) * BANK_SIZE;
+        let end = (offset..self.rom.len().min(bank_end))
+            .take(DATA_PER_LINE)
+            .find(|offset| self.code.contains_key(offset))
+            .unwrap_or_else(|| (offset + DATA_PER_LINE).min(self.rom.len()).min(bank_end));
+        let bytes: Vec<String> = self.rom[offset..end]
+            .iter()
+            .map(|byte| format!("${:02X}", byte))
+            .collect();
+        writeln!(output, "    db {}", bytes.join(", "))?;
+        Ok(end)
+    }
+
+    // the instruction as RGBDS source, with labels for targets that have one
+    fn source(&self, offset: usize, disassembly: &Disassembly) -> String {
+        let instruction = disassembly.instruction.as_ref().unwrap();
+        let bytes = disassembly.bytes.as_slice();
+        let ambiguous = match bytes {
+            // RGBDS always assembles STOP with a zero byte after it
+            [OPCODE_STOP, second] => *second != 0,
+            // and might pick LDH for the high page
+            [OPCODE_LD_A16_A | OPCODE_LD_A_A16, ..] => {
+                disassembly.immediate().unwrap_or(0) >= HIGH_PAGE
+            }
+            _ => false,
+        };
+        if ambiguous {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+            return format!("db {} ; {}", bytes.join(", "), disassembly);
+        }
+        let label = disassembly.jump_target().and_then(|target| {
+            let target_offset = offset_of(bank_of(offset), target)?;
+            // a relative jump can't reach a label in another section
+            if matches!(instruction, Instruction::JR(_))
+                && bank_of(target_offset) != bank_of(offset)
+            {
+                return None;
+            }
+            self.labels
+                .get(&target_offset)
+                .filter(|_| self.code.contains_key(&target_offset))
+        });
+        let text = match (instruction, label) {
+            (Instruction::JR(_), Some(label)) => instruction.to_string().replacen("e8", label, 1),
+            (Instruction::JP(_) | Instruction::CALL(_), Some(label)) => {
+                instruction.to_string().replacen("a16", label, 1)
+            }
+            _ => disassembly.to_string(),
+        };
+        // RGBDS wants brackets around memory operands
+        text.replace('(', "[").replace(')', "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two banks of invalid opcodes, which never decode, with a little code in each
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0xD3; 2 * BANK_SIZE];
+        #[rustfmt::skip]
+        let boot = [
+            0xCD, 0x00, 0x40, // CALL $4000
+            0x2A,             // LD A,(HL+)
+            0x10, 0x00,       // STOP
+            0x10, 0x01,       // STOP with a byte RGBDS wouldn't write
+            0xEA, 0x00, 0xC0, // LD ($C000),A
+            0xEA, 0x80, 0xFF, // LD ($FF80),A, which RGBDS might turn into LDH
+            0xC3, 0x50, 0x01, // JP $0150
+        ];
+        rom[0x100..0x100 + boot.len()].copy_from_slice(&boot);
+        // JR $0150
+        rom[0x150..0x152].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x160] = 0xC9;
+        // CALL $0160; JP $4010 in bank 1
+        rom[0x4000..0x4006].copy_from_slice(&[0xCD, 0x60, 0x01, 0xC3, 0x10, 0x40]);
+        rom[0x4010] = 0xC9;
+        rom
+    }
+
+    fn source(rom: &[u8]) -> String {
+        let mut output = Vec::new();
+        RomDisassembler::new(rom).write(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn code_is_labelled_and_the_rest_is_data() {
+        let source = source(&rom());
+        let data = format!("    db {}", ["$D3"; DATA_PER_LINE].join(", "));
+        let mut lines = source.lines();
+        assert_eq!(lines.next(), Some("SECTION \"ROM Bank $00\", ROM0[$0000]"));
+        assert_eq!(lines.next(), Some(data.as_str()));
+        assert!(source.contains(concat!(
+            "\nBoot:\n",
+            "    CALL Call_01_4000\n",
+            "    LD A,[HL+]\n",
+            "    STOP\n",
+            "    db $10, $01 ; STOP\n",
+            "    LD [$C000],A\n",
+            "    db $EA, $80, $FF ; LD ($FF80),A\n",
+            "    JP Jump_00_0150\n",
+            "    db $D3, $D3,",
+        )));
+        assert!(source.contains("\nJump_00_0150:\n    JR Jump_00_0150\n    db $D3,"));
+        // bank 1 calls back into bank 0 and jumps within itself
+        assert!(source.contains(concat!(
+            "SECTION \"ROM Bank $01\", ROMX[$4000], BANK[$01]\n\n",
+            "Call_01_4000:\n",
+            "    CALL Call_00_0160\n",
+            "    JP Jump_01_4010\n",
+            "    db $D3,",
+        )));
+        assert!(source.contains("\nCall_00_0160:\n    RET\n"));
+        assert!(source.contains("\nJump_01_4010:\n    RET\n"));
+        // the vectors don't decode, so they get no label
+        assert!(!source.contains("RST_00"));
+    }
+}
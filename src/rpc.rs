@@ -0,0 +1,415 @@
+use crate::{
+    cpu::CPU,
+    gpu::{self, SCREEN_HEIGHT, SCREEN_WIDTH},
+    joypad::Buttons,
+    GameBoy,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Map, Value};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// the first of the codes JSON-RPC leaves to the server
+const EMULATOR_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn invalid_params(message: impl Into<String>) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: message.into(),
+    }
+}
+
+fn emulator_error(message: impl Into<String>) -> RpcError {
+    RpcError {
+        code: EMULATOR_ERROR,
+        message: message.into(),
+    }
+}
+
+// an unsigned parameter no larger than max, the default when it's missing
+fn param(params: &Value, name: &str, default: Option<u64>, max: u64) -> Result<u64, RpcError> {
+    match params.get(name) {
+        Some(value) => value
+            .as_u64()
+            .filter(|&value| value <= max)
+            .ok_or_else(|| invalid_params(format!("{} must be a number up to {}", name, max))),
+        None => default.ok_or_else(|| invalid_params(format!("missing {}", name))),
+    }
+}
+
+fn param_str<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params(format!("missing {}", name)))
+}
+
+fn parse_buttons(params: &Value) -> Result<Buttons, RpcError> {
+    let names: Vec<&str> = params
+        .get("buttons")
+        .and_then(Value::as_array)
+        .and_then(|names| names.iter().map(Value::as_str).collect())
+        .ok_or_else(|| invalid_params("buttons must be an array of names"))?;
+    Buttons::from_names(names).map_err(|name| invalid_params(format!("unknown button: {}", name)))
+}
+
+// runs the emulator, a panic becomes an error instead of ending the server
+fn run_guarded<T>(
+    game_boy: &mut GameBoy,
+    run: impl FnOnce(&mut GameBoy) -> T,
+) -> Result<T, RpcError> {
+    panic::catch_unwind(AssertUnwindSafe(|| run(game_boy)))
+        .map_err(|_| emulator_error(format!("emulator panicked at ${:04X}", game_boy.cpu().pc())))
+}
+
+/// JSON-RPC 2.0 server on a Unix socket, for test harnesses in other
+/// languages. Requests and responses are single lines of JSON, batches
+/// included. Clients are served one after another until one of them calls
+/// `shutdown`.
+///
+/// Methods, with the framebuffer and states in base64:
+/// - `load_rom {path}` starts over with a new cartridge
+/// - `step {count=1}`, `run_frames {count=1}`
+/// - `set_buttons {buttons: ["a", "start"]}`, `get_buttons`
+/// - `read_memory {address, length=1}` and `write_memory {address, data: [..]}` with byte arrays
+/// - `get_registers`, `set_registers {a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc}`
+/// - `framebuffer` as RGB bytes, `screenshot {path}` as png
+/// - `save_state`, `load_state {state}`
+/// - `shutdown`
+pub struct RpcServer {
+    listener: UnixListener,
+    path: PathBuf,
+    boot_rom: Vec<u8>,
+    // frames run since the rom was loaded
+    frames: u64,
+    shutdown: bool,
+}
+
+impl RpcServer {
+    // a socket left over from an earlier server is replaced, any other file is an error
+    pub fn bind(path: &str, boot_rom: Vec<u8>) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        Ok(RpcServer {
+            listener: UnixListener::bind(path)?,
+            path: Path::new(path).to_path_buf(),
+            boot_rom,
+            frames: 0,
+            shutdown: false,
+        })
+    }
+
+    pub fn run(&mut self, game_boy: &mut GameBoy) -> io::Result<()> {
+        while !self.shutdown {
+            let (stream, _) = self.listener.accept()?;
+            // a client going away mid request only ends its own session
+            if let Err(err) = self.serve(stream, game_boy) {
+                eprintln!("RPC client failed: {}", err);
+            }
+        }
+        fs::remove_file(&self.path)
+    }
+
+    fn serve(&mut self, stream: UnixStream, game_boy: &mut GameBoy) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(game_boy, &line) {
+                writeln!(writer, "{}", response)?;
+            }
+            if self.shutdown {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // the response to a line, None when it only held notifications
+    fn handle(&mut self, game_boy: &mut GameBoy, line: &str) -> Option<Value> {
+        let request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = RpcError {
+                    code: PARSE_ERROR,
+                    message: err.to_string(),
+                };
+                return Some(RpcServer::response(Value::Null, Err(error)));
+            }
+        };
+        match request {
+            Value::Array(batch) if !batch.is_empty() => {
+                let responses: Vec<Value> = batch
+                    .into_iter()
+                    .filter_map(|request| self.call(game_boy, request))
+                    .collect();
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            request => self.call(game_boy, request),
+        }
+    }
+
+    fn call(&mut self, game_boy: &mut GameBoy, request: Value) -> Option<Value> {
+        let method = request
+            .get("method")
+            .and_then(Value::as_str)
+            .filter(|_| request.get("jsonrpc") == Some(&json!("2.0")));
+        let (id, result) = match (method, request.get("id")) {
+            (Some(method), id) => {
+                let params = request.get("params").unwrap_or(&Value::Null);
+                let result = self.execute(game_boy, method, params);
+                // notifications run without a response
+                (id.cloned()?, result)
+            }
+            (None, id) => {
+                let error = RpcError {
+                    code: INVALID_REQUEST,
+                    message: "invalid request".to_string(),
+                };
+                (id.cloned().unwrap_or(Value::Null), Err(error))
+            }
+        };
+        Some(RpcServer::response(id, result))
+    }
+
+    fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => json!({
+                "jsonrpc": "2.0",
+                "error": { "code": error.code, "message": error.message },
+                "id": id,
+            }),
+        }
+    }
+
+    fn execute(
+        &mut self,
+        game_boy: &mut GameBoy,
+        method: &str,
+        params: &Value,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "load_rom" => {
+                let path = param_str(params, "path")?;
+                let rom = fs::read(path).map_err(|err| emulator_error(err.to_string()))?;
+                *game_boy = GameBoy::new(self.boot_rom.clone(), &rom)
+                    .map_err(|err| emulator_error(err.to_string()))?;
+                self.frames = 0;
+                Ok(json!({ "size": rom.len() }))
+            }
+            "step" => {
+                let count = param(params, "count", Some(1), u32::MAX as u64)?;
+                let cycles = run_guarded(game_boy, |game_boy| {
+                    (0..count).map(|_| game_boy.step() as u64).sum::<u64>()
+                })?;
+                Ok(json!({ "cycles": cycles, "pc": game_boy.cpu().pc() }))
+            }
+            "run_frames" => {
+                let count = param(params, "count", Some(1), u32::MAX as u64)?;
+                for _ in 0..count {
+                    run_guarded(game_boy, GameBoy::run_frame)?;
+                    self.frames += 1;
+                }
+                Ok(json!({ "frame": self.frames }))
+            }
+            "set_buttons" => {
+                game_boy.set_buttons(parse_buttons(params)?);
+                Ok(Value::Null)
+            }
+            "get_buttons" => Ok(json!({ "buttons": game_boy.cpu().bus().buttons().names() })),
+            "read_memory" => {
+                let address = param(params, "address", None, 0xFFFF)? as u16;
+                let length = param(params, "length", Some(1), 0x10000)?;
+                let bus = game_boy.cpu().bus();
+                let bytes: Vec<u8> = (0..length)
+                    .map(|i| bus.peek_byte(address.wrapping_add(i as u16)))
+                    .collect();
+                Ok(json!({ "data": bytes }))
+            }
+            "write_memory" => {
+                let address = param(params, "address", None, 0xFFFF)? as u16;
+                let bytes: Vec<u8> = params
+                    .get("data")
+                    .and_then(|data| serde_json::from_value(data.clone()).ok())
+                    .ok_or_else(|| invalid_params("data must be an array of bytes"))?;
+                let result = run_guarded(game_boy, |game_boy| {
+                    let bus = game_boy.cpu_mut().bus_mut();
+                    for (i, byte) in bytes.iter().enumerate() {
+                        bus.write_byte(address.wrapping_add(i as u16), *byte);
+                    }
+                });
+                // the client's own writes shouldn't stop anyone later
+                game_boy.cpu_mut().bus_mut().watchpoints().take_hits();
+                result.map(|_| Value::Null)
+            }
+            "get_registers" => {
+                let cpu = game_boy.cpu();
+                let registers: Map<String, Value> = CPU::REGISTER_NAMES
+                    .iter()
+                    .map(|&name| (name.to_string(), json!(cpu.register(name))))
+                    .collect();
+                Ok(Value::Object(registers))
+            }
+            "set_registers" => {
+                let registers = params
+                    .as_object()
+                    .ok_or_else(|| invalid_params("registers must be an object"))?;
+                // all checked before any is written
+                for name in registers.keys() {
+                    param(params, name, None, 0xFFFF)?;
+                    if !CPU::REGISTER_NAMES.contains(&name.as_str()) {
+                        return Err(invalid_params(format!("unknown register: {}", name)));
+                    }
+                }
+                for (name, value) in registers {
+                    let value = value.as_u64().unwrap_or_default() as u16;
+                    game_boy.cpu_mut().set_register(name, value);
+                }
+                Ok(Value::Null)
+            }
+            "framebuffer" => {
+                let pixels: Vec<u8> = game_boy
+                    .framebuffer()
+                    .iter()
+                    .flat_map(|pixel| {
+                        let [_, red, green, blue] = pixel.to_be_bytes();
+                        [red, green, blue]
+                    })
+                    .collect();
+                Ok(json!({
+                    "width": SCREEN_WIDTH,
+                    "height": SCREEN_HEIGHT,
+                    "data": BASE64.encode(pixels),
+                }))
+            }
+            "screenshot" => {
+                let path = param_str(params, "path")?;
+                gpu::write_png(game_boy.framebuffer(), Path::new(path))
+                    .map_err(|err| emulator_error(err.to_string()))?;
+                Ok(Value::Null)
+            }
+            "save_state" => Ok(json!({ "state": BASE64.encode(game_boy.save_state()) })),
+            "load_state" => {
+                let state = BASE64
+                    .decode(param_str(params, "state")?)
+                    .map_err(|err| invalid_params(err.to_string()))?;
+                game_boy
+                    .load_state(&state)
+                    .map_err(|err| emulator_error(err.to_string()))?;
+                Ok(Value::Null)
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method: {}", method),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+    use std::{env, net::Shutdown, process};
+
+    fn server(name: &str) -> RpcServer {
+        let path = env::temp_dir().join(format!("rust_boy_rpc_{}_{}.sock", name, process::id()));
+        RpcServer::bind(path.to_str().unwrap(), vec![0; 0x100]).unwrap()
+    }
+
+    #[test]
+    fn requests_batches_and_notifications() {
+        let mut server = server("framing");
+        let mut game_boy = counter_game_boy();
+        let request = r#"{"jsonrpc":"2.0","method":"read_memory","params":{"address":256,"length":3},"id":7}"#;
+        assert_eq!(
+            server.handle(&mut game_boy, request),
+            Some(json!({ "jsonrpc": "2.0", "result": { "data": [0x21, 0x00, 0xC0] }, "id": 7 }))
+        );
+
+        let notification =
+            r#"{"jsonrpc":"2.0","method":"write_memory","params":{"address":49152,"data":[5]}}"#;
+        assert_eq!(server.handle(&mut game_boy, notification), None);
+        assert_eq!(game_boy.cpu().bus().peek_byte(0xC000), 5);
+        let batch = format!(
+            r#"[{}, {{"jsonrpc":"2.0","method":"nope","id":"a"}}, {{"id":1}}]"#,
+            notification
+        );
+        let responses = server.handle(&mut game_boy, &batch).unwrap();
+        assert_eq!(responses[0]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[0]["id"], "a");
+        assert_eq!(responses[1]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(responses.as_array().unwrap().len(), 2);
+        assert_eq!(
+            server.handle(&mut game_boy, &format!("[{}]", notification)),
+            None
+        );
+
+        let error = server.handle(&mut game_boy, "{").unwrap();
+        assert_eq!(error["error"]["code"], PARSE_ERROR);
+        assert_eq!(error["id"], Value::Null);
+        let error = server.handle(&mut game_boy, "[]").unwrap();
+        assert_eq!(error["error"]["code"], INVALID_REQUEST);
+        let request =
+            r#"{"jsonrpc":"2.0","method":"read_memory","params":{"address":65536},"id":1}"#;
+        let error = server.handle(&mut game_boy, request).unwrap();
+        assert_eq!(error["error"]["code"], INVALID_PARAMS);
+        fs::remove_file(&server.path).unwrap();
+    }
+
+    #[test]
+    fn serves_one_line_per_request_until_shutdown() {
+        let mut server = server("serve");
+        let mut game_boy = counter_game_boy();
+        let (stream, mut client) = UnixStream::pair().unwrap();
+        let requests = concat!(
+            r#"{"jsonrpc":"2.0","method":"set_registers","params":{"pc":256,"hl":4660},"id":1}"#,
+            "\n\n",
+            r#"{"jsonrpc":"2.0","method":"get_registers","id":2}"#,
+            "\n",
+            r#"{"jsonrpc":"2.0","method":"shutdown","id":3}"#,
+            "\n",
+            r#"{"jsonrpc":"2.0","method":"get_buttons","id":4}"#,
+            "\n",
+        );
+        client.write_all(requests.as_bytes()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        server.serve(stream, &mut game_boy).unwrap();
+        assert!(server.shutdown);
+
+        let responses: Vec<Value> = BufReader::new(client)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        let ids: Vec<&Value> = responses.iter().map(|response| &response["id"]).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(responses[1]["result"]["hl"], 0x1234);
+        assert_eq!(responses[1]["result"]["pc"], 0x100);
+        fs::remove_file(&server.path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use crate::save_state::{
+    read_bool, read_bytes, read_u32, write_bool, write_bytes, write_u32, SaveState,
+};
+use std::io;
+
+pub const SECONDS: usize = 0;
+pub const MINUTES: usize = 1;
+pub const HOURS: usize = 2;
+pub const DAY_LOW: usize = 3;
+pub const DAY_HIGH: usize = 4;
+pub const REGISTER_COUNT: usize = 5;
+
+// DAY_HIGH bits
+const DAY_BIT8: u8 = 0x01;
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+// bits each register keeps
+const MASKS: [u8; REGISTER_COUNT] = [0x3F, 0x3F, 0x1F, 0xFF, DAY_BIT8 | HALT | DAY_CARRY];
+
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+// what save_state writes
+pub const STATE_SIZE: usize = 2 * REGISTER_COUNT + 1 + 4;
+
+/// The real time clock of MBC3 cartridges. It counts emulated time rather than
+/// the host's, so replaying a movie or rewinding replays the clock as well.
+#[derive(Default)]
+pub struct Rtc {
+    // seconds, minutes, hours, lower 8 bits of the day and DH, selected by 08-0C
+    registers: [u8; REGISTER_COUNT],
+    // what reads see, copied from the registers by writing 00 then 01 to 6000-7FFF
+    latched: [u8; REGISTER_COUNT],
+    latch_armed: bool,
+    // into the current second
+    cycles: u32,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc::default()
+    }
+    pub fn registers(&self) -> [u8; REGISTER_COUNT] {
+        self.registers
+    }
+    pub fn latched(&self) -> [u8; REGISTER_COUNT] {
+        self.latched
+    }
+    // sets the clock from a snapshot, the second starts over
+    pub fn restore(&mut self, registers: [u8; REGISTER_COUNT], latched: [u8; REGISTER_COUNT]) {
+        for i in 0..REGISTER_COUNT {
+            self.registers[i] = registers[i] & MASKS[i];
+            self.latched[i] = latched[i] & MASKS[i];
+        }
+        self.cycles = 0;
+    }
+    pub fn read(&self, register: usize) -> u8 {
+        self.latched[register]
+    }
+    // written values read back without latching again
+    pub fn write(&mut self, register: usize, byte: u8) {
+        self.registers[register] = byte & MASKS[register];
+        self.latched[register] = self.registers[register];
+        if register == SECONDS {
+            self.cycles = 0;
+        }
+    }
+    pub fn latch(&mut self, byte: u8) {
+        if self.latch_armed && byte == 1 {
+            self.latched = self.registers;
+        }
+        self.latch_armed = byte == 0;
+    }
+    pub fn step(&mut self, cycles: u8) {
+        if self.registers[DAY_HIGH] & HALT != 0 {
+            return;
+        }
+        self.cycles += cycles as u32;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+    // counters set past their range count up to the mask and wrap without a carry
+    fn tick(&mut self) {
+        for (register, limit) in [(SECONDS, 60), (MINUTES, 60), (HOURS, 24)] {
+            self.registers[register] = (self.registers[register] + 1) & MASKS[register];
+            if self.registers[register] != limit {
+                return;
+            }
+            self.registers[register] = 0;
+        }
+        let high = self.registers[DAY_HIGH];
+        let day = ((high & DAY_BIT8) as u16) << 8 | self.registers[DAY_LOW] as u16;
+        let day = day + 1;
+        self.registers[DAY_LOW] = day as u8;
+        self.registers[DAY_HIGH] = (high & !DAY_BIT8) | ((day >> 8) as u8 & DAY_BIT8);
+        if day == 512 {
+            self.registers[DAY_HIGH] |= DAY_CARRY;
+        }
+    }
+}
+
+impl SaveState for Rtc {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_bytes(state, &self.registers);
+        write_bytes(state, &self.latched);
+        write_bool(state, self.latch_armed);
+        write_u32(state, self.cycles);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        let mut registers = [0; REGISTER_COUNT];
+        let mut latched = [0; REGISTER_COUNT];
+        read_bytes(state, &mut registers)?;
+        read_bytes(state, &mut latched)?;
+        self.restore(registers, latched);
+        self.latch_armed = read_bool(state)?;
+        self.cycles = read_u32(state)? % CYCLES_PER_SECOND;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_seconds(rtc: &mut Rtc, seconds: u32) {
+        for _ in 0..seconds * (CYCLES_PER_SECOND / 128) {
+            rtc.step(128);
+        }
+    }
+
+    #[test]
+    fn counts_emulated_seconds_into_days() {
+        let mut rtc = Rtc::new();
+        rtc.write(SECONDS, 59);
+        rtc.write(MINUTES, 59);
+        rtc.write(HOURS, 23);
+        rtc.write(DAY_LOW, 0xFF);
+        run_seconds(&mut rtc, 1);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, DAY_BIT8]);
+        // reads keep the latched time until the next 00, 01
+        assert_eq!(rtc.read(DAY_LOW), 0xFF);
+        rtc.latch(1);
+        assert_eq!(rtc.read(DAY_LOW), 0xFF);
+        rtc.latch(0);
+        rtc.latch(1);
+        assert_eq!(rtc.read(DAY_HIGH), DAY_BIT8);
+
+        rtc.restore([59, 59, 23, 0xFF, DAY_BIT8], [0; REGISTER_COUNT]);
+        run_seconds(&mut rtc, 1);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, DAY_CARRY]);
+    }
+
+    #[test]
+    fn halted_clock_stands_still() {
+        let mut rtc = Rtc::new();
+        rtc.write(DAY_HIGH, HALT);
+        run_seconds(&mut rtc, 2);
+        assert_eq!(rtc.registers()[SECONDS], 0);
+        rtc.write(DAY_HIGH, 0);
+        run_seconds(&mut rtc, 2);
+        assert_eq!(rtc.registers()[SECONDS], 2);
+    }
+}
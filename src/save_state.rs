@@ -0,0 +1,77 @@
+use std::io::{self, Error, ErrorKind};
+
+pub const MAGIC: &[u8; 8] = b"RUSTBOY\0";
+// bump whenever the layout of any component changes
+pub const VERSION: u16 = 1;
+
+/// Serialises a component into the snapshot format. Components write their
+/// fields in a fixed order and read them back in the same order.
+pub trait SaveState {
+    fn save_state(&self, state: &mut Vec<u8>);
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()>;
+}
+
+pub fn write_header(state: &mut Vec<u8>) {
+    state.extend_from_slice(MAGIC);
+    write_u16(state, VERSION);
+}
+
+pub fn read_header(state: &mut &[u8]) -> io::Result<()> {
+    let mut magic = [0; 8];
+    read_bytes(state, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a save state"));
+    }
+    let version = read_u16(state)?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported save state version {}", version),
+        ));
+    }
+    Ok(())
+}
+
+pub fn write_u8(state: &mut Vec<u8>, value: u8) {
+    state.push(value);
+}
+pub fn write_bool(state: &mut Vec<u8>, value: bool) {
+    state.push(value as u8);
+}
+pub fn write_u16(state: &mut Vec<u8>, value: u16) {
+    state.extend_from_slice(&value.to_le_bytes());
+}
+pub fn write_u32(state: &mut Vec<u8>, value: u32) {
+    state.extend_from_slice(&value.to_le_bytes());
+}
+pub fn write_bytes(state: &mut Vec<u8>, bytes: &[u8]) {
+    state.extend_from_slice(bytes);
+}
+
+pub fn read_bytes(state: &mut &[u8], bytes: &mut [u8]) -> io::Result<()> {
+    if state.len() < bytes.len() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "save state truncated"));
+    }
+    let (head, tail) = state.split_at(bytes.len());
+    bytes.copy_from_slice(head);
+    *state = tail;
+    Ok(())
+}
+pub fn read_u8(state: &mut &[u8]) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    read_bytes(state, &mut bytes)?;
+    Ok(bytes[0])
+}
+pub fn read_bool(state: &mut &[u8]) -> io::Result<bool> {
+    Ok(read_u8(state)? != 0)
+}
+pub fn read_u16(state: &mut &[u8]) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    read_bytes(state, &mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+pub fn read_u32(state: &mut &[u8]) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    read_bytes(state, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,374 @@
+use crate::{
+    gpu,
+    joypad::Buttons,
+    symbols::Symbols,
+    watchpoint::{WatchAction, WatchHit, Watchpoint},
+    GameBoy,
+};
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, FnPtr, NativeCallContext, INT};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn parse_buttons(names: &Array) -> ScriptResult<Buttons> {
+    let names: Vec<String> = names.iter().map(Dynamic::to_string).collect();
+    Buttons::from_names(names.iter().map(String::as_str))
+        .map_err(|name| format!("unknown button: {}", name).into())
+}
+
+// what the registered functions share, the engine needs them to be 'static
+struct State {
+    game_boy: RefCell<GameBoy>,
+    symbols: Arc<Symbols>,
+    frames: Cell<INT>,
+    // script functions called by watchpoints, by hook number
+    hooks: RefCell<HashMap<INT, (u32, FnPtr)>>,
+    next_hook: Cell<INT>,
+    // filled by the watchpoints while the emulator runs, the callbacks run after each instruction
+    hits: Arc<Mutex<Vec<(INT, WatchHit)>>>,
+}
+
+impl State {
+    fn location(&self, location: &Dynamic) -> ScriptResult<u16> {
+        if let Ok(address) = location.as_int() {
+            return u16::try_from(address)
+                .map_err(|_| format!("address out of range: {}", address).into());
+        }
+        let name = location.to_string();
+        self.symbols
+            .address_of(&name)
+            .ok_or_else(|| format!("unknown symbol: {}", name).into())
+    }
+
+    fn add_hook(
+        &self,
+        location: &Dynamic,
+        callback: FnPtr,
+        read: bool,
+        write: bool,
+        execute: bool,
+    ) -> ScriptResult<INT> {
+        let address = self.location(location)?;
+        let hook = self.next_hook.get();
+        self.next_hook.set(hook + 1);
+        let hits = self.hits.clone();
+        let id = self
+            .game_boy
+            .borrow_mut()
+            .cpu_mut()
+            .bus_mut()
+            .watchpoints()
+            .add(Watchpoint {
+                addresses: address..=address,
+                read,
+                write,
+                execute,
+                action: WatchAction::Callback(Box::new(move |hit| {
+                    hits.lock().unwrap().push((hook, hit))
+                })),
+            });
+        self.hooks.borrow_mut().insert(hook, (id, callback));
+        Ok(hook)
+    }
+
+    fn remove_hook(&self, hook: INT) -> bool {
+        match self.hooks.borrow_mut().remove(&hook) {
+            Some((id, _)) => self
+                .game_boy
+                .borrow_mut()
+                .cpu_mut()
+                .bus_mut()
+                .watchpoints()
+                .remove(id),
+            None => false,
+        }
+    }
+
+    // runs one instruction, or up to the end of the frame, calling the hooks it hit
+    fn run(&self, context: &NativeCallContext, whole_frame: bool) -> ScriptResult<()> {
+        loop {
+            let result = {
+                let mut game_boy = self.game_boy.borrow_mut();
+                panic::catch_unwind(AssertUnwindSafe(|| match whole_frame {
+                    true => game_boy.step_in_frame(),
+                    false => {
+                        game_boy.step();
+                        false
+                    }
+                }))
+            };
+            let hits = std::mem::take(&mut *self.hits.lock().unwrap());
+            for (hook, hit) in hits {
+                // a hook may remove itself or others
+                let callback = self.hooks.borrow().get(&hook).map(|(_, f)| f.clone());
+                if let Some(callback) = callback {
+                    let args = (hit.address as INT, hit.value as INT, hit.pc as INT);
+                    let _: Dynamic = callback.call_within_context(context, args)?;
+                }
+            }
+            match result {
+                Ok(true) => {
+                    self.frames.set(self.frames.get() + 1);
+                    return Ok(());
+                }
+                Ok(false) if !whole_frame => return Ok(()),
+                Ok(false) => {}
+                Err(_) => {
+                    let pc = self.game_boy.borrow().cpu().pc();
+                    let message = format!("emulator panicked at {}", self.symbols.format(pc));
+                    return Err(message.into());
+                }
+            }
+        }
+    }
+}
+
+/// Runs Rhai scripts against the emulator, for bots, checks on memory and
+/// batches of screenshots. Scripts get:
+///
+/// - `read(addr)`, `read16(addr)`, `write(addr, value)` on the memory map
+/// - `reg("hl")`, `set_reg("a", value)` for a, f, b, c, d, e, h, l, af, bc, de, hl, sp and pc
+/// - `step()`, `frame()`, `frames(n)` and `frame_count()` to run the emulator
+/// - `press(["a", "start"])` holding those buttons from now on, `release()`
+/// - `on_exec(addr, f)`, `on_read(addr, f)`, `on_write(addr, f)` calling
+///   `f(address, value, pc)` on each access, the hook they return goes to `remove_hook`
+/// - `screenshot(path)`, `save_state()`, `load_state(blob)`, `symbol(name)`
+///   and `assert(condition, message)`
+///
+/// Addresses can be symbol names wherever they're taken.
+pub struct Script {
+    engine: Engine,
+    state: Rc<State>,
+}
+
+impl Script {
+    pub fn new(game_boy: GameBoy, symbols: Arc<Symbols>) -> Self {
+        let state = Rc::new(State {
+            game_boy: RefCell::new(game_boy),
+            symbols,
+            frames: Cell::new(0),
+            hooks: RefCell::new(HashMap::new()),
+            next_hook: Cell::new(0),
+            hits: Arc::new(Mutex::new(Vec::new())),
+        });
+        let mut engine = Engine::new();
+        Script::register_memory(&mut engine, &state);
+        Script::register_control(&mut engine, &state);
+        Script::register_hooks(&mut engine, &state);
+        Script { engine, state }
+    }
+
+    fn register_memory(engine: &mut Engine, state: &Rc<State>) {
+        let s = state.clone();
+        engine.register_fn("read", move |location: Dynamic| -> ScriptResult<INT> {
+            let address = s.location(&location)?;
+            Ok(s.game_boy.borrow().cpu().bus().peek_byte(address) as INT)
+        });
+        let s = state.clone();
+        engine.register_fn("read16", move |location: Dynamic| -> ScriptResult<INT> {
+            let address = s.location(&location)?;
+            let game_boy = s.game_boy.borrow();
+            let bus = game_boy.cpu().bus();
+            let word = [
+                bus.peek_byte(address),
+                bus.peek_byte(address.wrapping_add(1)),
+            ];
+            Ok(u16::from_le_bytes(word) as INT)
+        });
+        let s = state.clone();
+        engine.register_fn(
+            "write",
+            move |location: Dynamic, value: INT| -> ScriptResult<()> {
+                let address = s.location(&location)?;
+                let mut game_boy = s.game_boy.borrow_mut();
+                let bus = game_boy.cpu_mut().bus_mut();
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| bus.write_byte(address, value as u8)));
+                // the script's own writes don't call its hooks
+                s.hits.lock().unwrap().clear();
+                result.map_err(|_| format!("can't write to ${:04X}", address).into())
+            },
+        );
+        let s = state.clone();
+        engine.register_fn("reg", move |name: &str| -> ScriptResult<INT> {
+            match s.game_boy.borrow().cpu().register(name) {
+                Some(value) => Ok(value as INT),
+                None => Err(format!("unknown register: {}", name).into()),
+            }
+        });
+        let s = state.clone();
+        engine.register_fn(
+            "set_reg",
+            move |name: &str, value: INT| -> ScriptResult<()> {
+                match s
+                    .game_boy
+                    .borrow_mut()
+                    .cpu_mut()
+                    .set_register(name, value as u16)
+                {
+                    true => Ok(()),
+                    false => Err(format!("unknown register: {}", name).into()),
+                }
+            },
+        );
+        let s = state.clone();
+        engine.register_fn("symbol", move |name: &str| -> ScriptResult<INT> {
+            s.location(&name.into()).map(|address| address as INT)
+        });
+    }
+
+    fn register_control(engine: &mut Engine, state: &Rc<State>) {
+        let s = state.clone();
+        engine.register_fn("step", move |context: NativeCallContext| {
+            s.run(&context, false)
+        });
+        let s = state.clone();
+        engine.register_fn("frame", move |context: NativeCallContext| {
+            s.run(&context, true)
+        });
+        let s = state.clone();
+        engine.register_fn(
+            "frames",
+            move |context: NativeCallContext, count: INT| -> ScriptResult<()> {
+                for _ in 0..count {
+                    s.run(&context, true)?;
+                }
+                Ok(())
+            },
+        );
+        let s = state.clone();
+        engine.register_fn("frame_count", move || s.frames.get());
+        let s = state.clone();
+        engine.register_fn("press", move |names: Array| -> ScriptResult<()> {
+            let buttons = parse_buttons(&names)?;
+            s.game_boy.borrow_mut().set_buttons(buttons);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("release", move || {
+            s.game_boy.borrow_mut().set_buttons(Buttons::default())
+        });
+        let s = state.clone();
+        engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+            gpu::write_png(s.game_boy.borrow().framebuffer(), Path::new(path))
+                .map_err(|err| format!("screenshot {} failed: {}", path, err).into())
+        });
+        let s = state.clone();
+        engine.register_fn("save_state", move || -> Blob {
+            s.game_boy.borrow().save_state()
+        });
+        let s = state.clone();
+        engine.register_fn("load_state", move |state: Blob| -> ScriptResult<()> {
+            s.game_boy
+                .borrow_mut()
+                .load_state(&state)
+                .map_err(|err| format!("load state failed: {}", err).into())
+        });
+        engine.register_fn(
+            "assert",
+            |condition: bool, message: &str| -> ScriptResult<()> {
+                match condition {
+                    true => Ok(()),
+                    false => Err(format!("assertion failed: {}", message).into()),
+                }
+            },
+        );
+    }
+
+    fn register_hooks(engine: &mut Engine, state: &Rc<State>) {
+        let s = state.clone();
+        engine.register_fn("on_exec", move |location: Dynamic, callback: FnPtr| {
+            s.add_hook(&location, callback, false, false, true)
+        });
+        let s = state.clone();
+        engine.register_fn("on_read", move |location: Dynamic, callback: FnPtr| {
+            s.add_hook(&location, callback, true, false, false)
+        });
+        let s = state.clone();
+        engine.register_fn("on_write", move |location: Dynamic, callback: FnPtr| {
+            s.add_hook(&location, callback, false, true, false)
+        });
+        let s = state.clone();
+        engine.register_fn("remove_hook", move |hook: INT| s.remove_hook(hook));
+    }
+
+    pub fn run(&self, source: &str) -> Result<(), Box<EvalAltResult>> {
+        self.engine.run(source)
+    }
+
+    // the emulator back, its watchpoints left by the hooks removed
+    pub fn finish(self) -> GameBoy {
+        let hooks: Vec<INT> = self.state.hooks.borrow().keys().copied().collect();
+        for hook in hooks {
+            self.state.remove_hook(hook);
+        }
+        drop(self.engine);
+        match Rc::try_unwrap(self.state) {
+            Ok(state) => state.game_boy.into_inner(),
+            Err(_) => unreachable!("the engine held the only other references"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::counter_game_boy;
+
+    fn script() -> Script {
+        Script::new(counter_game_boy(), Arc::new(Symbols::new()))
+    }
+
+    #[test]
+    fn scripts_drive_the_emulator_and_hook_into_it() {
+        let script = script();
+        script
+            .run(
+                r#"
+                write(0xC100, 0x12);
+                assert(read(0xC100) == 0x12, "write then read");
+                let calls = [];
+                let hook = on_exec(0x103, |address, value, pc| calls.push([address, value, pc]));
+                frames(2);
+                assert(frame_count() == 2, "two frames");
+                assert(calls.len() > 0, "INC (HL) ran");
+                assert(calls[0] == [0x103, 0x34, 0x103], "hook arguments");
+                assert(read(0xC000) == calls.len() % 256, "counted once per call");
+                assert(remove_hook(hook), "removed");
+                let count = calls.len();
+                frame();
+                assert(calls.len() == count, "no calls once removed");
+                assert(!remove_hook(hook), "removed already");
+                "#,
+            )
+            .unwrap();
+        let mut game_boy = script.finish();
+        assert_eq!(game_boy.cpu_mut().bus_mut().watchpoints().iter().count(), 0);
+    }
+
+    #[test]
+    fn failures_end_the_script() {
+        let err = script().run(r#"assert(1 == 2, "sums")"#).unwrap_err();
+        assert!(
+            err.to_string().contains("assertion failed: sums"),
+            "{}",
+            err
+        );
+        for source in ["read(0x10000)", "write(-1, 0)", "on_read(65536, || 0)"] {
+            let err = script().run(source).unwrap_err();
+            assert!(
+                err.to_string().contains("out of range"),
+                "{}: {}",
+                source,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::save_state::{read_u32, read_u8, write_u32, write_u8, SaveState};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+pub const SERIAL_DATA: u16 = 0xFF01;
+pub const SERIAL_CONTROL: u16 = 0xFF02;
+
+const TRANSFER_START: u8 = 0b1000_0000;
+const INTERNAL_CLOCK: u8 = 0b0000_0001;
+const CONTROL_UNUSED_BITS: u8 = 0b0111_1110;
+// 8192Hz on the 4.194304MHz system clock
+const CYCLES_PER_BIT: u32 = 128;
+
+/// A link partner plugged into the serial port.
+pub trait SerialDevice: Send {
+    /// Called when the Game Boy starts a transfer on the internal clock.
+    /// `byte` is what the Game Boy shifts out, the returned byte is shifted in.
+    fn transfer(&mut self, byte: u8) -> u8;
+    /// Polled every step so the device can clock transfers from the other side.
+    /// `pending` holds SB while the Game Boy waits on the external clock, the
+    /// returned byte completes that transfer.
+    fn poll(&mut self, _pending: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// Nothing plugged in, the data line floats high.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Collects every byte the Game Boy sends. Test ROMs such as Blargg's
+/// cpu_instrs report their results this way.
+pub struct SerialCapture {
+    output: Arc<Mutex<String>>,
+    echo: bool,
+}
+
+impl SerialCapture {
+    pub fn new(echo: bool) -> Self {
+        SerialCapture {
+            output: Arc::new(Mutex::new(String::new())),
+            echo,
+        }
+    }
+    // shared handle to the captured text, stays valid after the device is plugged in
+    pub fn output(&self) -> Arc<Mutex<String>> {
+        Arc::clone(&self.output)
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let c = byte as char;
+        self.output.lock().unwrap().push(c);
+        if self.echo {
+            print!("{}", c);
+            let _ = std::io::stdout().flush();
+        }
+        0xFF
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    incoming: u8,
+    bits_remaining: u8,
+    clock: u32,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            incoming: 0,
+            bits_remaining: 0,
+            clock: 0,
+            device: Box::new(Disconnected),
+        }
+    }
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+    // unplugs the device and hands it back
+    pub fn disconnect(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, Box::new(Disconnected))
+    }
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            SERIAL_DATA => self.data,
+            SERIAL_CONTROL => self.control | CONTROL_UNUSED_BITS,
+            _ => panic!("invalid serial address: {:x}", address),
+        }
+    }
+    pub fn write_byte(&mut self, address: u16, byte: u8) {
+        match address {
+            SERIAL_DATA => self.data = byte,
+            SERIAL_CONTROL => {
+                self.control = byte & !CONTROL_UNUSED_BITS;
+                if byte & TRANSFER_START != 0 && byte & INTERNAL_CLOCK != 0 {
+                    self.incoming = self.device.transfer(self.data);
+                    self.bits_remaining = 8;
+                    self.clock = 0;
+                }
+            }
+            _ => panic!("invalid serial address: {:x}", address),
+        }
+    }
+    // sets SB and SC from a snapshot taken by another emulator without starting a
+    // transfer, one in flight starts over with the line high
+    pub fn restore_registers(&mut self, data: u8, control: u8) {
+        self.data = data;
+        self.control = control & !CONTROL_UNUSED_BITS;
+        self.incoming = 0xFF;
+        self.clock = 0;
+        let internal_transfer = TRANSFER_START | INTERNAL_CLOCK;
+        self.bits_remaining = match control & internal_transfer == internal_transfer {
+            true => 8,
+            false => 0,
+        };
+    }
+    // returns true when a transfer completed and the serial interrupt should be requested
+    pub fn step(&mut self, cycles: u8) -> bool {
+        let external = self.control & (TRANSFER_START | INTERNAL_CLOCK) == TRANSFER_START;
+        let pending = if external { Some(self.data) } else { None };
+        if let Some(byte) = self.device.poll(pending) {
+            if external {
+                self.data = byte;
+                self.control &= !TRANSFER_START;
+                return true;
+            }
+        }
+        if self.bits_remaining == 0 {
+            return false;
+        }
+        self.clock += cycles as u32;
+        while self.clock >= CYCLES_PER_BIT && self.bits_remaining > 0 {
+            self.clock -= CYCLES_PER_BIT;
+            self.data = (self.data << 1) | (self.incoming >> 7);
+            self.incoming <<= 1;
+            self.bits_remaining -= 1;
+        }
+        if self.bits_remaining == 0 {
+            self.control &= !TRANSFER_START;
+            return true;
+        }
+        false
+    }
+}
+
+impl SaveState for Serial {
+    // the plugged in device is outside the Game Boy and keeps its own state
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_u8(state, self.data);
+        write_u8(state, self.control);
+        write_u8(state, self.incoming);
+        write_u8(state, self.bits_remaining);
+        write_u32(state, self.clock);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.data = read_u8(state)?;
+        self.control = read_u8(state)?;
+        self.incoming = read_u8(state)?;
+        self.bits_remaining = read_u8(state)?;
+        self.clock = read_u32(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interrupt::{Interrupt, INTERRUPT_FLAG},
+        memory_bus::MemoryBus,
+    };
+
+    // answers every transfer with the same byte and keeps what it was sent
+    struct Partner {
+        reply: u8,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl SerialDevice for Partner {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            self.sent.lock().unwrap().push(byte);
+            self.reply
+        }
+    }
+
+    #[test]
+    fn internal_clock_shifts_a_bit_every_128_cycles() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut bus = MemoryBus::new(vec![0; 0x100]);
+        bus.connect_serial(Box::new(Partner {
+            reply: 0xA5,
+            sent: sent.clone(),
+        }));
+        bus.write_byte(SERIAL_DATA, 0x3C);
+        bus.write_byte(SERIAL_CONTROL, TRANSFER_START | INTERNAL_CLOCK);
+        assert_eq!(*sent.lock().unwrap(), [0x3C]);
+
+        // four bits in, steps of 4 cycles, the nibbles have changed places
+        for _ in 0..CYCLES_PER_BIT {
+            bus.step(4);
+        }
+        assert_eq!(bus.read_byte(SERIAL_DATA), 0xCA);
+        for _ in 0..CYCLES_PER_BIT - 1 {
+            bus.step(4);
+        }
+        assert_ne!(bus.read_byte(SERIAL_CONTROL) & TRANSFER_START, 0);
+        assert_eq!(bus.read_byte(INTERRUPT_FLAG) & Interrupt::Serial.bit(), 0);
+
+        bus.step(4);
+        assert_eq!(bus.read_byte(SERIAL_DATA), 0xA5);
+        assert_eq!(bus.read_byte(SERIAL_CONTROL) & TRANSFER_START, 0);
+        assert_ne!(bus.read_byte(INTERRUPT_FLAG) & Interrupt::Serial.bit(), 0);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind},
+};
+
+const ROMX_FIRST: u16 = 0x4000;
+const ROMX_LAST: u16 = 0x7FFF;
+const WRAMX_FIRST: u16 = 0xD000;
+const WRAMX_LAST: u16 = 0xDFFF;
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("sym line {}: {}", line + 1, message),
+    )
+}
+
+// the bank RGBLINK assigns to an address on a cartridge without a mapper
+pub fn mapped_bank(address: u16) -> u8 {
+    match address {
+        ROMX_FIRST..=ROMX_LAST | WRAMX_FIRST..=WRAMX_LAST => 1,
+        _ => 0,
+    }
+}
+
+// first address of the memory area holding the address, names don't reach past it
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xFDFF => 0xE000,
+        0xFE00..=0xFE9F => 0xFE00,
+        0xFEA0..=0xFEFF => 0xFEA0,
+        0xFF00..=0xFF7F => 0xFF00,
+        _ => 0xFF80,
+    }
+}
+
+/// Symbols from a `.sym` file written by RGBLINK, lines of `BB:AAAA Name`
+/// with `;` starting a comment.
+#[derive(Default)]
+pub struct Symbols {
+    by_name: HashMap<String, (u8, u16)>,
+    // names sorted by bank and address, to find the one an address falls in
+    by_address: BTreeMap<(u8, u16), String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut symbols = Symbols::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (location, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(number, "missing name"))?;
+            let (bank, address) = location
+                .split_once(':')
+                .ok_or_else(|| invalid(number, "missing bank"))?;
+            let bank = u8::from_str_radix(bank, 16).map_err(|_| invalid(number, "invalid bank"))?;
+            let address =
+                u16::from_str_radix(address, 16).map_err(|_| invalid(number, "invalid address"))?;
+            symbols.insert(bank, address, name.trim());
+        }
+        Ok(symbols)
+    }
+    pub fn insert(&mut self, bank: u8, address: u16, name: &str) {
+        self.by_name.insert(name.to_string(), (bank, address));
+        // the first name for an address wins, RGBLINK lists the global label first
+        self.by_address
+            .entry((bank, address))
+            .or_insert_with(|| name.to_string());
+    }
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).map(|&(_, address)| address)
+    }
+    // the name at exactly this address
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.by_address
+            .get(&(mapped_bank(address), address))
+            .map(String::as_str)
+    }
+    // `Main.loop` or `Main.loop+3` after the closest symbol at or below the address
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let bank = mapped_bank(address);
+        let (&(_, start), name) = self
+            .by_address
+            .range((bank, region_start(address))..=(bank, address))
+            .next_back()?;
+        match address - start {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+{}", name, offset)),
+        }
+    }
+    // `01:4123 Main.loop+3`, just the bank and address without a symbol
+    pub fn format(&self, address: u16) -> String {
+        let location = format!("{:02X}:{:04X}", mapped_bank(address), address);
+        match self.describe(address) {
+            Some(name) => format!("{} {}", location, name),
+            None => location,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink\n\
+        00:0150 Start\n\
+        00:0150 Start.alias\n\
+        01:4000 Main ; entry\n\
+        01:4010 Main.loop\n\
+        \n\
+        00:C000 wCounter\n\
+        01:D000 wBuffer\n";
+
+    #[test]
+    fn parses_rgblink_files() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.address_of("Main.loop"), Some(0x4010));
+        assert_eq!(symbols.address_of("Start.alias"), Some(0x0150));
+        assert_eq!(symbols.name_at(0x0150), Some("Start"));
+        assert_eq!(symbols.name_at(0x4000), Some("Main"));
+        assert_eq!(symbols.name_at(0xD000), Some("wBuffer"));
+        assert_eq!(symbols.name_at(0x0151), None);
+        assert!(Symbols::parse("; nothing\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_name_the_line() {
+        for (text, message) in [
+            ("00:0150\n", "missing name"),
+            ("0150 Start\n", "missing bank"),
+            ("; ok\nXY:0150 Start\n", "sym line 2: invalid bank"),
+            ("00:10000 Start\n", "invalid address"),
+        ] {
+            let err = Symbols::parse(text).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn addresses_are_described_within_their_region() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(0x4013).as_deref(), Some("Main.loop+3"));
+        assert_eq!(symbols.format(0x4010), "01:4010 Main.loop");
+        assert_eq!(symbols.format(0x0100), "00:0100");
+        // ROM0 names don't run on into ROMX, nor WRAM0 into WRAMX
+        assert_eq!(symbols.describe(0x3FFF).as_deref(), Some("Start+16047"));
+        assert_eq!(symbols.describe(0xC123).as_deref(), Some("wCounter+291"));
+        assert_eq!(symbols.describe(0xE000), None);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::save_state::{read_u16, read_u8, write_u16, write_u8, SaveState};
+use std::io;
+
+pub const DIVIDER: u16 = 0xFF04;
+pub const TIMER_COUNTER: u16 = 0xFF05;
+pub const TIMER_MODULO: u16 = 0xFF06;
+pub const TIMER_CONTROL: u16 = 0xFF07;
+
+const TIMER_ENABLE: u8 = 0b100;
+const TIMER_CONTROL_UNUSED_BITS: u8 = 0b1111_1000;
+
+pub struct Timer {
+    // DIV is the upper byte of this free running counter
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+        }
+    }
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            DIVIDER => (self.counter >> 8) as u8,
+            TIMER_COUNTER => self.tima,
+            TIMER_MODULO => self.tma,
+            TIMER_CONTROL => self.tac | TIMER_CONTROL_UNUSED_BITS,
+            _ => panic!("invalid timer address: {:x}", address),
+        }
+    }
+    pub fn write_byte(&mut self, address: u16, byte: u8) {
+        match address {
+            DIVIDER => self.counter = 0,
+            TIMER_COUNTER => self.tima = byte,
+            TIMER_MODULO => self.tma = byte,
+            TIMER_CONTROL => self.tac = byte & !TIMER_CONTROL_UNUSED_BITS,
+            _ => panic!("invalid timer address: {:x}", address),
+        }
+    }
+    // a snapshot only knows DIV, the lower bits of the counter restart at 0
+    pub fn restore_divider(&mut self, divider: u8) {
+        self.counter = (divider as u16) << 8;
+    }
+    // TIMA ticks on the falling edge of the counter bit selected by TAC
+    fn counter_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0b00 => 1 << 9,
+            0b01 => 1 << 3,
+            0b10 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+    // returns true when TIMA overflowed and the timer interrupt should be requested
+    pub fn step(&mut self, cycles: u8) -> bool {
+        let mut overflow = false;
+        for _ in 0..cycles / 4 {
+            let previous = self.counter;
+            self.counter = self.counter.wrapping_add(4);
+            if self.tac & TIMER_ENABLE == 0 {
+                continue;
+            }
+            let bit = self.counter_bit();
+            if previous & bit != 0 && self.counter & bit == 0 {
+                let (tima, did_overflow) = self.tima.overflowing_add(1);
+                self.tima = if did_overflow { self.tma } else { tima };
+                overflow |= did_overflow;
+            }
+        }
+        overflow
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut Vec<u8>) {
+        write_u16(state, self.counter);
+        write_u8(state, self.tima);
+        write_u8(state, self.tma);
+        write_u8(state, self.tac);
+    }
+    fn load_state(&mut self, state: &mut &[u8]) -> io::Result<()> {
+        self.counter = read_u16(state)?;
+        self.tima = read_u8(state)?;
+        self.tma = read_u8(state)?;
+        self.tac = read_u8(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the number of overflows over the given cycles, in steps of one M-cycle
+    fn run(timer: &mut Timer, cycles: u32) -> usize {
+        (0..cycles / 4).filter(|_| timer.step(4)).count()
+    }
+
+    #[test]
+    fn div_counts_at_16384hz_and_resets_on_write() {
+        let mut timer = Timer::new();
+        run(&mut timer, 252);
+        assert_eq!(timer.read_byte(DIVIDER), 0);
+        run(&mut timer, 4);
+        assert_eq!(timer.read_byte(DIVIDER), 1);
+        run(&mut timer, 3 * 256);
+        assert_eq!(timer.read_byte(DIVIDER), 4);
+        timer.write_byte(DIVIDER, 0x55);
+        assert_eq!(timer.read_byte(DIVIDER), 0);
+    }
+
+    #[test]
+    fn tima_counts_at_the_rate_tac_selects() {
+        for (tac, period) in [(0b100, 1024), (0b101, 16), (0b110, 64), (0b111, 256)] {
+            let mut timer = Timer::new();
+            timer.write_byte(TIMER_CONTROL, tac);
+            run(&mut timer, period - 4);
+            assert_eq!(timer.read_byte(TIMER_COUNTER), 0, "TAC {:03b}", tac);
+            run(&mut timer, 4);
+            assert_eq!(timer.read_byte(TIMER_COUNTER), 1, "TAC {:03b}", tac);
+            run(&mut timer, 10 * period);
+            assert_eq!(timer.read_byte(TIMER_COUNTER), 11, "TAC {:03b}", tac);
+        }
+        // stopped, only DIV runs
+        let mut timer = Timer::new();
+        timer.write_byte(TIMER_CONTROL, 0b001);
+        run(&mut timer, 1024);
+        assert_eq!(timer.read_byte(TIMER_COUNTER), 0);
+        assert_eq!(timer.read_byte(DIVIDER), 4);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_and_requests_the_interrupt() {
+        let mut timer = Timer::new();
+        timer.write_byte(TIMER_MODULO, 0xAB);
+        timer.write_byte(TIMER_COUNTER, 0xFE);
+        timer.write_byte(TIMER_CONTROL, 0b101);
+        assert_eq!(run(&mut timer, 16), 0);
+        assert_eq!(timer.read_byte(TIMER_COUNTER), 0xFF);
+        assert_eq!(run(&mut timer, 16), 1);
+        assert_eq!(timer.read_byte(TIMER_COUNTER), 0xAB);
+        // from TMA on it takes 0x55 more ticks
+        assert_eq!(run(&mut timer, 0x54 * 16), 0);
+        assert_eq!(run(&mut timer, 16), 1);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{cpu::CPU, symbols::Symbols};
+use std::{
+    io::{self, BufWriter, Write},
+    sync::Arc,
+};
+
+/// Logs the registers and the next four bytes at PC before every instruction,
+/// in the format gameboy-doctor compares against its reference logs:
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`.
+/// Those logs start at the cartridge entry point, so nothing is written while
+/// the boot rom is mapped. With symbols each line ends in `; Main.loop+3`,
+/// which gameboy-doctor doesn't expect.
+pub struct Tracer {
+    output: BufWriter<Box<dyn Write + Send>>,
+    symbols: Option<Arc<Symbols>>,
+    doctor: bool,
+}
+
+// the line gameboy-doctor's reference logs were made with LY stuck at
+pub const DOCTOR_LINE: u8 = 0x90;
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Tracer {
+            output: BufWriter::new(output),
+            symbols: None,
+            doctor: false,
+        }
+    }
+    pub fn set_symbols(&mut self, symbols: Arc<Symbols>) {
+        self.symbols = Some(symbols);
+    }
+    // LY reads DOCTOR_LINE while the tracer is attached, so games that wait for
+    // a line take the same path they did for the reference logs
+    pub fn set_doctor_mode(&mut self, doctor: bool) {
+        self.doctor = doctor;
+    }
+    pub fn doctor_mode(&self) -> bool {
+        self.doctor
+    }
+    pub fn trace(&mut self, cpu: &CPU) -> io::Result<()> {
+        let bus = cpu.bus();
+        if bus.boot_rom_enabled() {
+            return Ok(());
+        }
+        let registers = cpu.registers();
+        let pc = cpu.pc();
+        write!(
+            self.output,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a,
+            u8::from(registers.f),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            cpu.sp(),
+            pc,
+            bus.peek_byte(pc),
+            bus.peek_byte(pc.wrapping_add(1)),
+            bus.peek_byte(pc.wrapping_add(2)),
+            bus.peek_byte(pc.wrapping_add(3))
+        )?;
+        match self
+            .symbols
+            .as_ref()
+            .and_then(|symbols| symbols.describe(pc))
+        {
+            Some(name) => writeln!(self.output, " ; {}", name),
+            None => writeln!(self.output),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game_boy::counter_game_boy, gpu::LINE};
+    use std::sync::Mutex;
+
+    // a writer the test still reads from once the tracer owns it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lines_match_gameboy_doctor() {
+        let mut game_boy = counter_game_boy();
+        let output = Shared::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()));
+        tracer.set_doctor_mode(true);
+        game_boy.set_tracer(Some(tracer));
+        assert_eq!(game_boy.cpu().bus().read_byte(LINE), DOCTOR_LINE);
+
+        // straight to the entry point with the registers the boot rom leaves
+        let cpu = game_boy.cpu_mut();
+        cpu.bus_mut().write_byte(0xFF50, 1);
+        for (name, value) in [
+            ("af", 0x01B0),
+            ("bc", 0x0013),
+            ("de", 0x00D8),
+            ("hl", 0x014D),
+        ] {
+            cpu.set_register(name, value);
+        }
+        cpu.set_sp(0xFFFE);
+        cpu.set_pc(0x100);
+        game_boy.step();
+        game_boy.step();
+        game_boy.set_tracer(None);
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            concat!(
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:21,00,C0,34\n",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:C0 L:00 SP:FFFE PC:0103 PCMEM:34,18,FD,00\n",
+            )
+        );
+        assert_ne!(game_boy.cpu().bus().read_byte(LINE), DOCTOR_LINE);
+    }
+}
@@ -0,0 +1,112 @@
+use std::ops::RangeInclusive;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// An access that matched a watchpoint. For execute watchpoints the value is
+/// the opcode and the address equals the PC.
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+    pub pc: u16,
+}
+
+pub enum WatchAction {
+    // queued for whoever drives the emulator, see Watchpoints::take_hits
+    Pause,
+    Callback(Box<dyn FnMut(WatchHit) + Send>),
+}
+
+pub struct Watchpoint {
+    pub addresses: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub action: WatchAction,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, address: u16) -> bool {
+        let watched = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+        watched && self.addresses.contains(&address)
+    }
+}
+
+/// Watchpoints of the memory bus, numbered in the order they were added.
+#[derive(Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<(u32, Watchpoint)>,
+    next_id: u32,
+    hits: Vec<WatchHit>,
+    // address of the instruction being executed
+    pc: u16,
+    // an execute watchpoint paused here, the next attempt runs the instruction
+    resume_pc: Option<u16>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints::default()
+    }
+    pub fn add(&mut self, watchpoint: Watchpoint) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.push((id, watchpoint));
+        id
+    }
+    pub fn remove(&mut self, id: u32) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints
+            .retain(|(watchpoint_id, _)| *watchpoint_id != id);
+        self.watchpoints.len() != count
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &(u32, Watchpoint)> {
+        self.watchpoints.iter()
+    }
+    // hits of pausing watchpoints since the last call
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+    // true when a pausing watchpoint stops the instruction at pc before it runs
+    pub(crate) fn check_execute(&mut self, pc: u16, opcode: u8) -> bool {
+        self.pc = pc;
+        if self.resume_pc.take() == Some(pc) {
+            return false;
+        }
+        let hits = self.hits.len();
+        self.check(Access::Execute, pc, opcode);
+        let paused = self.hits.len() > hits;
+        if paused {
+            self.resume_pc = Some(pc);
+        }
+        paused
+    }
+    pub(crate) fn check(&mut self, access: Access, address: u16, value: u8) {
+        let pc = self.pc;
+        for (_, watchpoint) in self.watchpoints.iter_mut() {
+            if !watchpoint.matches(access, address) {
+                continue;
+            }
+            let hit = WatchHit {
+                access,
+                address,
+                value,
+                pc,
+            };
+            match &mut watchpoint.action {
+                WatchAction::Pause => self.hits.push(hit),
+                WatchAction::Callback(callback) => callback(hit),
+            }
+        }
+    }
+}
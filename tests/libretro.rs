@@ -0,0 +1,264 @@
+// loads the built core the way a frontend does and drives it through the
+// exported API, run with `cargo test --features libretro`
+use libloading::{library_filename, Library, Symbol};
+use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    ffi::{c_char, c_uint, c_void, CString},
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    ptr, slice,
+    sync::{Mutex, OnceLock},
+};
+
+const ENVIRONMENT_GET_SYSTEM_DIRECTORY: c_uint = 9;
+const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const ENVIRONMENT_SET_MEMORY_MAPS: c_uint = 36 | 0x10000;
+const MEMDESC_SAVE_RAM: u64 = 1 << 3;
+const MEMORY_SAVE_RAM: c_uint = 0;
+
+#[repr(C)]
+struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct MemoryDescriptor {
+    flags: u64,
+    ptr: *mut c_void,
+    offset: usize,
+    start: usize,
+    select: usize,
+    disconnect: usize,
+    len: usize,
+    addrspace: *const c_char,
+}
+
+#[repr(C)]
+struct MemoryMap {
+    descriptors: *const MemoryDescriptor,
+    num_descriptors: c_uint,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+// what the core told the frontend, the core is one instance per process
+#[derive(Default)]
+struct Frontend {
+    memory_maps: Vec<(u64, usize, usize)>,
+    frame_hash: Option<u64>,
+}
+
+fn frontend() -> &'static Mutex<Frontend> {
+    static FRONTEND: OnceLock<Mutex<Frontend>> = OnceLock::new();
+    FRONTEND.get_or_init(Default::default)
+}
+
+// dmg_boot.bin lives in the repository root
+fn system_directory() -> &'static CString {
+    static DIRECTORY: OnceLock<CString> = OnceLock::new();
+    DIRECTORY.get_or_init(|| CString::new(env!("CARGO_MANIFEST_DIR")).unwrap())
+}
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match cmd {
+        ENVIRONMENT_GET_SYSTEM_DIRECTORY => {
+            *(data as *mut *const c_char) = system_directory().as_ptr();
+            true
+        }
+        ENVIRONMENT_SET_PIXEL_FORMAT => true,
+        ENVIRONMENT_SET_MEMORY_MAPS => {
+            let map = &*(data as *const MemoryMap);
+            let descriptors = slice::from_raw_parts(map.descriptors, map.num_descriptors as usize);
+            frontend().lock().unwrap().memory_maps = descriptors
+                .iter()
+                .map(|descriptor| (descriptor.flags, descriptor.start, descriptor.len))
+                .collect();
+            true
+        }
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn video_refresh(
+    data: *const c_void,
+    width: c_uint,
+    height: c_uint,
+    pitch: usize,
+) {
+    let frame = slice::from_raw_parts(data as *const u8, pitch * height as usize);
+    let mut hasher = DefaultHasher::new();
+    (width, frame).hash(&mut hasher);
+    frontend().lock().unwrap().frame_hash = Some(hasher.finish());
+}
+
+unsafe extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(
+    _port: c_uint,
+    _device: c_uint,
+    _index: c_uint,
+    _id: c_uint,
+) -> i16 {
+    0
+}
+
+// cargo builds the cdylib into the deps directory that holds this test
+fn core_path() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    path.join(library_filename("rust_boy"))
+}
+
+// a 32 KB ROM only cartridge looping at 0100, with the given type and ram size
+fn rom(kind: u8, ram_size: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // JR -2
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+    rom[0x147] = kind;
+    rom[0x149] = ram_size;
+    rom
+}
+
+struct Core {
+    library: Library,
+}
+
+impl Core {
+    fn load() -> Self {
+        let path = core_path();
+        let library = unsafe { Library::new(&path) }
+            .unwrap_or_else(|err| panic!("can't load {}: {}", path.display(), err));
+        let core = Core { library };
+        unsafe {
+            core.call::<unsafe extern "C" fn(EnvironmentFn)>(b"retro_set_environment")(environment);
+            core.call::<unsafe extern "C" fn(VideoRefreshFn)>(b"retro_set_video_refresh")(
+                video_refresh,
+            );
+            core.call::<unsafe extern "C" fn(AudioSampleBatchFn)>(b"retro_set_audio_sample_batch")(
+                audio_sample_batch,
+            );
+            core.call::<unsafe extern "C" fn(InputPollFn)>(b"retro_set_input_poll")(input_poll);
+            core.call::<unsafe extern "C" fn(InputStateFn)>(b"retro_set_input_state")(input_state);
+            core.call::<unsafe extern "C" fn()>(b"retro_init")();
+        }
+        core
+    }
+    unsafe fn call<T>(&self, name: &[u8]) -> Symbol<'_, T> {
+        self.library
+            .get(name)
+            .unwrap_or_else(|err| panic!("missing {}: {}", String::from_utf8_lossy(name), err))
+    }
+    fn load_game(&self, rom: &[u8]) -> bool {
+        let game = GameInfo {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+        unsafe {
+            self.call::<unsafe extern "C" fn(*const GameInfo) -> bool>(b"retro_load_game")(&game)
+        }
+    }
+    fn unload_game(&self) {
+        unsafe { self.call::<unsafe extern "C" fn()>(b"retro_unload_game")() }
+    }
+    // runs the frames and returns the hash of the last one shown
+    fn run(&self, frames: usize) -> u64 {
+        for _ in 0..frames {
+            unsafe { self.call::<unsafe extern "C" fn()>(b"retro_run")() };
+        }
+        frontend().lock().unwrap().frame_hash.take().unwrap()
+    }
+    fn serialize(&self) -> Vec<u8> {
+        unsafe {
+            let size = self.call::<unsafe extern "C" fn() -> usize>(b"retro_serialize_size")();
+            let mut state = vec![0u8; size];
+            let serialize =
+                self.call::<unsafe extern "C" fn(*mut c_void, usize) -> bool>(b"retro_serialize");
+            assert!(serialize(state.as_mut_ptr() as *mut c_void, state.len()));
+            state
+        }
+    }
+    fn unserialize(&self, state: &[u8]) -> bool {
+        unsafe {
+            self.call::<unsafe extern "C" fn(*const c_void, usize) -> bool>(b"retro_unserialize")(
+                state.as_ptr() as *const c_void,
+                state.len(),
+            )
+        }
+    }
+    fn save_ram_size(&self) -> usize {
+        unsafe {
+            self.call::<unsafe extern "C" fn(c_uint) -> usize>(b"retro_get_memory_size")(
+                MEMORY_SAVE_RAM,
+            )
+        }
+    }
+}
+
+impl Drop for Core {
+    fn drop(&mut self) {
+        unsafe { self.call::<unsafe extern "C" fn()>(b"retro_deinit")() }
+    }
+}
+
+// one test, the core behind the library is a single global instance
+#[test]
+fn frontend_loads_runs_and_restores_the_core() {
+    let core = Core::load();
+
+    assert!(!core.load_game(&rom(0x19, 0)), "MBC5 is refused");
+
+    assert!(core.load_game(&rom(0x00, 0)));
+    let save_ram = |frontend: &Frontend| {
+        frontend
+            .memory_maps
+            .iter()
+            .any(|&(flags, start, _)| flags & MEMDESC_SAVE_RAM != 0 && start == 0xA000)
+    };
+    assert!(
+        !save_ram(&frontend().lock().unwrap()),
+        "no save ram without ram on the cartridge"
+    );
+    assert_eq!(core.save_ram_size(), 0);
+
+    // a state taken mid boot plays back the same frames
+    core.run(30);
+    let state = core.serialize();
+    let frame = core.run(30);
+    let expected = core.serialize();
+    assert_ne!(expected, state);
+    assert!(core.unserialize(&state));
+    assert_eq!(core.run(30), frame);
+    assert_eq!(core.serialize(), expected);
+    assert!(
+        !core.unserialize(&state[..state.len() / 2]),
+        "truncated states are refused"
+    );
+    core.unload_game();
+
+    // MBC1 + RAM + battery with 8 KB
+    assert!(core.load_game(&rom(0x03, 0x02)));
+    let frontend = frontend().lock().unwrap();
+    assert!(save_ram(&frontend));
+    assert!(frontend
+        .memory_maps
+        .contains(&(MEMDESC_SAVE_RAM, 0xA000, 0x2000)));
+    drop(frontend);
+    assert_eq!(core.save_ram_size(), 0x2000);
+    core.unload_game();
+}